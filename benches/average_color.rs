@@ -0,0 +1,68 @@
+//! Benchmark of `get_average_color` on a 4K frame
+//!
+//! Compares the chunked single pass used when no pixels are skipped with the scalar
+//! filter-then-sum loop it replaced. Run it with `cargo bench --bench average_color`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use wiz_screen_sync::capture::BGRA8;
+use wiz_screen_sync::color::get_average_color;
+
+use std::hint::black_box;
+
+/// Size of the frame, 4K
+const WIDTH: usize = 3840;
+const HEIGHT: usize = 2160;
+/// Height of the black bars at the top and bottom of the frame, like a letterboxed movie
+const BAR_HEIGHT: usize = 276;
+/// Default `black_pixel_threshold` of the config
+const BLACK_THRESHOLD: f64 = 0.1;
+
+/// Create a letterboxed frame with a gradient between the black bars
+fn frame() -> Vec<BGRA8> {
+    (0..HEIGHT)
+        .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            if !(BAR_HEIGHT..HEIGHT - BAR_HEIGHT).contains(&y) {
+                return BGRA8 { b: 0, g: 0, r: 0, a: 255 };
+            }
+            BGRA8 {
+                b: (x * 255 / WIDTH) as u8,
+                g: (y * 255 / HEIGHT) as u8,
+                r: ((x + y) % 256) as u8,
+                a: 255,
+            }
+        })
+        .collect()
+}
+
+/// The scalar loop `get_average_color` used before, filtering the black pixels
+/// in one pass and summing the others in a second one
+fn scalar_average_color(pixels: &[BGRA8]) -> (u64, u64, u64) {
+    let filtered_pixels = pixels.iter().filter(|pixel| pixel.r != 0 || pixel.g != 0 || pixel.b != 0);
+    let pixel_count = filtered_pixels.clone().count() as u64;
+    if pixel_count == 0 || (pixel_count as f64) < pixels.len() as f64 * BLACK_THRESHOLD {
+        return (1, 1, 1);
+    }
+
+    let (mut r, mut g, mut b) = (0u64, 0u64, 0u64);
+    for pixel in filtered_pixels {
+        r += pixel.r as u64;
+        g += pixel.g as u64;
+        b += pixel.b as u64;
+    }
+
+    (r / pixel_count, g / pixel_count, b / pixel_count)
+}
+
+fn average_color(c: &mut Criterion) {
+    let pixels = frame();
+    assert_eq!(scalar_average_color(&pixels), get_average_color(&pixels, 1, BLACK_THRESHOLD));
+
+    let mut group = c.benchmark_group("average_color_4k");
+    group.bench_function("scalar", |b| b.iter(|| scalar_average_color(black_box(&pixels))));
+    group.bench_function("chunked", |b| b.iter(|| get_average_color(black_box(&pixels), 1, BLACK_THRESHOLD)));
+    group.finish();
+}
+
+criterion_group!(benches, average_color);
+criterion_main!(benches);
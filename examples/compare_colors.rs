@@ -0,0 +1,117 @@
+//! Compare the color modes on sample frames, to help choosing `color_mode`
+//!
+//! Loads each PNG given as an argument, runs every color extractor on it and prints
+//! the color each one gets and how long it took. Run it with
+//! `cargo run --release --example compare_colors -- frame1.png frame2.png`.
+//! Without arguments it uses a few generated frames whose color is known,
+//! which doubles as a quick check that the extractors behave sensibly.
+
+use wiz_screen_sync::capture::BGRA8;
+use wiz_screen_sync::color::*;
+use wiz_screen_sync::Config;
+
+use std::time::Instant;
+
+/// How many times each extractor is run, the average time is printed
+const RUNS: u32 = 10;
+/// Size of the generated frames
+const WIDTH: usize = 320;
+const HEIGHT: usize = 180;
+
+/// Gets the color of a frame with one of the color modes
+type Extractor<'a> = Box<dyn Fn(&Frame) -> (u64, u64, u64) + 'a>;
+
+/// A frame to run the extractors on
+struct Frame {
+    name: String,
+    pixels: Vec<BGRA8>,
+    width: usize,
+    height: usize,
+    /// Color the extractors should get, for the generated frames
+    expected: Option<(u64, u64, u64)>,
+}
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    let frames = if paths.is_empty() {
+        generated_frames()
+    } else {
+        paths.iter().map(|path| load_frame(path)).collect()
+    };
+
+    let config = Config::default();
+    let threshold = config.black_pixel_threshold;
+    let skipping = config.pixel_skipping;
+    let extractors: [(&str, Extractor); 9] = [
+        ("average", Box::new(|frame| get_average_color(&frame.pixels, skipping, threshold))),
+        ("average (skip 16)", Box::new(|frame| get_average_color(&frame.pixels, 16, threshold))),
+        ("average (64x36)", Box::new(|frame| {
+            let (pixels, _) = downscale(&frame.pixels, frame.width, frame.height, 64, 36);
+            get_average_color(&pixels, skipping, threshold)
+        })),
+        ("average (linear)", Box::new(|frame| get_average_color_linear(&frame.pixels, skipping, threshold))),
+        ("average (oklab)", Box::new(|frame| get_average_color_oklab(&frame.pixels, skipping, threshold))),
+        ("average (edges)", Box::new(|frame| get_average_color_edge_weighted(&frame.pixels, frame.width, frame.height, skipping, threshold))),
+        ("dominant", Box::new(|frame| get_dominant_color(&frame.pixels, config.dominant_clusters, skipping, threshold))),
+        ("vibrant", Box::new(|frame| get_vibrant_color(&frame.pixels, config.vibrant_buckets, skipping, threshold))),
+        ("most_common", Box::new(|frame| get_most_common_color(&frame.pixels, skipping, threshold))),
+    ];
+
+    for frame in frames.iter() {
+        match frame.expected {
+            Some(expected) => println!("{} ({}x{}), expected {:?}", frame.name, frame.width, frame.height, expected),
+            None => println!("{} ({}x{})", frame.name, frame.width, frame.height),
+        }
+
+        for (name, extractor) in extractors.iter() {
+            let start = Instant::now();
+            let mut color = (0, 0, 0);
+            for _ in 0..RUNS {
+                color = extractor(frame);
+            }
+            let elapsed = start.elapsed() / RUNS;
+
+            println!("  {:<18} {:<18} {:>8.2}ms", name, format!("{:?}", color), elapsed.as_secs_f64() * 1000.0);
+        }
+        println!();
+    }
+}
+
+/// Load a PNG as a frame
+fn load_frame(path: &str) -> Frame {
+    let image = image::open(path).unwrap_or_else(|error| panic!("Error loading {}: {}", path, error)).to_rgba8();
+    let pixels = image.pixels().map(|pixel| BGRA8 { b: pixel[2], g: pixel[1], r: pixel[0], a: pixel[3] }).collect();
+
+    Frame {
+        name: path.to_string(),
+        pixels,
+        width: image.width() as usize,
+        height: image.height() as usize,
+        expected: None,
+    }
+}
+
+/// Frames with a known color
+fn generated_frames() -> Vec<Frame> {
+    let frame = |name: &str, expected, pixel: &dyn Fn(usize, usize) -> (u8, u8, u8)| {
+        let pixels = (0..WIDTH * HEIGHT)
+            .map(|index| {
+                let (r, g, b) = pixel(index % WIDTH, index / WIDTH);
+                BGRA8 { b, g, r, a: 255 }
+            })
+            .collect();
+        Frame { name: name.to_string(), pixels, width: WIDTH, height: HEIGHT, expected }
+    };
+
+    vec![
+        frame("solid orange", Some((255, 120, 0)), &|_, _| (255, 120, 0)),
+        // The black bars are ignored
+        frame("letterboxed blue", Some((0, 60, 200)), &|_, y| if (HEIGHT / 8..HEIGHT * 7 / 8).contains(&y) { (0, 60, 200) } else { (0, 0, 0) }),
+        // Under `black_pixel_threshold` of colored pixels, the frame counts as black
+        frame("mostly black", Some((1, 1, 1)), &|x, y| if x < 4 && y < 4 { (255, 255, 255) } else { (0, 0, 0) }),
+        // The average is a gray, the other modes should pick red
+        frame("red and gray", None, &|x, _| if x < WIDTH * 2 / 3 { (200, 20, 20) } else { (128, 128, 128) }),
+        // Half red and half green: a dark olive in sRGB, a bright yellow in OKLab
+        frame("red and green", None, &|x, _| if x < WIDTH / 2 { (255, 0, 0) } else { (0, 255, 0) }),
+    ]
+}
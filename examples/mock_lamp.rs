@@ -0,0 +1,70 @@
+//! Fake WiZ lamp, to try the program without real lamps
+//!
+//! Answers `getPilot`, `getUserConfig`, `setPilot` and `setUserConfig` like a lamp does,
+//! and prints every message it receives. Run it with
+//! `cargo run --example mock_lamp -- [port]`, then put `127.0.0.1` in `ips.txt`
+//! and set `lamps_port` to the same port (38899 by default).
+
+use serde_json::{json, Value};
+
+use std::net::UdpSocket;
+
+fn main() {
+    let port = std::env::args().nth(1).unwrap_or_else(|| "38899".to_string());
+    let socket = UdpSocket::bind(format!("127.0.0.1:{}", port)).expect("Error binding the mock lamp socket");
+    println!("Mock lamp listening on 127.0.0.1:{}", port);
+
+    // State returned by getPilot, updated by setPilot
+    let mut pilot = json!({ "mac": "a8bb50000000", "rssi": -60, "state": true, "sceneId": 0, "r": 255, "g": 255, "b": 255, "dimming": 100 });
+    let mut user_config = json!({ "fadeIn": 500, "fadeOut": 500 });
+
+    let mut buffer = [0; 1024];
+    loop {
+        let (amt, src) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error) => {
+                println!("Error receiving: {}", error);
+                continue;
+            }
+        };
+        let message = String::from_utf8_lossy(&buffer[..amt]).to_string();
+        println!("{} -> {}", src, message);
+
+        let message: Value = match serde_json::from_str(&message) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+
+        let response = match method {
+            "getPilot" => json!({ "method": method, "env": "pro", "result": pilot }),
+            "getUserConfig" => json!({ "method": method, "env": "pro", "result": user_config }),
+            "setPilot" => {
+                // Like the lamps, color and temperature replace each other
+                if params.get("temp").is_some() {
+                    for key in ["r", "g", "b", "sceneId"] {
+                        pilot.as_object_mut().unwrap().remove(key);
+                    }
+                } else if params.get("r").is_some() {
+                    pilot.as_object_mut().unwrap().remove("temp");
+                }
+                for (key, value) in params.as_object().into_iter().flatten() {
+                    pilot[key] = value.clone();
+                }
+                json!({ "method": method, "env": "pro", "result": { "success": true } })
+            }
+            "setUserConfig" => {
+                for (key, value) in params.as_object().into_iter().flatten() {
+                    user_config[key] = value.clone();
+                }
+                json!({ "method": method, "env": "pro", "result": { "success": true } })
+            }
+            _ => json!({ "method": method, "env": "pro", "error": { "code": -32601, "message": "Method not found" } }),
+        };
+
+        if let Err(error) = socket.send_to(response.to_string().as_bytes(), src) {
+            println!("Error answering: {}", error);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use log::{info, warn};
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::config::Region;
+
+#[cfg(windows)]
+mod dxgi;
+#[cfg(all(unix, feature = "x11"))]
+mod x11;
+
+/// A pixel of a captured frame
+#[cfg(windows)]
+pub use dxgcap::BGRA8;
+
+/// A pixel of a captured frame, same layout as the one captured on Windows
+#[cfg(not(windows))]
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Ord)]
+pub struct BGRA8 {
+    pub b: u8,
+    pub g: u8,
+    pub r: u8,
+    pub a: u8,
+}
+
+/// How long to wait for a new frame, in milliseconds
+#[cfg(any(windows, all(unix, feature = "x11")))]
+const CAPTURE_TIMEOUT_MS: u32 = 300;
+/// How many times the first frame is captured before giving up
+const FIRST_FRAME_ATTEMPTS: u32 = 5;
+/// Delay between the attempts to capture the first frame
+const FIRST_FRAME_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often a paused `CaptureThread` checks if it has to capture again
+const INACTIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A captured frame: its pixels row by row and its size as `(width, height)`
+pub type Frame = (Vec<BGRA8>, (usize, usize));
+
+/// A monitor connected to this computer
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    pub width: usize,
+    pub height: usize,
+    pub primary: bool,
+}
+
+/// Errors that can happen while capturing a frame
+#[derive(Debug)]
+pub enum CaptureError {
+    /// No new frame arrived in time, usually because the screen didn't change
+    Timeout,
+    /// Access to the screen was lost and the capturer has to be created again.
+    /// Happens on resolution changes, fullscreen switches or UAC prompts
+    DeviceLost,
+    /// Any other error of the capture backend
+    Other(String),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Timeout => write!(f, "timed out waiting for a new frame"),
+            CaptureError::DeviceLost => write!(f, "lost access to the screen"),
+            CaptureError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Something that captures the screen, one frame at a time
+///
+/// The rest of the program only uses this trait, so it doesn't depend on the platform.
+/// DXGI is used on Windows and X11 on Linux (with the `x11` feature)
+pub trait FrameCapturer {
+    /// Capture a frame, returning its pixels row by row and its size as `(width, height)`
+    fn capture(&mut self) -> Result<(Vec<BGRA8>, (usize, usize)), CaptureError>;
+
+    /// Only capture a part of the screen from now on, so fewer pixels are copied each frame
+    ///
+    /// Returns false if the backend can't, then it keeps capturing the whole screen
+    /// and the frames have to be cropped afterwards
+    ///
+    /// # Arguments
+    /// * `region` - The part of the screen to capture
+    fn set_region(&mut self, _region: &Region) -> bool {
+        false
+    }
+}
+
+/// List the monitors, in the order used by `monitor_index`
+///
+/// The primary monitor is always first
+pub fn list_monitors() -> Vec<Monitor> {
+    #[cfg(windows)]
+    let mut monitors = dxgi::list_monitors();
+    #[cfg(all(unix, feature = "x11"))]
+    let mut monitors = x11::list_monitors();
+    #[cfg(not(any(windows, all(unix, feature = "x11"))))]
+    let mut monitors: Vec<Monitor> = Vec::new();
+
+    // Same order as the capture: primary first, then the rest
+    monitors.sort_by_key(|monitor| !monitor.primary);
+    monitors
+}
+
+/// Create a capturer for a monitor
+///
+/// # Arguments
+/// * `monitor_index` - The monitor to capture, 0 is the primary monitor
+pub fn create_capturer(monitor_index: usize) -> Result<Box<dyn FrameCapturer>, String> {
+    let monitors = list_monitors();
+    if monitor_index >= monitors.len() {
+        if monitors.is_empty() {
+            return Err(no_monitors_error());
+        }
+
+        let mut error = format!("Monitor {} doesn't exist, the available monitors are:", monitor_index);
+        for (index, monitor) in monitors.iter().enumerate() {
+            let primary = if monitor.primary { " (primary)" } else { "" };
+            error.push_str(&format!("\n{}: {}x{}{}", index, monitor.width, monitor.height, primary));
+        }
+        return Err(error);
+    }
+
+    #[cfg(windows)]
+    return Ok(Box::new(dxgi::DxgiCapturer::new(monitor_index, CAPTURE_TIMEOUT_MS)?));
+    #[cfg(all(unix, feature = "x11"))]
+    return Ok(Box::new(x11::X11Capturer::new(monitor_index, CAPTURE_TIMEOUT_MS)?));
+    #[cfg(not(any(windows, all(unix, feature = "x11"))))]
+    Err(no_monitors_error())
+}
+
+/// Error for when no monitor can be captured
+fn no_monitors_error() -> String {
+    if cfg!(any(windows, all(unix, feature = "x11"))) {
+        "No monitors found".to_string()
+    } else {
+        "The screen can't be captured on this platform, on Linux build it with the `x11` feature".to_string()
+    }
+}
+
+/// Capture the first frame of a new capturer
+///
+/// The first frames after creating a capturer are often missing or empty,
+/// so it's retried a few times before giving up
+pub fn capture_first_frame(capturer: &mut dyn FrameCapturer) -> Result<(Vec<BGRA8>, (usize, usize)), String> {
+    let mut last_error = String::new();
+    for _ in 0..FIRST_FRAME_ATTEMPTS {
+        match capturer.capture() {
+            Ok((frame, size)) if !frame.is_empty() => return Ok((frame, size)),
+            Ok(_) => last_error = "the captured frame is empty".to_string(),
+            Err(error) => last_error = error.to_string(),
+        }
+        thread::sleep(FIRST_FRAME_RETRY_DELAY);
+    }
+
+    Err(last_error)
+}
+
+/// Capture the first frame of a new capturer, skipping its warmup
+///
+/// Works like `capture_first_frame()`, but then `warmup_frames` more frames are captured
+/// and the last one is returned, since the first ones are often black or partial.
+/// Failed captures count as frames, so a static screen doesn't wait forever
+///
+/// # Arguments
+/// * `capturer` - The new capturer
+/// * `warmup_frames` - How many frames to skip after the first one
+pub fn capture_warm_frame(capturer: &mut dyn FrameCapturer, warmup_frames: u32) -> Result<(Vec<BGRA8>, (usize, usize)), String> {
+    let mut frame = capture_first_frame(capturer)?;
+    for _ in 0..warmup_frames {
+        if let Ok(captured) = capturer.capture() {
+            if !captured.0.is_empty() {
+                frame = captured;
+            }
+        }
+    }
+
+    Ok(frame)
+}
+
+/// Check if the capturer has to be created again after an error
+pub fn is_device_lost(error: &CaptureError) -> bool {
+    matches!(error, CaptureError::DeviceLost)
+}
+
+/// Create a capturer and capture its first frame, skipping its warmup
+///
+/// Returns the capturer, the frame and if the capturer only captures `region`, see `FrameCapturer::set_region()`
+fn start_capturer(monitor_index: usize, warmup_frames: u32, region: Option<&Region>) -> Result<(Box<dyn FrameCapturer>, Frame, bool), String> {
+    let mut capturer = create_capturer(monitor_index)?;
+    let captures_region = region.is_some_and(|region| capturer.set_region(region));
+    let first_frame = capture_warm_frame(capturer.as_mut(), warmup_frames)?;
+
+    Ok((capturer, first_frame, captures_region))
+}
+
+/// Latest frame of a `CaptureThread`, until it's taken
+struct LatestFrame {
+    frame: Mutex<Option<Frame>>,
+    /// Notified when a frame is captured
+    captured: Condvar,
+}
+
+/// Captures the screen on its own thread, so a slow send to the lamps doesn't delay the next capture
+///
+/// Only the latest frame is kept, a frame that isn't taken before the next one is captured is dropped.
+/// The capturer is created again when access to the screen is lost. The thread is stopped when it's dropped
+pub struct CaptureThread {
+    latest: Arc<LatestFrame>,
+    /// Cleared to stop capturing for a while, e.g. while the sync is paused
+    active: Arc<AtomicBool>,
+    /// Set to stop the thread
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+    /// If the frames only hold the region given to `start()`
+    captures_region: bool,
+}
+
+impl CaptureThread {
+    /// Start capturing a monitor
+    ///
+    /// Returns the thread and the first frame, once `warmup_frames` frames have been skipped
+    ///
+    /// # Arguments
+    /// * `monitor_index` - The monitor to capture, 0 is the primary monitor
+    /// * `warmup_frames` - How many frames to skip after creating the capturer
+    /// * `region` - Only capture this part of the screen if the backend can, see `captures_region()`
+    pub fn start(monitor_index: usize, warmup_frames: u32, region: Option<Region>) -> Result<(Self, Frame), String> {
+        let latest = Arc::new(LatestFrame { frame: Mutex::new(None), captured: Condvar::new() });
+        let active = Arc::new(AtomicBool::new(true));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (first_frame_sender, first_frame_receiver) = mpsc::channel();
+
+        let handle = {
+            let (latest, active, stop) = (latest.clone(), active.clone(), stop.clone());
+            // The capturer is created on the thread, the capture backends can't always move between threads
+            thread::spawn(move || {
+                let mut capturer = match start_capturer(monitor_index, warmup_frames, region.as_ref()) {
+                    Ok((capturer, first_frame, captures_region)) => {
+                        let _ = first_frame_sender.send(Ok((first_frame, captures_region)));
+                        capturer
+                    }
+                    Err(error) => {
+                        let _ = first_frame_sender.send(Err(error));
+                        return;
+                    }
+                };
+
+                while !stop.load(Ordering::SeqCst) {
+                    if !active.load(Ordering::SeqCst) {
+                        thread::sleep(INACTIVE_POLL_INTERVAL);
+                        continue;
+                    }
+
+                    let frame = match capturer.capture() {
+                        Ok(frame) => frame,
+                        Err(error) if is_device_lost(&error) => {
+                            warn!("Lost access to the screen, re-initializing capture...");
+                            match start_capturer(monitor_index, warmup_frames, region.as_ref()) {
+                                Ok((new_capturer, first_frame, _)) => {
+                                    info!("Capture re-initialized");
+                                    capturer = new_capturer;
+                                    first_frame
+                                }
+                                Err(error) => {
+                                    warn!("Error re-initializing capture: {}", error);
+                                    thread::sleep(FIRST_FRAME_RETRY_DELAY);
+                                    continue;
+                                }
+                            }
+                        }
+                        // No new frame, usually because the screen didn't change
+                        Err(_) => continue,
+                    };
+
+                    *latest.frame.lock().unwrap() = Some(frame);
+                    latest.captured.notify_one();
+                }
+            })
+        };
+
+        let (first_frame, captures_region) = first_frame_receiver.recv()
+            .unwrap_or_else(|_| Err("The capture thread stopped".to_string()))?;
+
+        Ok((Self { latest, active, stop, handle: Some(handle), captures_region }, first_frame))
+    }
+
+    /// Check if the frames only hold the region given to `start()`,
+    /// otherwise they hold the whole screen and have to be cropped
+    pub fn captures_region(&self) -> bool {
+        self.captures_region
+    }
+
+    /// Take the latest frame, waiting up to `timeout` for one
+    ///
+    /// Returns `None` if no frame was captured since the last one taken
+    pub fn next_frame(&self, timeout: Duration) -> Option<Frame> {
+        let frame = self.latest.frame.lock().unwrap();
+        let (mut frame, _) = self.latest.captured
+            .wait_timeout_while(frame, timeout, |frame| frame.is_none())
+            .unwrap();
+
+        frame.take()
+    }
+
+    /// Stop or start capturing, to not waste time on frames that aren't used.
+    /// The frame captured before stopping is dropped
+    pub fn set_active(&self, active: bool) {
+        if !active {
+            self.latest.frame.lock().unwrap().take();
+        }
+        self.active.store(active, Ordering::SeqCst);
+    }
+}
+
+impl Drop for CaptureThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use dxgcap::DXGIManager;
+use winapi::shared::minwindef::{BOOL, LPARAM, TRUE};
+use winapi::shared::windef::{HDC, HMONITOR, LPRECT};
+use winapi::um::winuser::*;
+
+use std::mem;
+use std::ptr;
+
+use super::{CaptureError, FrameCapturer, Monitor, BGRA8};
+
+/// Capture with the DXGI Desktop Duplication API, on Windows
+pub struct DxgiCapturer {
+    manager: DXGIManager,
+}
+
+impl DxgiCapturer {
+    /// Create a capturer for a monitor
+    ///
+    /// # Arguments
+    /// * `monitor_index` - The monitor to capture, 0 is the primary monitor
+    /// * `timeout_ms` - How long to wait for a new frame, in milliseconds
+    pub fn new(monitor_index: usize, timeout_ms: u32) -> Result<DxgiCapturer, String> {
+        let mut manager = DXGIManager::new(timeout_ms)?;
+        if monitor_index != 0 {
+            manager.set_capture_source_index(monitor_index);
+        }
+
+        Ok(DxgiCapturer { manager })
+    }
+}
+
+// dxgcap always copies the whole desktop from the GPU, so `set_region()` isn't supported
+// and the frames are cropped after the capture
+impl FrameCapturer for DxgiCapturer {
+    fn capture(&mut self) -> Result<(Vec<BGRA8>, (usize, usize)), CaptureError> {
+        self.manager.capture_frame().map_err(|error| match error {
+            dxgcap::CaptureError::AccessDenied | dxgcap::CaptureError::AccessLost | dxgcap::CaptureError::RefreshFailure => {
+                CaptureError::DeviceLost
+            }
+            dxgcap::CaptureError::Timeout => CaptureError::Timeout,
+            error => CaptureError::Other(format!("{:?}", error)),
+        })
+    }
+}
+
+/// List the monitors connected to this computer
+pub fn list_monitors() -> Vec<Monitor> {
+    unsafe extern "system" fn add_monitor(monitor: HMONITOR, _: HDC, _: LPRECT, data: LPARAM) -> BOOL {
+        let monitors = &mut *(data as *mut Vec<Monitor>);
+
+        let mut info: MONITORINFO = mem::zeroed();
+        info.cbSize = mem::size_of::<MONITORINFO>() as u32;
+        if GetMonitorInfoW(monitor, &mut info) != 0 {
+            let rect = info.rcMonitor;
+            monitors.push(Monitor {
+                width: (rect.right - rect.left) as usize,
+                height: (rect.bottom - rect.top) as usize,
+                primary: info.dwFlags & MONITORINFOF_PRIMARY != 0,
+            });
+        }
+
+        TRUE
+    }
+
+    let mut monitors: Vec<Monitor> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(ptr::null_mut(), ptr::null(), Some(add_monitor), &mut monitors as *mut _ as LPARAM);
+    }
+
+    monitors
+}
@@ -0,0 +1,111 @@
+use scrap::{Capturer, Display};
+
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{CaptureError, FrameCapturer, Monitor, BGRA8};
+use crate::config::Region;
+
+/// Delay between the checks for a new frame
+const FRAME_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Capture with X11 shared memory, on Linux
+pub struct X11Capturer {
+    capturer: Capturer,
+    width: usize,
+    height: usize,
+    timeout: Duration,
+    /// Part of the screen that is converted, as `(x, y, width, height)` in pixels.
+    /// The whole screen is in shared memory anyway, but the rest isn't copied
+    region: (usize, usize, usize, usize),
+}
+
+impl X11Capturer {
+    /// Create a capturer for a monitor
+    ///
+    /// # Arguments
+    /// * `monitor_index` - The monitor to capture, 0 is the primary monitor
+    /// * `timeout_ms` - How long to wait for a new frame, in milliseconds
+    pub fn new(monitor_index: usize, timeout_ms: u32) -> Result<X11Capturer, String> {
+        let display = Display::all()
+            .map_err(|error| format!("Error listing the displays: {}", error))?
+            .into_iter()
+            .nth(monitor_index)
+            .ok_or_else(|| format!("Monitor {} doesn't exist", monitor_index))?;
+        let capturer = Capturer::new(display).map_err(|error| format!("Error creating the capturer: {}", error))?;
+
+        Ok(X11Capturer {
+            width: capturer.width(),
+            height: capturer.height(),
+            region: (0, 0, capturer.width(), capturer.height()),
+            capturer,
+            timeout: Duration::from_millis(timeout_ms as u64),
+        })
+    }
+}
+
+impl FrameCapturer for X11Capturer {
+    fn capture(&mut self) -> Result<(Vec<BGRA8>, (usize, usize)), CaptureError> {
+        let start = Instant::now();
+        loop {
+            match self.capturer.frame() {
+                Ok(frame) => {
+                    // Rows can be padded, so the pixels are read row by row
+                    let stride = frame.len() / self.height.max(1);
+                    let (x, y, width, height) = self.region;
+                    let pixels = frame
+                        .chunks(stride)
+                        .skip(y)
+                        .take(height)
+                        .flat_map(|row| row[x * 4..(x + width) * 4].chunks_exact(4))
+                        .map(|pixel| BGRA8 { b: pixel[0], g: pixel[1], r: pixel[2], a: pixel[3] })
+                        .collect();
+                    return Ok((pixels, (width, height)));
+                }
+                // No new frame yet
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    if start.elapsed() >= self.timeout {
+                        return Err(CaptureError::Timeout);
+                    }
+                    thread::sleep(FRAME_POLL_INTERVAL);
+                }
+                Err(error) if matches!(error.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::NotConnected) => {
+                    return Err(CaptureError::DeviceLost);
+                }
+                Err(error) => return Err(CaptureError::Other(error.to_string())),
+            }
+        }
+    }
+
+    fn set_region(&mut self, region: &Region) -> bool {
+        // Same rounding as `sample_region()`, so the colors don't depend on where the frame is cropped
+        let x_start = (region.x * self.width as f64) as usize;
+        let y_start = (region.y * self.height as f64) as usize;
+        let x_end = (((region.x + region.w) * self.width as f64) as usize).min(self.width);
+        let y_end = (((region.y + region.h) * self.height as f64) as usize).min(self.height);
+        self.region = (x_start, y_start, x_end - x_start, y_end - y_start);
+
+        true
+    }
+}
+
+/// List the monitors of the X11 display
+///
+/// X11 doesn't say which one is the primary monitor, the first one is used as the primary
+pub fn list_monitors() -> Vec<Monitor> {
+    let displays = match Display::all() {
+        Ok(displays) => displays,
+        Err(_) => return Vec::new(),
+    };
+
+    displays
+        .iter()
+        .enumerate()
+        .map(|(index, display)| Monitor {
+            width: display.width(),
+            height: display.height(),
+            primary: index == 0,
+        })
+        .collect()
+}
@@ -0,0 +1,1521 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::capture::BGRA8;
+use crate::config::{ColorMode, ColorSpace, Config, Region, VariationMode};
+
+/// Exponential smoothing of the colors between iterations
+/// 
+/// The smoothed color is updated on every iteration, even when it's not sent to the lamps,
+/// so it keeps moving towards the screen color instead of getting stuck under the variation threshold.
+/// It's the last step of the color pipeline and the only one that rounds the color:
+/// `FrameBlender` and `ChannelFilter` pass their colors unrounded, so the dithering sees their fractions too
+pub struct ColorSmoother {
+    /// How much of the previous color is kept on each update
+    factor: f64,
+    /// Smoothed color, kept as floats so small steps aren't lost to rounding
+    color: Option<(f64, f64, f64)>,
+    /// Dithering added before rounding the smoothed color, see `set_dither()`
+    dither: Option<Dither>,
+}
+
+impl ColorSmoother {
+    /// Create a new ColorSmoother
+    /// 
+    /// # Arguments
+    /// * `factor` - How much of the previous color is kept on each update, from 0.0 to 1.0
+    pub fn new(factor: f64) -> Self {
+        Self { factor, color: None, dither: None }
+    }
+
+    /// Add a random offset to the smoothed color before rounding it, different on each update
+    /// 
+    /// In slow fades the colors sent alternate between the two closest values
+    /// instead of stepping from one to the next, which hides banding at low brightness.
+    /// Only colors between two steps are dithered, so it does nothing unless the smoothing,
+    /// `ChannelFilter` or `FrameBlender` make them. Whole values always round back to themselves
+    /// 
+    /// # Arguments
+    /// * `amount` - Width of the offset in color steps, from 0.0 (no dithering) to 1.0
+    pub fn set_dither(&mut self, amount: f64) {
+        self.dither = (amount > 0.0).then(|| Dither::new(amount));
+    }
+
+    /// Blend a new color into the smoothed color and return the result, rounded
+    /// 
+    /// # Arguments
+    /// * `new` - The color of the frame, unrounded from the previous steps of the pipeline
+    pub fn update(&mut self, new: (f64, f64, f64)) -> (u64, u64, u64) {
+        let color = match self.color {
+            Some(previous) => (
+                previous.0 * self.factor + new.0 * (1.0 - self.factor),
+                previous.1 * self.factor + new.1 * (1.0 - self.factor),
+                previous.2 * self.factor + new.2 * (1.0 - self.factor),
+            ),
+            None => new,
+        };
+        self.color = Some(color);
+
+        match self.dither.as_mut() {
+            Some(dither) => (dither.round(color.0), dither.round(color.1), dither.round(color.2)),
+            None => (color.0.round() as u64, color.1.round() as u64, color.2.round() as u64),
+        }
+    }
+}
+
+/// Random offsets added to a value before rounding it, so it averages out to the unrounded value over time
+/// 
+/// Uses a xorshift generator, the noise doesn't need to be good, only cheap
+struct Dither {
+    /// Width of the offset in steps, at most 1.0 so the result is one of the two closest values
+    amount: f64,
+    /// State of the generator, never 0
+    state: u64,
+}
+
+impl Dither {
+    fn new(amount: f64) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64);
+        Self { amount: amount.min(1.0), state: seed | 1 }
+    }
+
+    /// Round a value after adding an offset between `-amount / 2` and `amount / 2`
+    fn round(&mut self, value: f64) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let noise = (self.state >> 11) as f64 / (1u64 << 53) as f64 - 0.5;
+
+        (value + noise * self.amount).round().clamp(0.0, 255.0) as u64
+    }
+}
+
+/// Holds back color changes until they last a few frames
+/// 
+/// A change is only committed once the new color stays within the variation threshold
+/// of itself for `frames` consecutive frames, so single-frame flashes aren't sent
+pub struct ColorDebouncer {
+    /// How many consecutive frames a change has to last, 1 = no debouncing
+    frames: u32,
+    /// Color the following frames are compared against
+    candidate: Option<(u64, u64, u64)>,
+    /// How many consecutive frames stayed close to `candidate`
+    stable_frames: u32,
+}
+
+impl ColorDebouncer {
+    /// Create a new ColorDebouncer
+    /// 
+    /// # Arguments
+    /// * `frames` - How many consecutive frames a change has to last, 1 = no debouncing
+    pub fn new(frames: u32) -> Self {
+        Self { frames, candidate: None, stable_frames: 0 }
+    }
+
+    /// Check if a change should be sent to the lamps
+    /// 
+    /// # Arguments
+    /// * `rgb` - The color of this frame
+    /// * `changed` - If the color of this frame is different enough from the one the lamps have
+    /// * `mode` - How the variation between colors is measured
+    /// * `threshold` - The variation under which two colors are the same
+    pub fn update(&mut self, rgb: (u64, u64, u64), changed: bool, mode: VariationMode, threshold: u64) -> bool {
+        if !changed || self.frames <= 1 {
+            self.candidate = None;
+            self.stable_frames = 0;
+            return changed;
+        }
+
+        match self.candidate {
+            Some(candidate) if color_variation(rgb, candidate, mode) <= threshold => self.stable_frames += 1,
+            _ => {
+                self.candidate = Some(rgb);
+                self.stable_frames = 1;
+            }
+        }
+
+        if self.stable_frames < self.frames {
+            return false;
+        }
+
+        self.candidate = None;
+        self.stable_frames = 0;
+        true
+    }
+}
+
+/// First order low-pass filter on each channel of the colors, with its own state per channel
+/// 
+/// Unlike `ColorSmoother` it works on time instead of frames, so it filters the same at any FPS:
+/// changes slower than the cutoff frequency go through and faster ones (flicker) are damped.
+/// The variation threshold is compared against the last color sent, not the previous frame,
+/// so the small steps of the filter add up until they're sent instead of being lost under it
+pub struct ChannelFilter {
+    /// Time constant of the filter, from the cutoff frequency
+    time_constant: f64,
+    /// Filtered value of each channel, kept as floats so small steps aren't lost to rounding
+    channels: Option<[f64; 3]>,
+}
+
+impl ChannelFilter {
+    /// Create a new ChannelFilter
+    /// 
+    /// # Arguments
+    /// * `cutoff_hz` - Changes faster than this many times per second are damped, 0 = no filtering
+    pub fn new(cutoff_hz: f64) -> Self {
+        let time_constant = if cutoff_hz > 0.0 { 1.0 / (2.0 * std::f64::consts::PI * cutoff_hz) } else { 0.0 };
+        Self { time_constant, channels: None }
+    }
+
+    /// Filter the color of a new frame and return the result, unrounded
+    /// 
+    /// # Arguments
+    /// * `rgb` - The color of the frame
+    /// * `elapsed` - Time since the previous frame
+    pub fn update(&mut self, rgb: (f64, f64, f64), elapsed: Duration) -> (f64, f64, f64) {
+        let new = [rgb.0, rgb.1, rgb.2];
+        let elapsed = elapsed.as_secs_f64();
+        let alpha = if self.time_constant == 0.0 { 1.0 } else { elapsed / (self.time_constant + elapsed) };
+
+        let channels = match self.channels {
+            Some(previous) => [0, 1, 2].map(|channel| previous[channel] + alpha * (new[channel] - previous[channel])),
+            None => new,
+        };
+        self.channels = Some(channels);
+
+        (channels[0], channels[1], channels[2])
+    }
+}
+
+/// Limits how much a value can change on each update, e.g. the dimming of the lamps
+/// 
+/// Unlike the smoothing, big jumps are spread over several frames at a constant rate
+/// and small changes go through unchanged
+pub struct SlewLimiter {
+    /// Most the value can change per update, 0 = no limit
+    max_step: u64,
+    /// Value returned by the last update
+    value: Option<u64>,
+}
+
+impl SlewLimiter {
+    /// Create a new SlewLimiter
+    /// 
+    /// # Arguments
+    /// * `max_step` - Most the value can change per update, 0 = no limit
+    pub fn new(max_step: u64) -> Self {
+        Self { max_step, value: None }
+    }
+
+    /// Move towards `target` by at most `max_step` and return the result
+    pub fn update(&mut self, target: u64) -> u64 {
+        let value = match self.value {
+            Some(previous) if self.max_step != 0 => {
+                target.clamp(previous.saturating_sub(self.max_step), previous + self.max_step)
+            }
+            _ => target,
+        };
+        self.value = Some(value);
+
+        value
+    }
+}
+
+/// Weighted average of the colors of the last few frames, to hide flicker from noisy or dithered content
+/// 
+/// Unlike `ColorSmoother` the colors older than `frames` frames don't count at all,
+/// so a change is fully followed after `frames` frames
+pub struct FrameBlender {
+    /// How many frames are averaged, 1 = no blending
+    frames: usize,
+    /// Weight of each frame relative to the next newer one, 1.0 = plain average
+    decay: f64,
+    /// Colors of the last frames, newest last. Never longer than `frames`
+    colors: VecDeque<(u64, u64, u64)>,
+}
+
+impl FrameBlender {
+    /// Create a new FrameBlender
+    /// 
+    /// # Arguments
+    /// * `frames` - How many frames are averaged, 1 = no blending
+    /// * `decay` - Weight of each frame relative to the next newer one, from 0.0 to 1.0
+    pub fn new(frames: usize, decay: f64) -> Self {
+        let frames = frames.max(1);
+        Self { frames, decay, colors: VecDeque::with_capacity(frames) }
+    }
+
+    /// Add the color of a new frame and return the weighted average of the last frames, unrounded
+    pub fn update(&mut self, rgb: (u64, u64, u64)) -> (f64, f64, f64) {
+        if self.frames == 1 {
+            return (rgb.0 as f64, rgb.1 as f64, rgb.2 as f64);
+        }
+
+        if self.colors.len() == self.frames {
+            self.colors.pop_front();
+        }
+        self.colors.push_back(rgb);
+
+        let mut sum = (0.0, 0.0, 0.0);
+        let mut total_weight = 0.0;
+        let mut weight = 1.0;
+        for color in self.colors.iter().rev() {
+            sum.0 += color.0 as f64 * weight;
+            sum.1 += color.1 as f64 * weight;
+            sum.2 += color.2 as f64 * weight;
+            total_weight += weight;
+            weight *= self.decay;
+        }
+
+        (sum.0 / total_weight, sum.1 / total_weight, sum.2 / total_weight)
+    }
+}
+
+/// Variation threshold that follows how fast the screen colors change
+/// 
+/// Keeps the variation between the last few frames. When the colors change a lot the threshold
+/// goes up to send fewer colors, and when the screen is calm it goes down to react to small changes
+pub struct AdaptiveThreshold {
+    /// Threshold used when the colors change at the same pace as it
+    base: u64,
+    /// Variation between consecutive frames, oldest first
+    deltas: VecDeque<u64>,
+    /// Color of the previous frame
+    previous: Option<(u64, u64, u64)>,
+}
+
+impl AdaptiveThreshold {
+    /// How many frames the pace of the changes is measured over
+    const WINDOW: usize = 30;
+    /// Lowest and highest threshold, as multipliers of `base`
+    const MIN_FACTOR: f64 = 0.5;
+    const MAX_FACTOR: f64 = 3.0;
+
+    /// Create a new AdaptiveThreshold
+    /// 
+    /// # Arguments
+    /// * `base` - The configured variation threshold
+    pub fn new(base: u64) -> Self {
+        Self { base, deltas: VecDeque::with_capacity(Self::WINDOW), previous: None }
+    }
+
+    /// Add the color of a frame and get the threshold to use for it
+    /// 
+    /// Goes from half of `base` on a static screen to three times `base`
+    /// when the colors change by more than `base` on every frame
+    /// 
+    /// # Arguments
+    /// * `rgb` - The color of this frame
+    /// * `mode` - How the variation between colors is measured
+    pub fn update(&mut self, rgb: (u64, u64, u64), mode: VariationMode) -> u64 {
+        if let Some(previous) = self.previous {
+            if self.deltas.len() == Self::WINDOW {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(color_variation(rgb, previous, mode));
+        }
+        self.previous = Some(rgb);
+
+        if self.deltas.is_empty() || self.base == 0 {
+            return self.base;
+        }
+
+        let average_delta = self.deltas.iter().sum::<u64>() as f64 / self.deltas.len() as f64;
+        let factor = (Self::MIN_FACTOR + average_delta / self.base as f64).min(Self::MAX_FACTOR);
+
+        (self.base as f64 * factor).round() as u64
+    }
+}
+
+/// Increase the saturation and brightness of a color
+/// 
+/// The color is converted to HSV, its saturation and value are multiplied
+/// and clamped, and then it's converted back to RGB.
+/// Grays stay gray since their saturation is 0
+/// 
+/// # Arguments
+/// * `rgb` - The color to boost
+/// * `sat_mul` - The saturation multiplier, 1.0 = no change
+/// * `val_mul` - The value (brightness) multiplier, 1.0 = no change
+pub fn boost_color(rgb: (u64, u64, u64), sat_mul: f64, val_mul: f64) -> (u64, u64, u64) {
+    let (h, s, v) = rgb_to_hsv(rgb);
+
+    hsv_to_rgb(h, (s * sat_mul).clamp(0.0, 1.0), (v * val_mul).clamp(0.0, 1.0))
+}
+
+/// Map each channel of a color through an S-shaped tone curve, for a punchier light
+/// 
+/// The contrast steepens the curve around the middle, pushing darks down and brights up,
+/// and the lift raises the bottom of the curve so dark colors don't go fully black.
+/// Neutral parameters (1.0 and 0.0) return the color unchanged
+/// 
+/// # Arguments
+/// * `rgb` - The color to map
+/// * `contrast` - How steep the curve is, 1.0 = straight line, higher = more contrast
+/// * `lift` - Lowest output of the curve, from 0.0 to 1.0
+pub fn apply_tone_curve(rgb: (u64, u64, u64), contrast: f64, lift: f64) -> (u64, u64, u64) {
+    if contrast == 1.0 && lift == 0.0 {
+        return rgb;
+    }
+
+    let map = |channel: u64| {
+        let x = channel.min(255) as f64 / 255.0;
+        let curved = if x < 0.5 {
+            0.5 * (2.0 * x).powf(contrast)
+        } else {
+            1.0 - 0.5 * (2.0 * (1.0 - x)).powf(contrast)
+        };
+        let lifted = lift + (1.0 - lift) * curved;
+
+        (lifted.clamp(0.0, 1.0) * 255.0).round() as u64
+    };
+
+    (map(rgb.0), map(rgb.1), map(rgb.2))
+}
+
+/// Rotate the hue of a color, e.g. by 180 degrees to get its complementary color
+/// 
+/// Saturation and value are kept. Grays are returned unchanged, since they have no hue
+/// 
+/// # Arguments
+/// * `rgb` - The color to shift
+/// * `degrees` - How much the hue is rotated, can be negative
+pub fn shift_hue(rgb: (u64, u64, u64), degrees: f64) -> (u64, u64, u64) {
+    let (h, s, v) = rgb_to_hsv(rgb);
+    if s == 0.0 || degrees.rem_euclid(360.0) == 0.0 {
+        return rgb;
+    }
+
+    hsv_to_rgb((h + degrees).rem_euclid(360.0), s, v)
+}
+
+/// Convert an RGB color (0 to 255) to HSV
+/// 
+/// Returns the hue in degrees (0 to 360), and saturation and value from 0.0 to 1.0
+pub fn rgb_to_hsv(rgb: (u64, u64, u64)) -> (f64, f64, f64) {
+    let r = rgb.0.min(255) as f64 / 255.0;
+    let g = rgb.1.min(255) as f64 / 255.0;
+    let b = rgb.2.min(255) as f64 / 255.0;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+
+    (h, s, max)
+}
+
+/// Convert an HSV color to RGB (0 to 255)
+/// 
+/// # Arguments
+/// * `h` - The hue in degrees
+/// * `s` - The saturation, from 0.0 to 1.0
+/// * `v` - The value, from 0.0 to 1.0
+pub fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u64, u64, u64) {
+    let c = v * s;
+    let h = h.rem_euclid(360.0) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let m = v - c;
+
+    let (r, g, b) = match h as u64 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    (
+        ((r + m) * 255.0).round().clamp(0.0, 255.0) as u64,
+        ((g + m) * 255.0).round().clamp(0.0, 255.0) as u64,
+        ((b + m) * 255.0).round().clamp(0.0, 255.0) as u64,
+    )
+}
+
+/// Sum of the absolute differences of each RGB channel
+pub fn calculate_color_variation(rgb: (u64, u64, u64), previous_rgb: (u64, u64, u64)) -> u64 {
+    let r = rgb.0.abs_diff(previous_rgb.0);
+    let g = rgb.1.abs_diff(previous_rgb.1);
+    let b = rgb.2.abs_diff(previous_rgb.2);
+
+    r + g + b
+}
+
+/// Luma-weighted sum of the absolute differences of each RGB channel
+/// 
+/// Green changes count more than red, and red more than blue, like the eye perceives them.
+/// Scaled to the same range as `calculate_color_variation` (0 to 765)
+pub fn calculate_color_variation_weighted(rgb: (u64, u64, u64), previous_rgb: (u64, u64, u64)) -> u64 {
+    let r = (rgb.0 as f64 - previous_rgb.0 as f64).abs();
+    let g = (rgb.1 as f64 - previous_rgb.1 as f64).abs();
+    let b = (rgb.2 as f64 - previous_rgb.2 as f64).abs();
+
+    ((0.3 * r + 0.59 * g + 0.11 * b) * 3.0).round() as u64
+}
+
+/// CIE76 color difference (ΔE), the distance between the colors in CIELAB space
+/// 
+/// A difference of about 2.3 is the smallest one the eye notices
+pub fn calculate_color_variation_delta_e(rgb: (u64, u64, u64), previous_rgb: (u64, u64, u64)) -> u64 {
+    let (l1, a1, b1) = rgb_to_lab(rgb);
+    let (l2, a2, b2) = rgb_to_lab(previous_rgb);
+
+    ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt().round() as u64
+}
+
+/// Get the color variation using the configured `variation_mode`
+pub fn color_variation(rgb: (u64, u64, u64), previous_rgb: (u64, u64, u64), mode: VariationMode) -> u64 {
+    match mode {
+        VariationMode::Sum => calculate_color_variation(rgb, previous_rgb),
+        VariationMode::Weighted => calculate_color_variation_weighted(rgb, previous_rgb),
+        VariationMode::DeltaE => calculate_color_variation_delta_e(rgb, previous_rgb),
+    }
+}
+
+/// Parse a color written as `RRGGBB` or `#RRGGBB`, e.g. `FF8800`
+/// 
+/// # Arguments
+/// * `hex` - The color, in hexadecimal
+pub fn parse_hex_color(hex: &str) -> Result<(u64, u64, u64), String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid color ({}), it has to be 6 hexadecimal digits like FF8800", hex));
+    }
+
+    let channel = |start: usize| u64::from_str_radix(&digits[start..start + 2], 16).unwrap();
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+/// Clamp each channel of a color to 0 to 255, the range the lamps accept
+pub fn clamp_rgb(rgb: (u64, u64, u64)) -> (u64, u64, u64) {
+    (rgb.0.min(255), rgb.1.min(255), rgb.2.min(255))
+}
+
+/// Correct a color with a calibration matrix, e.g. for a lamp that doesn't show colors like the screen
+/// 
+/// Each output channel is the dot product of a matrix row with the input `[r, g, b]`,
+/// clamped to 0 to 255. The identity matrix leaves the color unchanged
+/// 
+/// # Arguments
+/// * `rgb` - The color to correct
+/// * `matrix` - The matrix, one row per output channel
+pub fn apply_calibration(rgb: (u64, u64, u64), matrix: &[[f64; 3]; 3]) -> (u64, u64, u64) {
+    let input = [rgb.0.min(255) as f64, rgb.1.min(255) as f64, rgb.2.min(255) as f64];
+    let channel = |row: &[f64; 3]| {
+        let value = row[0] * input[0] + row[1] * input[1] + row[2] * input[2];
+        value.round().clamp(0.0, 255.0) as u64
+    };
+
+    (channel(&matrix[0]), channel(&matrix[1]), channel(&matrix[2]))
+}
+
+/// Correlated color temperature of a color in Kelvin, for the `temp_mode`
+/// 
+/// Uses McCamy's approximation on the CIE xy chromaticity of the color, clamped to
+/// 2200K to 6500K (the range of WiZ lamps). Colors too far from white, like deep blues,
+/// give the coldest temperature, and black gives the temperature of white
+pub fn rgb_to_cct(rgb: (u64, u64, u64)) -> u64 {
+    const MIN_TEMP: f64 = 2200.0;
+    const MAX_TEMP: f64 = 6500.0;
+
+    let (r, g, b) = (srgb_to_linear(rgb.0), srgb_to_linear(rgb.1), srgb_to_linear(rgb.2));
+
+    // Linear sRGB to XYZ
+    let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+    let sum = x + y + z;
+    if sum == 0.0 {
+        return MAX_TEMP as u64;
+    }
+    let (x, y) = (x / sum, y / sum);
+
+    // The approximation breaks down below its epicenter, where only blues and purples are
+    if y <= 0.1858 {
+        return MAX_TEMP as u64;
+    }
+    let n = (x - 0.3320) / (y - 0.1858);
+    let temp = -449.0 * n.powi(3) + 3525.0 * n.powi(2) - 6823.3 * n + 5520.33;
+
+    temp.clamp(MIN_TEMP, MAX_TEMP).round() as u64
+}
+
+/// Relative luminance of a color, from 0.0 (black) to 1.0 (white)
+/// 
+/// Uses the Rec. 709 coefficients directly on the sRGB values
+pub fn luminance(rgb: (u64, u64, u64)) -> f64 {
+    (0.2126 * rgb.0.min(255) as f64 + 0.7152 * rgb.1.min(255) as f64 + 0.0722 * rgb.2.min(255) as f64) / 255.0
+}
+
+/// Average relative luminance of the pixels, from 0.0 (black) to 1.0 (white)
+/// 
+/// Unlike the color functions black pixels are counted, so dark scenes give a low luminance
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `pixel_skipping` - Only every nth pixel is used
+pub fn get_average_luminance(pixels: &[BGRA8], pixel_skipping: usize) -> f64 {
+    let mut sum = 0.0;
+    let mut sampled_count = 0;
+    for pixel in pixels.iter().step_by(pixel_skipping) {
+        sum += luminance((pixel.r as u64, pixel.g as u64, pixel.b as u64));
+        sampled_count += 1;
+    }
+
+    if sampled_count == 0 {
+        return 0.0;
+    }
+    sum / sampled_count as f64
+}
+
+/// Map a luminance from 0.0 to 1.0 onto a dimming range
+/// 
+/// # Arguments
+/// * `luminance` - The luminance, clamped to 0.0 to 1.0
+/// * `min_dimming` - The dimming of a black frame
+/// * `max_dimming` - The dimming of a white frame
+pub fn luminance_to_dimming(luminance: f64, min_dimming: u64, max_dimming: u64) -> u64 {
+    let range = max_dimming.saturating_sub(min_dimming) as f64;
+
+    min_dimming + (luminance.clamp(0.0, 1.0) * range).round() as u64
+}
+
+/// Convert an sRGB color (0 to 255) to CIELAB, using the D65 white point
+pub fn rgb_to_lab(rgb: (u64, u64, u64)) -> (f64, f64, f64) {
+    let (r, g, b) = (srgb_to_linear(rgb.0), srgb_to_linear(rgb.1), srgb_to_linear(rgb.2));
+
+    // Linear sRGB to XYZ, relative to the D65 white point
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// Check if two frames have the same pixels, comparing only one of every `stride` pixels
+/// 
+/// # Arguments
+/// * `a` - The pixels of the first frame
+/// * `b` - The pixels of the second frame
+/// * `stride` - How many pixels to skip between comparisons, 1 = compare every pixel
+pub fn frames_match(a: &[BGRA8], b: &[BGRA8], stride: usize) -> bool {
+    a.len() == b.len() && a.iter().step_by(stride).zip(b.iter().step_by(stride)).all(|(a, b)| a == b)
+}
+
+/// Check if the pixels are mostly black
+/// 
+/// Uses the same rule as the color functions: less than `black_threshold` of the pixels aren't (0,0,0)
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to check
+/// * `black_threshold` - Fraction of the pixels that have to be non-black
+pub fn is_mostly_black(pixels: &[BGRA8], black_threshold: f64) -> bool {
+    non_black(pixels, black_threshold).is_none()
+}
+
+/// Check if a pixel is (0,0,0), the color functions ignore those
+fn is_black(pixel: &BGRA8) -> bool {
+    pixel.r == 0 && pixel.g == 0 && pixel.b == 0
+}
+
+/// Get the pixels that aren't black, or `None` if there are too few of them, see `too_few_non_black()`
+/// 
+/// The color functions return (1,1,1) then, (0,0,0) is not accepted by the lamps
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to filter
+/// * `black_threshold` - Fraction of the pixels that have to be non-black
+fn non_black(pixels: &[BGRA8], black_threshold: f64) -> Option<impl Iterator<Item = &BGRA8> + Clone> {
+    let non_black = pixels.iter().filter(|pixel| !is_black(pixel));
+    if too_few_non_black(non_black.clone().count(), pixels.len(), black_threshold) {
+        return None;
+    }
+
+    Some(non_black)
+}
+
+/// Check if the amount of non-black pixels is less than `black_threshold` (a fraction) of the total
+/// 
+/// With no non-black pixels at all there's nothing to get a color from, even if `black_threshold` is 0
+fn too_few_non_black(non_black_count: usize, total_count: usize, black_threshold: f64) -> bool {
+    non_black_count == 0 || (non_black_count as f64) < total_count as f64 * black_threshold
+}
+
+/// Get the color of the pixels using the configured `color_mode`
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use, row by row
+/// * `width` - The width of the frame
+/// * `height` - The height of the frame
+/// * `config` - The configuration to use
+pub fn get_color(pixels: &[BGRA8], width: usize, height: usize, config: &Config) -> (u64, u64, u64) {
+    match config.color_mode {
+        ColorMode::Average if config.edge_weighting => {
+            get_average_color_edge_weighted(pixels, width, height, config.pixel_skipping, config.black_pixel_threshold)
+        }
+        ColorMode::Average => match config.color_space {
+            ColorSpace::Srgb => get_average_color(pixels, config.pixel_skipping, config.black_pixel_threshold),
+            ColorSpace::Linear => get_average_color_linear(pixels, config.pixel_skipping, config.black_pixel_threshold),
+            ColorSpace::Oklab => get_average_color_oklab(pixels, config.pixel_skipping, config.black_pixel_threshold),
+        },
+        ColorMode::Dominant => get_dominant_color(pixels, config.dominant_clusters, config.pixel_skipping, config.black_pixel_threshold),
+        ColorMode::Vibrant => get_vibrant_color(pixels, config.vibrant_buckets, config.pixel_skipping, config.black_pixel_threshold),
+        ColorMode::MostCommon => get_most_common_color(pixels, config.pixel_skipping, config.black_pixel_threshold),
+    }
+}
+
+/// Get the average color of the pixels
+/// 
+/// Black pixels are ignored. If less than `black_threshold` of the pixels aren't black, returns (1,1,1)
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `pixel_skipping` - Only every nth non-black pixel is used
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_average_color(pixels: &[BGRA8], pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    // Without skipping every non-black pixel is used, so a single pass is enough
+    if pixel_skipping <= 1 {
+        let ((r, g, b), pixel_count) = sum_channels(pixels);
+        if too_few_non_black(pixel_count as usize, pixels.len(), black_threshold) {
+            return (1, 1, 1);
+        }
+        return (r / pixel_count, g / pixel_count, b / pixel_count);
+    }
+
+    let mut r: u64 = 0;
+    let mut g: u64 = 0;
+    let mut b: u64 = 0;
+
+    // If amount of pixels after filtering out black is too low, return (1,1,1).
+    // (0,0,0) is not accepted by the lamps
+    let Some(filtered_pixels) = non_black(pixels, black_threshold) else {
+        return (1, 1, 1);
+    };
+    
+    // Divide by the pixels actually summed, not all the non-black ones,
+    // or the average gets darker the higher `pixel_skipping` is
+    let mut sampled_count: u64 = 0;
+    for pixel in filtered_pixels.step_by(pixel_skipping) {
+        r += pixel.r as u64;
+        g += pixel.g as u64;
+        b += pixel.b as u64;
+        sampled_count += 1;
+    }
+
+    (
+        (r / sampled_count),
+        (g / sampled_count),
+        (b / sampled_count),
+    )
+}
+
+/// Sum the channels of the pixels and count the non-black ones, in a single pass
+/// 
+/// Black pixels add nothing to the sums, so they don't have to be filtered out first.
+/// The pixels are summed in independent lanes the compiler can vectorize,
+/// with small sums per chunk that are added up at the end
+fn sum_channels(pixels: &[BGRA8]) -> ((u64, u64, u64), u64) {
+    // Small enough for the sums of a lane not to overflow a u32 (255 * CHUNK_SIZE / LANES)
+    const CHUNK_SIZE: usize = 1 << 16;
+    const LANES: usize = 8;
+
+    let mut totals = (0u64, 0u64, 0u64);
+    let mut non_black_count = 0u64;
+    for chunk in pixels.chunks(CHUNK_SIZE) {
+        let mut r = [0u32; LANES];
+        let mut g = [0u32; LANES];
+        let mut b = [0u32; LANES];
+        let mut count = [0u32; LANES];
+
+        let groups = chunk.chunks_exact(LANES);
+        let remainder = groups.remainder();
+        for group in groups {
+            for lane in 0..LANES {
+                let pixel = group[lane];
+                r[lane] += pixel.r as u32;
+                g[lane] += pixel.g as u32;
+                b[lane] += pixel.b as u32;
+                count[lane] += ((pixel.r | pixel.g | pixel.b) != 0) as u32;
+            }
+        }
+        for pixel in remainder {
+            r[0] += pixel.r as u32;
+            g[0] += pixel.g as u32;
+            b[0] += pixel.b as u32;
+            count[0] += ((pixel.r | pixel.g | pixel.b) != 0) as u32;
+        }
+
+        let sum = |lanes: [u32; LANES]| lanes.iter().map(|value| *value as u64).sum::<u64>();
+        totals.0 += sum(r);
+        totals.1 += sum(g);
+        totals.2 += sum(b);
+        non_black_count += sum(count);
+    }
+
+    (totals, non_black_count)
+}
+
+/// Get the average color of the pixels, weighting them by their distance to the center
+/// 
+/// Works like `get_average_color`, but pixels at the edges of the frame count the most and
+/// the center doesn't count at all. The edges are what spills onto the wall behind the monitor
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use, row by row
+/// * `width` - The width of the frame
+/// * `height` - The height of the frame
+/// * `pixel_skipping` - Only every nth non-black pixel is used
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_average_color_edge_weighted(pixels: &[BGRA8], width: usize, height: usize, pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    let mut r: f64 = 0.0;
+    let mut g: f64 = 0.0;
+    let mut b: f64 = 0.0;
+    let mut total_weight: f64 = 0.0;
+
+    // Same fallback as get_average_color, (0,0,0) is not accepted by the lamps
+    if width == 0 || height == 0 || non_black(pixels, black_threshold).is_none() {
+        return (1, 1, 1);
+    }
+
+    // Filter (0,0,0) pixels, keeping their position
+    let filtered_pixels = pixels.iter().enumerate().filter(|(_, pixel)| !is_black(pixel));
+
+    let center_x = width as f64 / 2.0;
+    let center_y = height as f64 / 2.0;
+    for (index, pixel) in filtered_pixels.step_by(pixel_skipping) {
+        // 0.0 at the center, 1.0 at the edges
+        let distance_x = ((index % width) as f64 + 0.5 - center_x).abs() / center_x;
+        let distance_y = ((index / width) as f64 + 0.5 - center_y).abs() / center_y;
+        let weight = distance_x.max(distance_y).powi(2);
+
+        r += pixel.r as f64 * weight;
+        g += pixel.g as f64 * weight;
+        b += pixel.b as f64 * weight;
+        total_weight += weight;
+    }
+
+    if total_weight == 0.0 {
+        return get_average_color(pixels, pixel_skipping, black_threshold);
+    }
+
+    ((r / total_weight).round() as u64, (g / total_weight).round() as u64, (b / total_weight).round() as u64)
+}
+
+/// Get the average color of the pixels, averaging in linear light
+/// 
+/// Works like `get_average_color`, but each channel is converted from sRGB to linear light
+/// with `srgb_to_linear()` before accumulating, and the result is converted back with `linear_to_srgb()`.
+/// This avoids the bias towards dark colors of averaging sRGB values directly
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `pixel_skipping` - Only every nth non-black pixel is used
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_average_color_linear(pixels: &[BGRA8], pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    // Linear value of each possible channel value
+    let to_linear: Vec<f64> = (0..256).map(srgb_to_linear).collect();
+
+    let mut r: f64 = 0.0;
+    let mut g: f64 = 0.0;
+    let mut b: f64 = 0.0;
+
+    // Same fallback as get_average_color, (0,0,0) is not accepted by the lamps
+    let Some(filtered_pixels) = non_black(pixels, black_threshold) else {
+        return (1, 1, 1);
+    };
+
+    let mut sampled_count = 0;
+    for pixel in filtered_pixels.step_by(pixel_skipping) {
+        r += to_linear[pixel.r as usize];
+        g += to_linear[pixel.g as usize];
+        b += to_linear[pixel.b as usize];
+        sampled_count += 1;
+    }
+
+    let sampled_count = sampled_count as f64;
+    (linear_to_srgb(r / sampled_count), linear_to_srgb(g / sampled_count), linear_to_srgb(b / sampled_count))
+}
+
+/// Get the average color of the pixels, averaging in OKLab
+/// 
+/// Works like `get_average_color`, but each pixel is converted to OKLab before accumulating,
+/// and the result is converted back to sRGB. Blends of different hues keep their lightness
+/// and saturation, e.g. red and green give a bright yellow instead of a dark olive
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `pixel_skipping` - Only every nth non-black pixel is used
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_average_color_oklab(pixels: &[BGRA8], pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    let mut l: f64 = 0.0;
+    let mut a: f64 = 0.0;
+    let mut b: f64 = 0.0;
+
+    // Same fallback as get_average_color, (0,0,0) is not accepted by the lamps
+    let Some(filtered_pixels) = non_black(pixels, black_threshold) else {
+        return (1, 1, 1);
+    };
+
+    let mut sampled_count = 0;
+    for pixel in filtered_pixels.step_by(pixel_skipping) {
+        let (pixel_l, pixel_a, pixel_b) = rgb_to_oklab((pixel.r as u64, pixel.g as u64, pixel.b as u64));
+        l += pixel_l;
+        a += pixel_a;
+        b += pixel_b;
+        sampled_count += 1;
+    }
+
+    let sampled_count = sampled_count as f64;
+    oklab_to_rgb((l / sampled_count, a / sampled_count, b / sampled_count))
+}
+
+/// Convert an sRGB channel (0 to 255) to linear light, between 0.0 and 1.0
+pub fn srgb_to_linear(value: u64) -> f64 {
+    let value = value.min(255) as f64 / 255.0;
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Convert a channel in linear light (0.0 to 1.0) to sRGB, clamped between 0 and 255
+pub fn linear_to_srgb(value: f64) -> u64 {
+    let value = value.clamp(0.0, 1.0);
+    let value = if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 };
+    (value * 255.0).round() as u64
+}
+
+/// Convert an sRGB color (0 to 255) to OKLab, as `(lightness, a, b)`
+pub fn rgb_to_oklab(rgb: (u64, u64, u64)) -> (f64, f64, f64) {
+    let (r, g, b) = (srgb_to_linear(rgb.0), srgb_to_linear(rgb.1), srgb_to_linear(rgb.2));
+
+    // Linear sRGB to the LMS cone responses, then the cube root to match how the eye perceives them
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    (
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    )
+}
+
+/// Convert an OKLab color to sRGB (0 to 255), clamping the colors out of the sRGB gamut
+pub fn oklab_to_rgb((lightness, a, b): (f64, f64, f64)) -> (u64, u64, u64) {
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+
+    (
+        linear_to_srgb(4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+        linear_to_srgb(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+        linear_to_srgb(-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s),
+    )
+}
+
+/// Get the most common saturated color of the pixels
+/// 
+/// Pixels are put in a histogram by hue, ignoring the grayish and dark ones,
+/// and the average of the fullest bucket is returned.
+/// The first bucket is centered on red, so the reds on both sides of 0° count together.
+/// Black pixels are ignored the same way `get_average_color` does.
+/// Falls back to `get_average_color` if there are no saturated pixels
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `buckets` - The number of hue buckets
+/// * `pixel_skipping` - Only every nth pixel is used
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_vibrant_color(pixels: &[BGRA8], buckets: usize, pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    // Pixels under these are too gray or too dark to count as vibrant
+    const MIN_SATURATION: f64 = 0.3;
+    const MIN_VALUE: f64 = 0.2;
+
+    // Same fallback as get_average_color, (0,0,0) is not accepted by the lamps
+    if non_black(pixels, black_threshold).is_none() {
+        return (1, 1, 1);
+    }
+
+    let buckets = buckets.max(1);
+    // Sum of the colors and amount of pixels in each bucket
+    let mut histogram = vec![((0u64, 0u64, 0u64), 0u64); buckets];
+    for pixel in pixels.iter().step_by(pixel_skipping) {
+        let rgb = (pixel.r as u64, pixel.g as u64, pixel.b as u64);
+        let (h, s, v) = rgb_to_hsv(rgb);
+        if s < MIN_SATURATION || v < MIN_VALUE {
+            continue;
+        }
+
+        let bucket = ((h / 360.0 * buckets as f64 + 0.5) as usize) % buckets;
+        let (sum, count) = &mut histogram[bucket];
+        sum.0 += rgb.0;
+        sum.1 += rgb.1;
+        sum.2 += rgb.2;
+        *count += 1;
+    }
+
+    match histogram.into_iter().max_by_key(|(_, count)| *count) {
+        Some((sum, count)) if count > 0 => (sum.0 / count, sum.1 / count, sum.2 / count),
+        _ => get_average_color(pixels, pixel_skipping, black_threshold),
+    }
+}
+
+/// Get the dominant color of the pixels using k-means clustering
+/// 
+/// Returns the centroid of the largest cluster.
+/// Black pixels are ignored the same way `get_average_color` does.
+/// 
+/// This is much slower than averaging: every iteration compares each sampled pixel
+/// with every centroid, so it's recommended to use a high `pixel_skipping` with it
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `k` - The number of clusters
+/// * `pixel_skipping` - Only every nth non-black pixel is sampled
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_dominant_color(pixels: &[BGRA8], k: usize, pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    const MAX_ITERATIONS: usize = 10;
+
+    // Same fallback as get_average_color, (0,0,0) is not accepted by the lamps
+    let Some(non_black) = non_black(pixels, black_threshold) else {
+        return (1, 1, 1);
+    };
+
+    let samples: Vec<(f64, f64, f64)> = non_black
+        .step_by(pixel_skipping)
+        .map(|pixel| (pixel.r as f64, pixel.g as f64, pixel.b as f64))
+        .collect();
+    let k = k.min(samples.len());
+
+    // Start with centroids spread evenly over the samples
+    let mut centroids: Vec<(f64, f64, f64)> = (0..k)
+        .map(|i| samples[i * samples.len() / k])
+        .collect();
+    let mut counts = vec![0usize; k];
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut sums = vec![(0.0, 0.0, 0.0); k];
+        counts = vec![0; k];
+
+        // Assign each sample to its closest centroid
+        for sample in samples.iter() {
+            let closest = (0..k)
+                .min_by(|a, b| {
+                    squared_distance(*sample, centroids[*a])
+                        .total_cmp(&squared_distance(*sample, centroids[*b]))
+                })
+                .unwrap();
+
+            sums[closest].0 += sample.0;
+            sums[closest].1 += sample.1;
+            sums[closest].2 += sample.2;
+            counts[closest] += 1;
+        }
+
+        // Move each centroid to the mean of its cluster
+        let mut moved = false;
+        for i in 0..k {
+            if counts[i] == 0 {
+                continue;
+            }
+
+            let count = counts[i] as f64;
+            let centroid = (sums[i].0 / count, sums[i].1 / count, sums[i].2 / count);
+            if centroid != centroids[i] {
+                centroids[i] = centroid;
+                moved = true;
+            }
+        }
+
+        if !moved {
+            break;
+        }
+    }
+
+    let largest = (0..k).max_by_key(|i| counts[*i]).unwrap();
+    let dominant = centroids[largest];
+
+    (dominant.0.round() as u64, dominant.1.round() as u64, dominant.2.round() as u64)
+}
+
+fn squared_distance(a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)
+}
+
+/// Turn the pixels inside some regions of the frame black, so the color functions ignore them
+/// 
+/// Black pixels still count towards the total of the black screen check
+/// 
+/// # Arguments
+/// * `pixels` - The frame pixels, row by row
+/// * `width` - The frame width
+/// * `height` - The frame height
+/// * `regions` - The regions to exclude, in fractional coordinates
+pub fn exclude_regions(pixels: &mut [BGRA8], width: usize, height: usize, regions: &[Region]) {
+    const BLACK: BGRA8 = BGRA8 { b: 0, g: 0, r: 0, a: 255 };
+
+    for region in regions {
+        let x_start = (region.x * width as f64) as usize;
+        let y_start = (region.y * height as f64) as usize;
+        let x_end = (((region.x + region.w) * width as f64).ceil() as usize).min(width);
+        let y_end = (((region.y + region.h) * height as f64).ceil() as usize).min(height);
+
+        for row in y_start..y_end {
+            pixels[row * width + x_start..row * width + x_end].fill(BLACK);
+        }
+    }
+}
+
+/// Extract the pixels inside a region of the frame
+/// 
+/// Returns the pixels and their size, like `capture_frame()`
+/// 
+/// # Arguments
+/// * `pixels` - The frame pixels, row by row
+/// * `width` - The frame width
+/// * `height` - The frame height
+/// * `region` - The region to extract, in fractional coordinates
+pub fn crop_region(pixels: &[BGRA8], width: usize, height: usize, region: &Region) -> (Vec<BGRA8>, (usize, usize)) {
+    sample_region(pixels, width, height, region, 1)
+}
+
+/// Extract the pixels inside a region of the frame, keeping only one of every `row_skipping` rows
+/// 
+/// Works like `crop_region()`. Skipping whole rows is much cheaper than skipping pixels
+/// with `pixel_skipping`, since the skipped rows are never read
+/// 
+/// # Arguments
+/// * `pixels` - The frame pixels, row by row
+/// * `width` - The frame width
+/// * `height` - The frame height
+/// * `region` - The region to extract, in fractional coordinates
+/// * `row_skipping` - 1 = keep every row, 2 = skip every other row, etc.
+pub fn sample_region(pixels: &[BGRA8], width: usize, height: usize, region: &Region, row_skipping: usize) -> (Vec<BGRA8>, (usize, usize)) {
+    let x_start = (region.x * width as f64) as usize;
+    let y_start = (region.y * height as f64) as usize;
+    let x_end = (((region.x + region.w) * width as f64) as usize).min(width);
+    let y_end = (((region.y + region.h) * height as f64) as usize).min(height);
+    let rows = (y_start..y_end).step_by(row_skipping.max(1));
+
+    let mut cropped = Vec::with_capacity((x_end - x_start) * rows.len());
+    for row in rows.clone() {
+        cropped.extend_from_slice(&pixels[row * width + x_start..row * width + x_end]);
+    }
+
+    (cropped, (x_end - x_start, rows.len()))
+}
+
+/// Shrink the frame to `dst_width`x`dst_height` by averaging the pixels each output pixel covers
+///
+/// Unlike `pixel_skipping`, every pixel counts towards the result, so small bright details
+/// aren't missed. The frame is returned as is if it's already smaller than the target size
+///
+/// Black pixels, like the excluded regions, are ignored the same way the color functions do,
+/// so they don't darken the colors next to them: an output pixel is the average of the non-black
+/// pixels it covers, or black if at least half of them are black. That also keeps the share of
+/// black pixels about the same for the black screen check
+///
+/// # Arguments
+/// * `frame` - The frame pixels, row by row
+/// * `src_width` - The frame width
+/// * `src_height` - The frame height
+/// * `dst_width` - The width of the downscaled frame
+/// * `dst_height` - The height of the downscaled frame
+pub fn downscale(frame: &[BGRA8], src_width: usize, src_height: usize, dst_width: usize, dst_height: usize) -> (Vec<BGRA8>, (usize, usize)) {
+    if dst_width == 0 || dst_height == 0 || (dst_width >= src_width && dst_height >= src_height) {
+        return (frame.to_vec(), (src_width, src_height));
+    }
+
+    let dst_width = dst_width.min(src_width);
+    let dst_height = dst_height.min(src_height);
+
+    let mut downscaled = Vec::with_capacity(dst_width * dst_height);
+    for dst_y in 0..dst_height {
+        let y_start = dst_y * src_height / dst_height;
+        let y_end = (dst_y + 1) * src_height / dst_height;
+
+        for dst_x in 0..dst_width {
+            let x_start = dst_x * src_width / dst_width;
+            let x_end = (dst_x + 1) * src_width / dst_width;
+
+            let (mut b, mut g, mut r) = (0, 0, 0);
+            let mut non_black_count = 0;
+            for row in y_start..y_end {
+                for pixel in &frame[row * src_width + x_start..row * src_width + x_end] {
+                    b += pixel.b as usize;
+                    g += pixel.g as usize;
+                    r += pixel.r as usize;
+                    non_black_count += !is_black(pixel) as usize;
+                }
+            }
+
+            let count = (x_end - x_start) * (y_end - y_start);
+            if non_black_count * 2 <= count {
+                downscaled.push(BGRA8 { b: 0, g: 0, r: 0, a: 255 });
+                continue;
+            }
+            downscaled.push(BGRA8 {
+                b: (b / non_black_count) as u8,
+                g: (g / non_black_count) as u8,
+                r: (r / non_black_count) as u8,
+                a: 255,
+            });
+        }
+    }
+
+    (downscaled, (dst_width, dst_height))
+}
+
+/// Get the most common color of the pixels
+/// 
+/// Colors are grouped in buckets of similar colors, so slight variations like gradients
+/// or video noise count as the same color, and the average of the fullest bucket is returned.
+/// Black pixels are ignored the same way `get_average_color` does
+/// 
+/// # Arguments
+/// * `pixels` - The pixels to use
+/// * `pixel_skipping` - Only every nth non-black pixel is used
+/// * `black_threshold` - Fraction of the pixels that have to be non-black, (1,1,1) is returned under it
+pub fn get_most_common_color(pixels: &[BGRA8], pixel_skipping: usize, black_threshold: f64) -> (u64, u64, u64) {
+    // Bits kept of each channel, 4 bits = 16 levels per channel and 4096 buckets
+    const BITS: u32 = 4;
+    const SHIFT: u32 = 8 - BITS;
+
+    // Same fallback as get_average_color, (0,0,0) is not accepted by the lamps
+    let Some(non_black) = non_black(pixels, black_threshold) else {
+        return (1, 1, 1);
+    };
+
+    // Sum of the colors and amount of pixels in each bucket
+    let mut buckets = vec![((0u64, 0u64, 0u64), 0u64); 1 << (3 * BITS)];
+    for pixel in non_black.step_by(pixel_skipping) {
+        let bucket = ((pixel.r as usize >> SHIFT) << (2 * BITS))
+            | ((pixel.g as usize >> SHIFT) << BITS)
+            | (pixel.b as usize >> SHIFT);
+        let (sum, count) = &mut buckets[bucket];
+        sum.0 += pixel.r as u64;
+        sum.1 += pixel.g as u64;
+        sum.2 += pixel.b as u64;
+        *count += 1;
+    }
+
+    match buckets.into_iter().max_by_key(|(_, count)| *count) {
+        Some((sum, count)) if count > 0 => (sum.0 / count, sum.1 / count, sum.2 / count),
+        _ => (1, 1, 1),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Same default as `black_pixel_threshold`
+    const BLACK_THRESHOLD: f64 = 0.1;
+
+    fn pixel(r: u8, g: u8, b: u8) -> BGRA8 {
+        BGRA8 { b, g, r, a: 255 }
+    }
+
+    /// A frame with `black` black pixels followed by `count` pixels of `color`
+    fn frame(black: usize, color: BGRA8, count: usize) -> Vec<BGRA8> {
+        let mut pixels = vec![pixel(0, 0, 0); black];
+        pixels.extend(vec![color; count]);
+        pixels
+    }
+
+    #[test]
+    fn average_of_a_white_frame() {
+        let pixels = frame(0, pixel(255, 255, 255), 100);
+
+        assert_eq!(get_average_color(&pixels, 1, BLACK_THRESHOLD), (255, 255, 255));
+    }
+
+    #[test]
+    fn average_of_a_mostly_black_frame() {
+        // (0,0,0) isn't accepted by the lamps, so the darkest color is returned
+        let pixels = frame(95, pixel(255, 255, 255), 5);
+
+        assert_eq!(get_average_color(&pixels, 1, BLACK_THRESHOLD), (1, 1, 1));
+    }
+
+    #[test]
+    fn average_ignores_black_pixels() {
+        let pixels = frame(50, pixel(100, 150, 200), 50);
+
+        assert_eq!(get_average_color(&pixels, 1, BLACK_THRESHOLD), (100, 150, 200));
+    }
+
+    #[test]
+    fn average_at_the_black_threshold() {
+        let color = pixel(100, 150, 200);
+
+        assert_eq!(get_average_color(&frame(91, color, 9), 1, BLACK_THRESHOLD), (1, 1, 1));
+        assert_eq!(get_average_color(&frame(90, color, 10), 1, BLACK_THRESHOLD), (100, 150, 200));
+    }
+
+    #[test]
+    fn pixel_skipping_uses_every_nth_non_black_pixel() {
+        // Black pixels are filtered before skipping, so only the red ones are used
+        let pixels = [pixel(255, 0, 0), pixel(0, 0, 0), pixel(0, 255, 0), pixel(255, 0, 0), pixel(0, 255, 0)];
+
+        assert_eq!(get_average_color(&pixels, 2, BLACK_THRESHOLD), (255, 0, 0));
+    }
+
+    #[test]
+    fn pixel_skipping_keeps_the_brightness() {
+        let pixels = frame(0, pixel(200, 100, 50), 100);
+
+        assert_eq!(get_average_color(&pixels, 1, BLACK_THRESHOLD), (200, 100, 50));
+        assert_eq!(get_average_color(&pixels, 4, BLACK_THRESHOLD), (200, 100, 50));
+    }
+
+    #[test]
+    fn variation_of_the_same_color() {
+        for mode in [VariationMode::Sum, VariationMode::Weighted, VariationMode::DeltaE] {
+            assert_eq!(color_variation((120, 60, 200), (120, 60, 200), mode), 0);
+        }
+    }
+
+    #[test]
+    fn variation_from_black_to_white() {
+        // The largest difference, the weighted one has the same range as the sum
+        assert_eq!(calculate_color_variation((255, 255, 255), (0, 0, 0)), 765);
+        assert_eq!(calculate_color_variation_weighted((255, 255, 255), (0, 0, 0)), 765);
+        assert_eq!(calculate_color_variation_delta_e((255, 255, 255), (0, 0, 0)), 100);
+    }
+
+    #[test]
+    fn variation_is_symmetric() {
+        for mode in [VariationMode::Sum, VariationMode::Weighted, VariationMode::DeltaE] {
+            assert_eq!(color_variation((255, 80, 0), (20, 40, 90), mode), color_variation((20, 40, 90), (255, 80, 0), mode));
+        }
+    }
+
+    #[test]
+    fn green_changes_count_more_than_blue_ones() {
+        let green_change = ((100, 150, 100), (100, 100, 100));
+        let blue_change = ((100, 100, 150), (100, 100, 100));
+
+        // The sum of the channels can't tell them apart
+        assert_eq!(calculate_color_variation(green_change.0, green_change.1), 50);
+        assert_eq!(calculate_color_variation(blue_change.0, blue_change.1), 50);
+
+        assert_eq!(calculate_color_variation_weighted(green_change.0, green_change.1), 89);
+        assert_eq!(calculate_color_variation_weighted(blue_change.0, blue_change.1), 17);
+
+        let green_delta_e = calculate_color_variation_delta_e(green_change.0, green_change.1);
+        let blue_delta_e = calculate_color_variation_delta_e(blue_change.0, blue_change.1);
+        assert!(green_delta_e > blue_delta_e, "green {} blue {}", green_delta_e, blue_delta_e);
+    }
+
+    #[test]
+    fn small_changes_are_under_the_noticeable_delta_e() {
+        // Under the 2.3 the eye notices, even though the sum isn't 0
+        assert_eq!(calculate_color_variation((101, 100, 101), (100, 100, 100)), 2);
+        assert!(calculate_color_variation_delta_e((101, 100, 101), (100, 100, 100)) < 2);
+    }
+
+    /// A frame with horizontal and vertical gradients and some fine detail, like a game or a video
+    fn gradient_frame(width: usize, height: usize) -> Vec<BGRA8> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| pixel((x * 255 / width) as u8, (y * 255 / height) as u8, ((x ^ y) & 0xff) as u8)))
+            .collect()
+    }
+
+    #[test]
+    fn row_skipping_keeps_one_of_every_n_rows() {
+        let pixels = gradient_frame(64, 36);
+        let full_frame = Region { x: 0.0, y: 0.0, w: 1.0, h: 1.0 };
+
+        let (sampled, size) = sample_region(&pixels, 64, 36, &full_frame, 4);
+
+        assert_eq!(size, (64, 9));
+        assert_eq!(&sampled[64..128], &pixels[4 * 64..5 * 64]);
+    }
+
+    #[test]
+    fn row_skipping_stays_close_to_the_full_average() {
+        let (width, height) = (640, 360);
+        let pixels = gradient_frame(width, height);
+        let full_frame = Region { x: 0.0, y: 0.0, w: 1.0, h: 1.0 };
+        let full_average = get_average_color(&pixels, 1, BLACK_THRESHOLD);
+
+        // Every 4th row and every 4th pixel of those reads 1/16 of the frame
+        for (row_skipping, pixel_skipping) in [(2, 1), (4, 1), (4, 4), (8, 2)] {
+            let (sampled, _) = sample_region(&pixels, width, height, &full_frame, row_skipping);
+            let average = get_average_color(&sampled, pixel_skipping, BLACK_THRESHOLD);
+
+            let difference = calculate_color_variation(average, full_average);
+            assert!(difference <= 6, "row_skipping {} pixel_skipping {}: {:?} vs {:?}", row_skipping, pixel_skipping, average, full_average);
+        }
+    }
+
+    #[test]
+    fn average_of_a_red_and_green_split_in_each_color_space() {
+        let mut pixels = frame(0, pixel(255, 0, 0), 50);
+        pixels.extend(frame(0, pixel(0, 255, 0), 50));
+
+        let srgb = get_average_color(&pixels, 1, BLACK_THRESHOLD);
+        let linear = get_average_color_linear(&pixels, 1, BLACK_THRESHOLD);
+        let oklab = get_average_color_oklab(&pixels, 1, BLACK_THRESHOLD);
+        assert_eq!(srgb, (127, 127, 0));
+        assert_eq!(linear, (188, 188, 0));
+        assert_eq!(oklab, (208, 168, 0));
+
+        // sRGB gives a muddy olive, darker than both red and green
+        let red_lightness = rgb_to_oklab((255, 0, 0)).0;
+        let green_lightness = rgb_to_oklab((0, 255, 0)).0;
+        assert!(rgb_to_oklab(srgb).0 < red_lightness.min(green_lightness));
+        // Linear light is brighter, and OKLab keeps the lightness halfway between them
+        assert!(rgb_to_oklab(linear).0 > red_lightness);
+        assert!((rgb_to_oklab(oklab).0 - (red_lightness + green_lightness) / 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn luminance_of_black_white_and_green() {
+        assert_eq!(get_average_luminance(&frame(0, pixel(0, 0, 0), 10), 1), 0.0);
+        assert!((get_average_luminance(&frame(0, pixel(255, 255, 255), 10), 1) - 1.0).abs() < 1e-9);
+        assert!((get_average_luminance(&frame(0, pixel(0, 255, 0), 10), 1) - 0.7152).abs() < 1e-9);
+    }
+
+    #[test]
+    fn luminance_counts_the_black_pixels() {
+        let pixels = frame(50, pixel(255, 255, 255), 50);
+
+        assert!((get_average_luminance(&pixels, 1) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn luminance_with_pixel_skipping() {
+        // Skipping every other pixel of a black and white stripe only sees the black ones
+        let pixels: Vec<BGRA8> = (0..100).map(|index| if index % 2 == 0 { pixel(0, 0, 0) } else { pixel(255, 255, 255) }).collect();
+
+        assert_eq!(get_average_luminance(&pixels, 2), 0.0);
+        assert_eq!(get_average_luminance(&[], 1), 0.0);
+    }
+
+    #[test]
+    fn luminance_maps_onto_the_dimming_range() {
+        assert_eq!(luminance_to_dimming(0.0, 20, 80), 20);
+        assert_eq!(luminance_to_dimming(0.5, 20, 80), 50);
+        assert_eq!(luminance_to_dimming(1.0, 20, 80), 80);
+        assert_eq!(luminance_to_dimming(-1.0, 20, 80), 20);
+        assert_eq!(luminance_to_dimming(2.0, 20, 80), 80);
+        assert_eq!(luminance_to_dimming(0.3, 100, 100), 100);
+    }
+
+    #[test]
+    fn vibrant_counts_the_reds_on_both_sides_of_0_degrees_together() {
+        // 355° and 5°, more than the orange at 30° together but not on their own
+        let mut pixels = frame(0, pixel(255, 0, 21), 30);
+        pixels.extend(frame(0, pixel(255, 21, 0), 30));
+        pixels.extend(frame(0, pixel(255, 128, 0), 40));
+
+        assert_eq!(get_vibrant_color(&pixels, 12, 1, BLACK_THRESHOLD), (255, 10, 10));
+    }
+
+    #[test]
+    fn vibrant_of_a_mostly_black_frame() {
+        let pixels = frame(99, pixel(255, 0, 0), 1);
+
+        assert_eq!(get_vibrant_color(&pixels, 12, 1, BLACK_THRESHOLD), (1, 1, 1));
+    }
+
+    /// Vertical lines one pixel wide, one bright red every `period` columns and dark blue otherwise
+    fn striped_frame(width: usize, height: usize, period: usize) -> Vec<BGRA8> {
+        (0..height)
+            .flat_map(|_| (0..width).map(|x| if x % period == 0 { pixel(255, 0, 0) } else { pixel(0, 0, 60) }))
+            .collect()
+    }
+
+    #[test]
+    fn downscaling_is_more_accurate_than_pixel_skipping() {
+        let (width, height) = (640, 360);
+        let pixels = striped_frame(width, height, 4);
+        let full_average = get_average_color(&pixels, 1, BLACK_THRESHOLD);
+
+        // Skipping lands on the same column of every stripe, it only sees the red lines
+        let skipped_average = get_average_color(&pixels, 4, BLACK_THRESHOLD);
+        let (downscaled, _) = downscale(&pixels, width, height, 64, 36);
+        let downscaled_average = get_average_color(&downscaled, 1, BLACK_THRESHOLD);
+
+        assert_eq!(skipped_average, (255, 0, 0));
+        assert!(calculate_color_variation(downscaled_average, full_average) <= 2, "{:?} vs {:?}", downscaled_average, full_average);
+    }
+
+    #[test]
+    fn downscaling_doesnt_darken_the_excluded_regions_edges() {
+        // The edge of the excluded region is in the middle of the downscaled pixels
+        let (width, height) = (100, 100);
+        let mut pixels = frame(0, pixel(40, 200, 120), width * height);
+        exclude_regions(&mut pixels, width, height, &[Region { x: 0.0, y: 0.0, w: 0.35, h: 1.0 }]);
+
+        let (downscaled, _) = downscale(&pixels, width, height, 10, 10);
+
+        assert!(downscaled.iter().all(|pixel| *pixel == BGRA8 { b: 0, g: 0, r: 0, a: 255 } || *pixel == BGRA8 { b: 120, g: 200, r: 40, a: 255 }));
+        assert_eq!(get_average_color(&downscaled, 1, BLACK_THRESHOLD), (40, 200, 120));
+    }
+
+    #[test]
+    fn downscaling_keeps_a_mostly_black_frame_black() {
+        // A few bright pixels spread over a black frame, like stars
+        let (width, height) = (160, 90);
+        let pixels: Vec<BGRA8> = (0..width * height)
+            .map(|index| if index % 37 == 0 { pixel(255, 255, 255) } else { pixel(0, 0, 0) })
+            .collect();
+        assert!(is_mostly_black(&pixels, BLACK_THRESHOLD));
+
+        let (downscaled, _) = downscale(&pixels, width, height, 16, 9);
+
+        assert!(is_mostly_black(&downscaled, BLACK_THRESHOLD));
+    }
+
+    #[test]
+    fn dithering_averages_to_the_unrounded_color() {
+        let mut smoother = ColorSmoother::new(0.0);
+        smoother.set_dither(1.0);
+
+        let colors: Vec<(u64, u64, u64)> = (0..10000).map(|_| smoother.update((10.25, 10.5, 10.75))).collect();
+
+        // Only the two closest values are sent
+        assert!(colors.iter().all(|color| (10..=11).contains(&color.0) && (10..=11).contains(&color.1) && (10..=11).contains(&color.2)));
+        let average = |channel: fn(&(u64, u64, u64)) -> u64| colors.iter().map(channel).sum::<u64>() as f64 / colors.len() as f64;
+        assert!((average(|color| color.0) - 10.25).abs() < 0.05);
+        assert!((average(|color| color.1) - 10.5).abs() < 0.05);
+        assert!((average(|color| color.2) - 10.75).abs() < 0.05);
+    }
+
+    #[test]
+    fn dithering_keeps_whole_colors() {
+        let mut smoother = ColorSmoother::new(0.0);
+        smoother.set_dither(1.0);
+
+        assert!((0..1000).all(|_| smoother.update((10.0, 128.0, 255.0)) == (10, 128, 255)));
+    }
+
+    #[test]
+    fn dithering_sees_the_blended_fractions() {
+        // Blending 10 and 11 gives 10.5, which the dithering spreads over both values
+        let mut blender = FrameBlender::new(2, 1.0);
+        let mut smoother = ColorSmoother::new(0.0);
+        smoother.set_dither(1.0);
+        blender.update((10, 10, 10));
+
+        let colors: Vec<(u64, u64, u64)> = (0..100).map(|index| smoother.update(blender.update((10 + index % 2, 10, 10)))).collect();
+
+        assert!(colors.contains(&(10, 10, 10)) && colors.contains(&(11, 10, 10)));
+    }
+}
@@ -0,0 +1,811 @@
+use log::warn;
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::keys::virtual_key_code;
+
+/// Default path of the configuration file, next to `ips.txt`
+pub const CONFIG_PATH: &str = "config.toml";
+/// Environment variable with the path of the configuration file, used instead of `CONFIG_PATH`
+pub const CONFIG_ENV: &str = "WIZ_SYNC_CONFIG";
+/// Default path of the file with the IPs of the lamps
+pub const IPS_PATH: &str = "ips.txt";
+/// Environment variable with the path of the file with the IPs of the lamps, used instead of `IPS_PATH`
+pub const IPS_ENV: &str = "WIZ_SYNC_IPS";
+
+/// Most scenes that can be set, one per number key from 1 to 9
+const MAX_SCENES: usize = 9;
+
+/// Runtime settings loaded from `config.toml`
+///
+/// Every field is optional in the file, missing ones take the default value.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Improves performance by skipping pixels. Reduces color accuracy.
+    /// 1 = no skipping, 2 = skip every other pixel, etc.
+    pub pixel_skipping: usize,
+    /// Improves performance by skipping whole rows of the screen, much cheaper than `pixel_skipping`.
+    /// 1 = no skipping, 2 = skip every other row, etc.
+    pub row_skipping: usize,
+    /// Shrink the frame to this `[width, height]` before extracting the colors, averaging the pixels.
+    /// Unlike the skipping every pixel counts. `[0, 0]` = no downscaling
+    pub downscale_size: [usize; 2],
+    /// If the color variation between iterations is lower than this value,
+    /// the program will not send a new color to the lamps
+    pub color_variation_threshold: u64,
+    /// Raise `color_variation_threshold` when the colors change fast, and lower it when the screen is calm
+    pub adaptive_threshold: bool,
+    /// How the color variation is measured
+    pub variation_mode: VariationMode,
+    /// How the color is extracted from the screen
+    pub color_mode: ColorMode,
+    /// Color space the `average` color mode averages in
+    pub color_space: ColorSpace,
+    /// Deprecated, use `color_space = "linear"`. `load()` turns it into that when `color_space` is `srgb`
+    pub linear_averaging: bool,
+    /// Weight the average towards the edges of the screen, for bias lighting.
+    /// Only used with `ColorMode::Average`, and takes precedence over `color_space`
+    pub edge_weighting: bool,
+    /// Number of clusters used by the `dominant` color mode
+    pub dominant_clusters: usize,
+    /// Number of hue buckets used by the `vibrant` color mode
+    pub vibrant_buckets: usize,
+    /// What to do with the lamps when the screen is mostly black
+    pub black_screen_behavior: BlackScreenBehavior,
+    /// Fraction of the pixels that have to be non-black for the screen (or a region) not to count as black.
+    /// 0.0 = only completely black frames count, for dark but colorful scenes
+    pub black_pixel_threshold: f64,
+    /// Color sent by the `min` black screen behavior, as `[r, g, b]`
+    pub black_screen_color: [u64; 3],
+    /// Dimming sent by the `min` black screen behavior, from 10 to 100
+    pub black_screen_dimming: u64,
+    /// Seconds the screen has to stay black, e.g. a monitor asleep or protected content, before
+    /// `black_screen_timeout_behavior` applies until a frame isn't black anymore. 0 = never
+    pub black_screen_timeout_secs: u64,
+    /// What to do with the lamps once the screen has been black for `black_screen_timeout_secs`
+    pub black_screen_timeout_behavior: BlackScreenTimeoutBehavior,
+    /// Only sync while a window whose title or program name contains this is focused,
+    /// e.g. a game or `vlc.exe`. Empty = always sync. Only works on Windows
+    pub sync_when_focused: String,
+    /// Set the lamps to the idle state while the `sync_when_focused` window isn't focused,
+    /// instead of keeping the last color
+    pub unfocused_idle: bool,
+    /// Seconds without a color change above the threshold before the lamps get the idle color,
+    /// until the screen changes again. 0 = never
+    pub idle_timeout_secs: u64,
+    /// Color of the lamps while idle, as `[r, g, b]`. Not used if `idle_temp` is set
+    pub idle_color: [u64; 3],
+    /// Color temperature of the lamps while idle in Kelvin, 0 = use `idle_color`
+    pub idle_temp: u64,
+    /// Dimming of the lamps while idle, from 10 to 100. 0 = turn them off, keeping their color
+    pub idle_dimming: u64,
+    /// Monitor to capture, 0 is the primary monitor
+    pub monitor_index: usize,
+    /// How many frames are skipped after the capture starts, since the first ones are often black or partial
+    pub warmup_frames: u32,
+    /// Show the live color and latency in the terminal instead of logging them
+    pub tui: bool,
+    /// Show the captured frame, the color sent and the crop and regions in a window.
+    /// Needs the `preview` feature
+    pub preview: bool,
+    /// Run without a terminal, e.g. as a service or at login: no `tui`, no quit key or scene keys.
+    /// The sync is stopped with Ctrl+C, a signal or the HTTP control endpoint
+    pub daemon: bool,
+    /// Log the min, average, max and 95th percentile of the frame times every this many seconds.
+    /// 0 = disabled
+    pub latency_stats_interval_secs: u64,
+    /// Key that stops the sync, e.g. `escape`, `q` or `f12`. Empty = no quit key.
+    /// Only works on Windows, Ctrl+C always stops the sync
+    pub quit_key: String,
+    /// Let `quit_key` stop the sync while any window is focused, not only this terminal
+    pub global_quit_key: bool,
+    /// Key that restores the lamps of the profile and switches to the next one, see `profiles`.
+    /// Only works on Windows, empty = none. Works from any window if `global_quit_key` is set
+    pub profile_key: String,
+    /// Key that holds the last color sent while the screen is still captured, pressed again to sync again.
+    /// Only works on Windows, empty = none. Works from any window if `global_quit_key` is set
+    pub lock_key: String,
+    /// Sets of lamps and settings, by name, selected with `--profile` or `profile_key`
+    pub profiles: BTreeMap<String, Profile>,
+    /// Write a JSON line to stdout each time a color is sent, instead of the `tui` output
+    pub json_status: bool,
+    /// Compute the colors without sending them to the lamps
+    pub dry_run: bool,
+    /// File where the initial state of the lamps is saved while syncing, to restore them
+    /// with `--restore` if the program doesn't exit cleanly. Empty = not saved
+    pub state_file: String,
+    /// TCP port of the HTTP server used to pause and resume the sync, 0 = disabled.
+    /// Needs the `http` feature
+    pub control_port: u16,
+    /// Reuse the colors of the previous frame when the screen doesn't change,
+    /// comparing one of every `static_frame_stride` pixels. 0 = always get the colors
+    pub static_frame_stride: usize,
+    /// Maximum number of frames processed per second. 0 = no limit
+    pub max_fps: u64,
+    /// UDP port the lamps listen on
+    pub lamps_port: u16,
+    /// Send the color to every lamp in the network with a single broadcast message
+    /// when all the lamps get the same color. Stays off if lamps not in `ips.txt` answer
+    pub broadcast_colors: bool,
+    /// Shortest time between two colors sent to the same lamp, in milliseconds. Colors that come sooner are held back
+    /// until it has passed.
+    /// WiZ lamps get unresponsive when they get more than about 10 colors per second. 0 = no limit
+    pub min_send_interval_ms: u64,
+    /// Most color changes per second the lamps make, whatever the screen does, for photosensitive viewers.
+    /// The previous color is held until the interval passes, even if the screen flashes. 0 = no limit
+    pub max_change_hz: f64,
+    /// How long to keep retrying the lamps that don't answer on start, e.g. while the network comes up.
+    /// 0 = try once
+    pub startup_timeout_secs: u64,
+    /// Skip the lamps that still don't answer after `startup_timeout_secs` instead of stopping
+    pub skip_unreachable: bool,
+    /// What to do when a color can't be sent to a lamp while syncing
+    pub on_lamp_error: OnLampError,
+    /// How often a lamp skipped by `on_lamp_error = "skip"` is checked, in seconds
+    pub lamp_recheck_secs: u64,
+    /// Sync the lamps that are off on start too, turning them on. When false they're left off and untouched
+    pub include_off_lamps: bool,
+    /// How many times `--identify` blinks the lamp
+    pub identify_blinks: u32,
+    /// How long each half of an `--identify` blink lasts, in milliseconds
+    pub identify_interval_ms: u64,
+    /// Color of the `--identify` blinks, as `[r, g, b]`
+    pub identify_color: [u64; 3],
+    /// How long to wait for a lamp to answer, in milliseconds. Can't be 0
+    pub read_timeout_ms: u64,
+    /// How many times a failed message to a lamp is retried
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds, it doubles on each retry
+    pub base_delay_ms: u64,
+    /// Saturation multiplier applied to the screen color. 1.0 = no change
+    pub saturation_boost: f64,
+    /// Brightness multiplier applied to the screen color. 1.0 = no change
+    pub brightness_boost: f64,
+    /// Steepness of the S-shaped tone curve applied to the screen color, 1.0 = no curve
+    pub tone_contrast: f64,
+    /// Lowest output of the tone curve, from 0.0 to 1.0, so dark colors still light up. 0.0 = no lift
+    pub tone_lift: f64,
+    /// Rotate the hue of the screen colors by this many degrees before sending them
+    pub hue_shift: f64,
+    /// Send the complementary color of the screen, adds 180 degrees to `hue_shift`
+    pub complementary: bool,
+    /// Send the colors to every lamp as a color temperature, for tunable white lamps.
+    /// The brightness of the color is sent as the dimming
+    pub temp_mode: bool,
+    /// IPs of the lamps that get the colors as a color temperature even if `temp_mode` isn't set.
+    /// Lamps that can't show RGB always get a temperature
+    pub temp_lamps: Vec<String>,
+    /// Lowest dimming sent to the lamps while syncing, from 10 to 100
+    pub min_dimming: u64,
+    /// Highest dimming sent to the lamps while syncing, from 10 to 100
+    pub max_dimming: u64,
+    /// Derive the dimming from the average luminance of the screen, between `min_dimming` and `max_dimming`.
+    /// On by default, if not set the lamps always use `max_dimming`
+    pub dimming_from_luminance: bool,
+    /// Most the dimming can change per frame, so the brightness eases into big changes
+    /// while the color still follows the screen. 0 = no limit
+    pub max_dimming_step: u64,
+    /// How much of the previous color is kept on each iteration.
+    /// 0.0 = instant changes, 0.9 = very smooth changes
+    pub smoothing_factor: f64,
+    /// Random offset added to the smoothed colors before rounding them, in color steps from 0.0 to 1.0.
+    /// Hides banding in slow fades at low brightness, only with `smoothing_factor`, `lowpass_cutoff_hz`
+    /// or `blend_frames`, which make colors between two steps. 0.0 = no dithering
+    pub dither_amount: f64,
+    /// Average the color over this many frames, to hide flicker from noisy or dithered content.
+    /// 1 = only the current frame
+    pub blend_frames: usize,
+    /// Weight of each frame in `blend_frames` relative to the next newer one, from 0.0 to 1.0.
+    /// 1.0 = plain average, lower values follow the newest frames more
+    pub blend_decay: f64,
+    /// Cutoff frequency of the low-pass filter applied to each channel, in Hz.
+    /// Changes faster than this (flicker) are damped, 0 = no filtering
+    pub lowpass_cutoff_hz: f64,
+    /// How many consecutive frames a color change has to last before it's sent.
+    /// 1 = send right away, higher values ignore flashes but add that many frames of delay
+    pub debounce_frames: u32,
+    /// Fade time the lamps use for every color change while syncing, in milliseconds.
+    /// 0 = instant changes
+    pub sync_fade_speed: u64,
+    /// Margins cut from the captured frame before getting its color, e.g. to ignore letterboxing
+    pub capture_crop: CaptureCrop,
+    /// Parts of the screen ignored when getting its color, e.g. a taskbar or a game HUD.
+    /// Relative to the cropped screen
+    pub excluded_regions: Vec<Region>,
+    /// Screen region each lamp syncs to, by lamp IP.
+    /// Lamps without a region sync to the whole screen
+    pub regions: HashMap<String, Region>,
+    /// Named sets of lamps, by group name, as lists of lamp IPs. Scenes can be applied to a group
+    pub groups: HashMap<String, Vec<String>>,
+    /// Color correction of each lamp, by lamp IP.
+    /// Lamps without one get the screen colors unchanged
+    pub calibration: HashMap<String, Calibration>,
+    /// Offset of the white of each lamp, as `[r, g, b]` from -255 to 255, by lamp IP.
+    /// Each channel sent to the lamp is scaled by `(255 + offset) / 255`, after its `calibration`,
+    /// so lamps that look warmer or colder than the others match them. Found with `--calibrate`
+    pub white_point_offset: HashMap<String, [i64; 3]>,
+    /// Preset states of the lamps, applied with the number keys while syncing (1 is the first one).
+    /// The sync stops while a scene is applied, 0 starts it again
+    pub scenes: Vec<Scene>,
+}
+
+/// A set of lamps and settings, see `Config::profiles`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    /// File with the lamps of the profile, in the `ips.txt` format
+    pub ips: String,
+    /// TOML file with settings that replace the ones of `config.toml` for this profile. Empty = none
+    pub config: String,
+}
+
+/// Algorithm used to extract a color from the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Average of all the pixels
+    Average,
+    /// Largest cluster found with k-means.
+    /// Gives more vivid colors on colorful scenes, but is a lot slower than `Average`
+    Dominant,
+    /// Most common saturated hue, found with a histogram.
+    /// Avoids gray results like `Dominant`, but is much cheaper
+    Vibrant,
+    /// Most common color, grouping similar colors together.
+    /// Follows large flat areas, like a UI background, instead of blending everything
+    #[serde(rename = "most_common")]
+    #[value(name = "most_common")]
+    MostCommon,
+}
+
+/// Color space the pixels are averaged in, see `Config::color_space`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Average the sRGB values directly, the fastest but biased towards dark colors
+    Srgb,
+    /// Average in linear light, brighter and more accurate on bright scenes
+    Linear,
+    /// Average in OKLab, blends of different hues keep their lightness and saturation
+    Oklab,
+}
+
+/// How the variation between two colors is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VariationMode {
+    /// Sum of the RGB channels differences, 0 to 765
+    Sum,
+    /// Sum of the RGB channels differences weighted by how the eye perceives them, 0 to 765
+    Weighted,
+    /// CIE76 color difference, around 0 to 100. 2.3 is about the smallest noticeable difference
+    DeltaE,
+}
+
+/// What to do with the lamps when the screen is mostly black
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlackScreenBehavior {
+    /// Set the lamps to `black_screen_color` and `black_screen_dimming`,
+    /// by default the lowest color they accept, (1,1,1)
+    Min,
+    /// Set the lamps to a dim warm white
+    Dim,
+    /// Turn the lamps off
+    Off,
+}
+
+/// What to do with the lamps when the screen stays black for `black_screen_timeout_secs`
+///
+/// Nothing is sent to the lamps afterwards, until the screen isn't black anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlackScreenTimeoutBehavior {
+    /// Keep the `black_screen_behavior` state, lamps added meanwhile don't get it
+    Hold,
+    /// Turn the lamps off
+    Off,
+    /// Give the lamps back the state they had before syncing, like a pause
+    Restore,
+}
+
+/// What to do when a color can't be sent to a lamp while syncing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnLampError {
+    /// Stop the sync and restore the lamps
+    Exit,
+    /// Stop sending to the lamp, and check every `lamp_recheck_secs` if it answers again
+    Skip,
+    /// Send the color again on the next frame
+    Retry,
+}
+
+/// Preset state of the lamps, applied with a number key while syncing
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Scene {
+    /// Name shown when the scene is applied
+    pub name: String,
+    /// Color of the lamps, as `[r, g, b]`. Not used if `temp` is set
+    pub rgb: [u64; 3],
+    /// Color temperature of the lamps in Kelvin, 0 = use `rgb`
+    pub temp: u64,
+    /// Dimming of the lamps, from 10 to 100
+    pub dimming: u64,
+    /// If the lamps are on, false turns them off
+    pub on: bool,
+    /// Group of lamps the scene is applied to, empty = every lamp
+    pub group: String,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            rgb: [255, 255, 255],
+            temp: 0,
+            dimming: 100,
+            on: true,
+            group: String::new(),
+        }
+    }
+}
+
+impl Scene {
+    /// Get the color of the scene as a tuple, like the screen colors
+    pub fn rgb(&self) -> (u64, u64, u64) {
+        (self.rgb[0], self.rgb[1], self.rgb[2])
+    }
+}
+
+/// Rectangle of the screen, in fractional coordinates (0.0 to 1.0)
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+}
+
+impl Region {
+    /// Check if the region fits in the screen and has a size
+    pub fn is_inside_screen(&self) -> bool {
+        self.x >= 0.0 && self.y >= 0.0
+            && self.w > 0.0 && self.h > 0.0
+            && self.x + self.w <= 1.0 && self.y + self.h <= 1.0
+    }
+}
+
+/// Color correction of a lamp, applied to the RGB colors sent to it while syncing
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Calibration {
+    /// Multiplier of each channel, as `[r, g, b]`
+    Gain { gain: [f64; 3] },
+    /// Matrix the color is multiplied by, one row per output channel
+    Matrix { matrix: [[f64; 3]; 3] },
+}
+
+impl Calibration {
+    /// Get the correction as a matrix, for `apply_calibration()`
+    pub fn matrix(&self) -> [[f64; 3]; 3] {
+        match *self {
+            Calibration::Gain { gain: [r, g, b] } => [[r, 0.0, 0.0], [0.0, g, 0.0], [0.0, 0.0, b]],
+            Calibration::Matrix { matrix } => matrix,
+        }
+    }
+}
+
+/// Margins of the screen, as fractions of its size (0.0 to 1.0)
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct CaptureCrop {
+    pub top: f64,
+    pub bottom: f64,
+    pub left: f64,
+    pub right: f64,
+}
+
+impl CaptureCrop {
+    /// Check if any margin is set
+    pub fn is_empty(&self) -> bool {
+        self.top == 0.0 && self.bottom == 0.0 && self.left == 0.0 && self.right == 0.0
+    }
+
+    /// Get the part of the screen left inside the margins
+    pub fn region(&self) -> Region {
+        Region {
+            x: self.left,
+            y: self.top,
+            w: 1.0 - self.left - self.right,
+            h: 1.0 - self.top - self.bottom,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pixel_skipping: 1,
+            row_skipping: 1,
+            downscale_size: [0, 0],
+            color_variation_threshold: 20,
+            adaptive_threshold: false,
+            variation_mode: VariationMode::Sum,
+            color_mode: ColorMode::Average,
+            color_space: ColorSpace::Srgb,
+            linear_averaging: false,
+            edge_weighting: false,
+            dominant_clusters: 4,
+            vibrant_buckets: 12,
+            black_screen_behavior: BlackScreenBehavior::Min,
+            black_pixel_threshold: 0.1,
+            black_screen_color: [1, 1, 1],
+            black_screen_dimming: 100,
+            black_screen_timeout_secs: 0,
+            black_screen_timeout_behavior: BlackScreenTimeoutBehavior::Off,
+            sync_when_focused: String::new(),
+            unfocused_idle: false,
+            idle_timeout_secs: 0,
+            idle_color: [255, 255, 255],
+            idle_temp: 2700,
+            idle_dimming: 60,
+            monitor_index: 0,
+            warmup_frames: 3,
+            tui: false,
+            preview: false,
+            daemon: false,
+            latency_stats_interval_secs: 0,
+            quit_key: "escape".to_string(),
+            global_quit_key: false,
+            profile_key: String::new(),
+            lock_key: String::new(),
+            profiles: BTreeMap::new(),
+            json_status: false,
+            dry_run: false,
+            state_file: "lamps_state.json".to_string(),
+            control_port: 0,
+            static_frame_stride: 0,
+            max_fps: 0,
+            lamps_port: 38899,
+            broadcast_colors: false,
+            min_send_interval_ms: 100,
+            max_change_hz: 0.0,
+            startup_timeout_secs: 30,
+            skip_unreachable: false,
+            on_lamp_error: OnLampError::Retry,
+            lamp_recheck_secs: 10,
+            include_off_lamps: true,
+            identify_blinks: 3,
+            identify_interval_ms: 300,
+            identify_color: [255, 255, 255],
+            read_timeout_ms: 400,
+            retries: 2,
+            base_delay_ms: 50,
+            saturation_boost: 1.0,
+            brightness_boost: 1.0,
+            tone_contrast: 1.0,
+            tone_lift: 0.0,
+            hue_shift: 0.0,
+            complementary: false,
+            temp_mode: false,
+            temp_lamps: Vec::new(),
+            min_dimming: 10,
+            max_dimming: 100,
+            dimming_from_luminance: true,
+            max_dimming_step: 0,
+            smoothing_factor: 0.0,
+            dither_amount: 0.0,
+            blend_frames: 1,
+            blend_decay: 0.7,
+            lowpass_cutoff_hz: 0.0,
+            debounce_frames: 1,
+            sync_fade_speed: 0,
+            capture_crop: CaptureCrop::default(),
+            excluded_regions: Vec::new(),
+            regions: HashMap::new(),
+            groups: HashMap::new(),
+            calibration: HashMap::new(),
+            white_point_offset: HashMap::new(),
+            scenes: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from a TOML file
+    ///
+    /// Falls back to the default configuration if the file doesn't exist
+    ///
+    /// # Arguments
+    /// * `path` - The path of the configuration file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut config = match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| format!("Error parsing {}: {}", path, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("Error reading {}: {}", path, e)),
+        };
+
+        config.replace_deprecated();
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Load the configuration of a profile
+    ///
+    /// Works like `load()`, then the settings in the `config` file of the profile replace
+    /// the ones of `path`. Tables like `regions` are replaced whole, not merged
+    ///
+    /// # Arguments
+    /// * `path` - The path of the configuration file with the profiles
+    /// * `name` - The name of the profile
+    ///
+    /// Returns the configuration and the profile
+    pub fn load_profile(path: &str, name: &str) -> Result<(Self, Profile), String> {
+        let config = Self::load(path)?;
+        let Some(profile) = config.profiles.get(name).cloned() else {
+            let names: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+            return Err(format!("Unknown profile {}, the profiles in {} are: {}", name, path, names.join(", ")));
+        };
+        if profile.config.is_empty() {
+            return Ok((config, profile));
+        }
+
+        let mut settings = match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str::<toml::value::Table>(&contents)
+                .map_err(|e| format!("Error parsing {}: {}", path, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => toml::value::Table::new(),
+            Err(e) => return Err(format!("Error reading {}: {}", path, e)),
+        };
+        let contents = fs::read_to_string(&profile.config)
+            .map_err(|e| format!("Error reading {}: {}", profile.config, e))?;
+        let profile_settings: toml::value::Table = toml::from_str(&contents)
+            .map_err(|e| format!("Error parsing {}: {}", profile.config, e))?;
+        settings.extend(profile_settings);
+
+        let mut config: Config = toml::Value::Table(settings).try_into()
+            .map_err(|e| format!("Error parsing {}: {}", profile.config, e))?;
+        config.replace_deprecated();
+        config.validate()?;
+
+        Ok((config, profile))
+    }
+
+    /// Replace the deprecated settings with the ones that took their place, with a warning
+    fn replace_deprecated(&mut self) {
+        if self.linear_averaging {
+            warn!("linear_averaging is deprecated, use color_space = \"linear\" instead");
+            if self.color_space == ColorSpace::Srgb {
+                self.color_space = ColorSpace::Linear;
+            }
+        }
+    }
+
+    /// Get the color correction of each lamp, for `LightCommunication::set_calibrations()`
+    ///
+    /// Combines the `calibration` and the `white_point_offset` of each lamp into one matrix
+    pub fn lamp_calibrations(&self) -> HashMap<String, [[f64; 3]; 3]> {
+        let mut calibrations: HashMap<String, [[f64; 3]; 3]> = self.calibration.iter()
+            .map(|(ip, calibration)| (ip.to_string(), calibration.matrix()))
+            .collect();
+
+        for (ip, offset) in self.white_point_offset.iter() {
+            let matrix = calibrations.entry(ip.to_string())
+                .or_insert_with(|| Calibration::Gain { gain: [1.0; 3] }.matrix());
+            // Scaling a row scales the output channel
+            for (row, offset) in matrix.iter_mut().zip(offset) {
+                let gain = (255 + offset) as f64 / 255.0;
+                row.iter_mut().for_each(|value| *value *= gain);
+            }
+        }
+
+        calibrations
+    }
+
+    /// Check that the values are usable
+    ///
+    /// Already done by `load()`, call it again after changing the values
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pixel_skipping < 1 {
+            return Err(format!("Invalid pixel_skipping ({}), it has to be 1 or more", self.pixel_skipping));
+        }
+
+        if self.row_skipping < 1 {
+            return Err(format!("Invalid row_skipping ({}), it has to be 1 or more", self.row_skipping));
+        }
+
+        if (self.downscale_size[0] == 0) != (self.downscale_size[1] == 0) {
+            return Err(format!(
+                "Invalid downscale_size ({:?}), both sizes have to be 0 or more than 0",
+                self.downscale_size
+            ));
+        }
+
+        if self.blend_frames < 1 || !(0.0..=1.0).contains(&self.blend_decay) {
+            return Err(format!(
+                "Invalid blend_frames ({}) or blend_decay ({}), blend_frames has to be 1 or more and blend_decay between 0.0 and 1.0",
+                self.blend_frames, self.blend_decay
+            ));
+        }
+
+        if self.lowpass_cutoff_hz < 0.0 || self.lowpass_cutoff_hz.is_nan() {
+            return Err(format!("Invalid lowpass_cutoff_hz ({}), it can't be negative", self.lowpass_cutoff_hz));
+        }
+
+        if self.debounce_frames < 1 {
+            return Err(format!("Invalid debounce_frames ({}), it has to be 1 or more", self.debounce_frames));
+        }
+
+        if self.vibrant_buckets < 1 {
+            return Err(format!("Invalid vibrant_buckets ({}), it has to be 1 or more", self.vibrant_buckets));
+        }
+
+        if self.read_timeout_ms == 0 {
+            return Err("Invalid read_timeout_ms (0), it has to be 1 or more".to_string());
+        }
+
+        if self.dominant_clusters < 1 {
+            return Err(format!("Invalid dominant_clusters ({}), it has to be 1 or more", self.dominant_clusters));
+        }
+
+        if self.tone_contrast <= 0.0 || !self.tone_contrast.is_finite() || !(0.0..1.0).contains(&self.tone_lift) {
+            return Err(format!(
+                "Invalid tone_contrast ({}) or tone_lift ({}), the contrast has to be more than 0.0 and the lift between 0.0 and 1.0",
+                self.tone_contrast, self.tone_lift
+            ));
+        }
+
+        if !self.hue_shift.is_finite() {
+            return Err(format!("Invalid hue_shift ({}), it has to be a number of degrees", self.hue_shift));
+        }
+
+        if self.saturation_boost < 0.0 || self.brightness_boost < 0.0 {
+            return Err("Invalid saturation_boost or brightness_boost, they can't be negative".to_string());
+        }
+
+        if !(10..=100).contains(&self.min_dimming) || !(10..=100).contains(&self.max_dimming) || self.min_dimming > self.max_dimming {
+            return Err(format!(
+                "Invalid min_dimming ({}) or max_dimming ({}), they have to be between 10 and 100 and min_dimming can't be higher",
+                self.min_dimming, self.max_dimming
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.black_pixel_threshold) {
+            return Err(format!("Invalid black_pixel_threshold ({}), it has to be between 0.0 and 1.0", self.black_pixel_threshold));
+        }
+
+        if !(10..=100).contains(&self.black_screen_dimming) || self.black_screen_color.iter().any(|channel| *channel > 255) {
+            return Err("Invalid black_screen_dimming or black_screen_color, the dimming has to be between 10 and 100 and the color between 0 and 255".to_string());
+        }
+
+        if !(self.idle_dimming == 0 || (10..=100).contains(&self.idle_dimming)) || self.idle_color.iter().any(|channel| *channel > 255) {
+            return Err("Invalid idle_dimming or idle_color, the dimming has to be 0 or between 10 and 100 and the color between 0 and 255".to_string());
+        }
+
+        if self.idle_temp != 0 && !(2200..=6500).contains(&self.idle_temp) {
+            return Err(format!("Invalid idle_temp ({}), it has to be 0 or between 2200 and 6500", self.idle_temp));
+        }
+
+        if self.identify_blinks == 0 || self.identify_interval_ms == 0 || self.identify_color.iter().any(|channel| *channel > 255) {
+            return Err("Invalid identify_blinks, identify_interval_ms or identify_color, the blinks and interval have to be 1 or more and the color between 0 and 255".to_string());
+        }
+
+        if self.max_change_hz < 0.0 || !self.max_change_hz.is_finite() {
+            return Err(format!("Invalid max_change_hz ({}), it has to be 0 or more", self.max_change_hz));
+        }
+
+        if !(0.0..=1.0).contains(&self.dither_amount) {
+            return Err(format!("Invalid dither_amount ({}), it has to be between 0.0 and 1.0", self.dither_amount));
+        }
+
+        if !(0.0..1.0).contains(&self.smoothing_factor) {
+            return Err(format!("Invalid smoothing_factor ({}), it has to be between 0.0 and 1.0 (not included)", self.smoothing_factor));
+        }
+
+        let crop = &self.capture_crop;
+        let valid_margins = [crop.top, crop.bottom, crop.left, crop.right].iter().all(|margin| *margin >= 0.0)
+            && crop.top + crop.bottom < 1.0 && crop.left + crop.right < 1.0;
+        if !valid_margins {
+            return Err("Invalid capture_crop, the margins can't be negative and have to leave part of the screen".to_string());
+        }
+
+        for (ip, region) in self.regions.iter() {
+            if !region.is_inside_screen() {
+                return Err(format!("Invalid region for {}, it has to fit between 0.0 and 1.0 and have a size", ip));
+            }
+        }
+
+        if !self.excluded_regions.iter().all(Region::is_inside_screen) {
+            return Err("Invalid excluded_regions, they have to fit between 0.0 and 1.0 and have a size".to_string());
+        }
+
+        if !self.quit_key.is_empty() && virtual_key_code(&self.quit_key).is_none() {
+            return Err(format!("Invalid quit_key ({}), use a key name like \"escape\", \"q\" or \"f12\"", self.quit_key));
+        }
+
+        if !self.profile_key.is_empty() && virtual_key_code(&self.profile_key).is_none() {
+            return Err(format!("Invalid profile_key ({}), use a key name like \"f9\" or \"p\"", self.profile_key));
+        }
+
+        if !self.lock_key.is_empty() && virtual_key_code(&self.lock_key).is_none() {
+            return Err(format!("Invalid lock_key ({}), use a key name like \"f8\" or \"l\"", self.lock_key));
+        }
+
+        for (name, profile) in self.profiles.iter() {
+            if profile.ips.is_empty() {
+                return Err(format!("Invalid profile {}, it needs an ips file", name));
+            }
+        }
+
+        for (ip, offset) in self.white_point_offset.iter() {
+            if !offset.iter().all(|value| (-255..=255).contains(value)) {
+                return Err(format!("Invalid white_point_offset for {}, its values have to be between -255 and 255", ip));
+            }
+        }
+
+        for (ip, calibration) in self.calibration.iter() {
+            if !calibration.matrix().iter().flatten().all(|value| value.is_finite()) {
+                return Err(format!("Invalid calibration for {}, its values have to be numbers", ip));
+            }
+        }
+
+        for (name, ips) in self.groups.iter() {
+            if ips.is_empty() {
+                return Err(format!("Invalid group {}, it has to have at least one lamp", name));
+            }
+        }
+
+        if self.scenes.len() > MAX_SCENES {
+            return Err(format!("Too many scenes ({}), only {} can be applied with the number keys", self.scenes.len(), MAX_SCENES));
+        }
+
+        for scene in self.scenes.iter() {
+            if !(10..=100).contains(&scene.dimming) || scene.rgb.iter().any(|channel| *channel > 255) {
+                return Err(format!("Invalid scene {}, its dimming has to be between 10 and 100 and its rgb between 0 and 255", scene.name));
+            }
+            if scene.temp != 0 && !(2200..=6500).contains(&scene.temp) {
+                return Err(format!("Invalid scene {}, its temp has to be between 2200 and 6500", scene.name));
+            }
+            if !scene.group.is_empty() && !self.groups.contains_key(&scene.group) {
+                return Err(format!("Invalid scene {}, there's no group called {}", scene.name, scene.group));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a configuration written to a temporary file
+    fn load_toml(name: &str, contents: &str) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!("wiz-screen-sync-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn linear_averaging_is_read_as_the_linear_color_space() {
+        let config = load_toml("linear-averaging", "linear_averaging = true").unwrap();
+
+        assert_eq!(config.color_space, ColorSpace::Linear);
+    }
+
+    #[test]
+    fn color_space_takes_precedence_over_linear_averaging() {
+        let config = load_toml("linear-averaging-oklab", "linear_averaging = true\ncolor_space = \"oklab\"").unwrap();
+
+        assert_eq!(config.color_space, ColorSpace::Oklab);
+    }
+}
@@ -0,0 +1,133 @@
+//! Minimal HTTP server to control a running sync
+//!
+//! Only built with the `http` feature. It understands just enough HTTP for
+//! `curl` or a phone shortcut: `POST /pause`, `POST /resume` and `GET /status`.
+//! With the `metrics` feature, `GET /metrics` serves the counters of the sync to Prometheus.
+
+use log::{info, warn};
+use serde_json::json;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::Metrics;
+
+/// How often the server checks if it has to shut down, in milliseconds
+const POLL_INTERVAL_MS: u64 = 100;
+/// Content types of the answers
+const JSON: &str = "application/json";
+#[cfg(feature = "metrics")]
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// State of the sync shared with the server
+#[derive(Clone)]
+pub struct ControlState {
+    /// Set while the sync is paused
+    pub paused: Arc<AtomicBool>,
+    /// Set while the lamps hold their color because of `lock_key`
+    pub locked: Arc<AtomicBool>,
+    /// Last color sent to the lamps
+    pub color: Arc<Mutex<(u64, u64, u64)>>,
+    /// Counters of the sync, served on `GET /metrics`
+    pub metrics: Arc<Metrics>,
+}
+
+/// A running control server, stopped with `shutdown()`
+pub struct ControlServer {
+    shutdown_requested: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl ControlServer {
+    /// Start the server in its own thread
+    ///
+    /// # Arguments
+    /// * `port` - The TCP port to listen on, on all interfaces
+    /// * `state` - The state to control
+    pub fn start(port: u16, state: ControlState) -> Result<Self, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .map_err(|e| format!("Error starting the control server on port {}: {}", port, e))?;
+        // Non-blocking so the thread can notice `shutdown()`
+        listener.set_nonblocking(true)
+            .map_err(|e| format!("Error starting the control server on port {}: {}", port, e))?;
+
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+        let shutdown = shutdown_requested.clone();
+        let handle = thread::spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(error) = handle_connection(stream, &state) {
+                            warn!("Error answering a control request: {}", error);
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                    }
+                    Err(e) => warn!("Error accepting a control connection: {}", e),
+                }
+            }
+        });
+
+        info!("Control server listening on port {}", port);
+
+        Ok(Self { shutdown_requested, handle })
+    }
+
+    /// Stop the server and wait for its thread to end
+    pub fn shutdown(self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+    }
+}
+
+/// Answer a single request and close the connection
+fn handle_connection(stream: TcpStream, state: &ControlState) -> std::io::Result<()> {
+    // The listener is non-blocking, the connection shouldn't be
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    // Only the request line matters, e.g. "POST /pause HTTP/1.1"
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (status, content_type, body) = match (method, path) {
+        ("POST", "/pause") => {
+            state.paused.store(true, Ordering::SeqCst);
+            ("200 OK", JSON, status_json(state))
+        }
+        ("POST", "/resume") => {
+            state.paused.store(false, Ordering::SeqCst);
+            ("200 OK", JSON, status_json(state))
+        }
+        ("GET", "/status") => ("200 OK", JSON, status_json(state)),
+        #[cfg(feature = "metrics")]
+        ("GET", "/metrics") => ("200 OK", PROMETHEUS, state.metrics.render()),
+        _ => ("404 Not Found", JSON, json!({ "error": "not found" }).to_string()),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    )
+}
+
+/// Current state of the sync, as JSON
+fn status_json(state: &ControlState) -> String {
+    let (r, g, b) = *state.color.lock().unwrap();
+
+    json!({
+        "paused": state.paused.load(Ordering::SeqCst),
+        "locked": state.locked.load(Ordering::SeqCst),
+        "color": { "r": r, "g": g, "b": b },
+    }).to_string()
+}
@@ -0,0 +1,105 @@
+//! Focused window checks, used with `sync_when_focused`
+//!
+//! Reading the focused window only works on Windows, elsewhere the sync never stops for it.
+
+use std::time::{Duration, Instant};
+
+#[cfg(windows)]
+use winapi::shared::minwindef::FALSE;
+#[cfg(windows)]
+use winapi::um::handleapi::CloseHandle;
+#[cfg(windows)]
+use winapi::um::processthreadsapi::OpenProcess;
+#[cfg(windows)]
+use winapi::um::winbase::QueryFullProcessImageNameW;
+#[cfg(windows)]
+use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+#[cfg(windows)]
+use winapi::um::winuser::{GetForegroundWindow, GetWindowTextW, GetWindowThreadProcessId};
+
+/// How often the focused window is checked
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The window that has the keyboard focus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FocusedWindow {
+    /// Title of the window
+    pub title: String,
+    /// File name of the program that owns it, e.g. `vlc.exe`. Empty if it can't be read
+    pub process: String,
+}
+
+impl FocusedWindow {
+    /// Check if the title or the program name contains `target`, ignoring case
+    pub fn matches(&self, target: &str) -> bool {
+        let target = target.to_lowercase();
+        self.title.to_lowercase().contains(&target) || self.process.to_lowercase().contains(&target)
+    }
+}
+
+/// Get the window that has the keyboard focus, `None` if there's none or it can't be read
+pub fn focused_window() -> Option<FocusedWindow> {
+    #[cfg(windows)]
+    unsafe {
+        let window = GetForegroundWindow();
+        if window.is_null() {
+            return None;
+        }
+
+        let mut title = [0u16; 512];
+        let length = GetWindowTextW(window, title.as_mut_ptr(), title.len() as i32);
+        let title = String::from_utf16_lossy(&title[..length.max(0) as usize]);
+
+        let mut process_id = 0;
+        GetWindowThreadProcessId(window, &mut process_id);
+        let mut process = String::new();
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id);
+        if !handle.is_null() {
+            let mut path = [0u16; 1024];
+            let mut size = path.len() as u32;
+            if QueryFullProcessImageNameW(handle, 0, path.as_mut_ptr(), &mut size) != 0 {
+                let path = String::from_utf16_lossy(&path[..size as usize]);
+                process = path.rsplit('\\').next().unwrap_or_default().to_string();
+            }
+            CloseHandle(handle);
+        }
+
+        Some(FocusedWindow { title, process })
+    }
+
+    #[cfg(not(windows))]
+    None
+}
+
+/// Tells if the window to sync is focused, checking at most every `CHECK_INTERVAL`
+pub struct FocusWatcher {
+    /// Title or program name of the window to sync, see `FocusedWindow::matches()`
+    target: String,
+    /// If the window was focused on the last check
+    focused: bool,
+    /// When the focused window was last checked
+    last_check: Option<Instant>,
+}
+
+impl FocusWatcher {
+    /// Create a new FocusWatcher
+    ///
+    /// # Arguments
+    /// * `target` - Part of the title or program name of the window to sync
+    pub fn new(target: &str) -> Self {
+        Self { target: target.to_string(), focused: true, last_check: None }
+    }
+
+    /// Check if the window to sync is focused
+    ///
+    /// Windows that can't be read, and every window outside of Windows, count as focused
+    pub fn is_focused(&mut self) -> bool {
+        if self.last_check.is_some_and(|last_check| last_check.elapsed() < CHECK_INTERVAL) {
+            return self.focused;
+        }
+        self.last_check = Some(Instant::now());
+
+        self.focused = focused_window().is_none_or(|window| window.matches(&self.target));
+        self.focused
+    }
+}
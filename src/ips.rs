@@ -0,0 +1,232 @@
+use log::warn;
+
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often `IpsWatcher` checks if the file changed
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Error found while parsing the lamps IPs
+#[derive(Debug)]
+pub struct ParseError {
+    /// Line number where the error was found, starting at 1
+    pub line: usize,
+    /// Content of the offending line
+    pub text: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid name, IP or port on line {}: '{}'", self.line, self.text)
+    }
+}
+
+/// A lamp from `ips.txt`
+#[derive(Debug, Clone)]
+pub struct Lamp {
+    /// Friendly name of the lamp, used in messages
+    pub name: Option<String>,
+    /// IP of the lamp
+    pub ip: IpAddr,
+    /// Port the lamp listens on, if it's not the default one
+    pub port: Option<u16>,
+}
+
+/// Parse the contents of `ips.txt`
+///
+/// Each line holds one lamp, either as `<IP>` or `<NAME>=<IP>`. The name can't be empty.
+/// The IP can be followed by a port, e.g. `192.168.0.12:38900` (`[fd00::12]:38900` for IPv6).
+/// IPv6 addresses without a port can be written with or without brackets.
+/// Blank lines and lines starting with `#` are skipped.
+///
+/// # Arguments
+/// * `contents` - The contents of the file
+pub fn parse_lamps(contents: &str) -> Result<Vec<Lamp>, ParseError> {
+    let mut lamps = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, ip) = match line.split_once('=') {
+            Some((name, _)) if name.trim().is_empty() => {
+                return Err(ParseError { line: index + 1, text: line.to_string() });
+            }
+            Some((name, ip)) => (Some(name.trim().to_string()), ip.trim()),
+            None => (None, line),
+        };
+
+        // `[fd00::12]` is an IPv6 address without a port
+        let unbracketed = ip.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(ip);
+        let (ip, port) = match (SocketAddr::from_str(ip), IpAddr::from_str(unbracketed)) {
+            (Ok(address), _) if address.port() != 0 => (address.ip(), Some(address.port())),
+            (_, Ok(ip)) => (ip, None),
+            _ => {
+                return Err(ParseError { line: index + 1, text: line.to_string() });
+            }
+        };
+        lamps.push(Lamp { name, ip, port });
+    }
+
+    Ok(lamps)
+}
+
+/// Parse the contents of `ips.txt`, ignoring the lamps names
+///
+/// # Arguments
+/// * `contents` - The contents of the file
+pub fn parse_ips(contents: &str) -> Result<Vec<IpAddr>, ParseError> {
+    Ok(parse_lamps(contents)?.into_iter().map(|lamp| lamp.ip).collect())
+}
+
+/// Watches `ips.txt` and parses it again when it changes
+///
+/// The modification time of the file is polled from its own thread,
+/// the new lamps are picked up with `take_changes()`
+pub struct IpsWatcher {
+    /// Lamps parsed since the last `take_changes()`
+    pending: Arc<Mutex<Option<Vec<Lamp>>>>,
+    shutdown_requested: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl IpsWatcher {
+    /// Start watching a file in its own thread
+    ///
+    /// Changes made before this call are ignored
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file
+    pub fn start(path: &str) -> Self {
+        let pending = Arc::new(Mutex::new(None));
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+
+        let path = path.to_string();
+        let thread_pending = pending.clone();
+        let shutdown = shutdown_requested.clone();
+        let handle = thread::spawn(move || {
+            let mut modified = modified_time(&path);
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(WATCH_INTERVAL);
+
+                let new_modified = modified_time(&path);
+                if new_modified == modified {
+                    continue;
+                }
+                modified = new_modified;
+
+                // Keep the current lamps if the file can't be used, it may be half written
+                match fs::read_to_string(&path).map(|contents| parse_lamps(&contents)) {
+                    Ok(Ok(lamps)) => *thread_pending.lock().unwrap() = Some(lamps),
+                    Ok(Err(error)) => warn!("Error parsing {}, keeping the current lamps\n{}", path, error),
+                    Err(error) => warn!("Error reading {}, keeping the current lamps: {}", path, error),
+                }
+            }
+        });
+
+        Self { pending, shutdown_requested, handle }
+    }
+
+    /// Get the lamps in the file if it changed since the last call
+    pub fn take_changes(&self) -> Option<Vec<Lamp>> {
+        self.pending.lock().unwrap().take()
+    }
+
+    /// Stop watching and wait for the thread to end
+    pub fn shutdown(self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+    }
+}
+
+/// Get when a file was last modified, `None` if it can't be read
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a single line, expecting it to be valid
+    fn parse_lamp(line: &str) -> Lamp {
+        let mut lamps = parse_lamps(line).unwrap();
+        assert_eq!(lamps.len(), 1);
+        lamps.remove(0)
+    }
+
+    /// Get the line number of the error found in `contents`
+    fn error_line(contents: &str) -> usize {
+        parse_lamps(contents).unwrap_err().line
+    }
+
+    #[test]
+    fn blank_and_comment_lines_are_skipped() {
+        let lamps = parse_lamps("\n# Living room\n   \n192.168.0.12\n  # 192.168.0.13\n").unwrap();
+
+        assert_eq!(lamps.len(), 1);
+        assert_eq!(lamps[0].ip, IpAddr::from([192, 168, 0, 12]));
+        assert_eq!(lamps[0].name, None);
+        assert_eq!(lamps[0].port, None);
+    }
+
+    #[test]
+    fn a_bad_ip_is_reported_with_its_line() {
+        let error = parse_lamps("192.168.0.12\n# comment\n192.168.0.300\n").unwrap_err();
+
+        assert_eq!(error.line, 3);
+        assert_eq!(error.text, "192.168.0.300");
+        assert_eq!(error.to_string(), "Invalid name, IP or port on line 3: '192.168.0.300'");
+    }
+
+    #[test]
+    fn names_are_trimmed() {
+        let lamp = parse_lamp("  Desk Left = 192.168.0.12 ");
+
+        assert_eq!(lamp.name.as_deref(), Some("Desk Left"));
+        assert_eq!(lamp.ip, IpAddr::from([192, 168, 0, 12]));
+    }
+
+    #[test]
+    fn empty_names_are_rejected() {
+        assert_eq!(error_line("192.168.0.11\n=192.168.0.12"), 2);
+        assert_eq!(error_line("  = 192.168.0.12"), 1);
+    }
+
+    #[test]
+    fn ports() {
+        let lamp = parse_lamp("Desk=192.168.0.12:38900");
+
+        assert_eq!(lamp.ip, IpAddr::from([192, 168, 0, 12]));
+        assert_eq!(lamp.port, Some(38900));
+    }
+
+    #[test]
+    fn port_0_and_out_of_range_ports_are_rejected() {
+        assert_eq!(error_line("192.168.0.12:0"), 1);
+        assert_eq!(error_line("192.168.0.11\n192.168.0.12:70000"), 2);
+        assert_eq!(error_line("[fd00::12]:0"), 1);
+    }
+
+    #[test]
+    fn ipv6_with_and_without_brackets_or_port() {
+        let ip = IpAddr::from_str("fd00::12").unwrap();
+
+        for line in ["fd00::12", "[fd00::12]"] {
+            let lamp = parse_lamp(line);
+            assert_eq!((lamp.ip, lamp.port), (ip, None), "{}", line);
+        }
+
+        let lamp = parse_lamp("Ceiling=[fd00::12]:38900");
+        assert_eq!(lamp.name.as_deref(), Some("Ceiling"));
+        assert_eq!((lamp.ip, lamp.port), (ip, Some(38900)));
+    }
+}
@@ -0,0 +1,172 @@
+//! Keys that control the sync, see `quit_key` and `profile_key` in the config
+//!
+//! Key names are mapped to Windows virtual-key codes. Reading the keys
+//! only works on Windows, elsewhere the sync is stopped with Ctrl+C.
+
+#[cfg(windows)]
+use winapi::shared::windef::HWND;
+#[cfg(windows)]
+use winapi::um::winuser::{GetAsyncKeyState, GetForegroundWindow, GetKeyState};
+
+/// Names of the keys that aren't a letter, digit or function key, with their virtual-key code
+const NAMED_KEYS: [(&str, i32); 17] = [
+    ("escape", 0x1B),
+    ("esc", 0x1B),
+    ("space", 0x20),
+    ("enter", 0x0D),
+    ("tab", 0x09),
+    ("backspace", 0x08),
+    ("insert", 0x2D),
+    ("delete", 0x2E),
+    ("home", 0x24),
+    ("end", 0x23),
+    ("pageup", 0x21),
+    ("pagedown", 0x22),
+    ("pause", 0x13),
+    ("left", 0x25),
+    ("up", 0x26),
+    ("right", 0x27),
+    ("down", 0x28),
+];
+
+/// Get the virtual-key code of a key name
+///
+/// Accepts letters (`q`), digits (`0`), function keys (`f1` to `f24`),
+/// numpad digits (`numpad0`) and the names in `NAMED_KEYS`, ignoring case
+///
+/// # Arguments
+/// * `name` - The name of the key
+pub fn virtual_key_code(name: &str) -> Option<i32> {
+    let name = name.trim().to_lowercase();
+
+    if let Some(&(_, code)) = NAMED_KEYS.iter().find(|(key, _)| *key == name) {
+        return Some(code);
+    }
+
+    let mut chars = name.chars();
+    if let (Some(key), None) = (chars.next(), chars.next()) {
+        return match key {
+            'a'..='z' => Some(key.to_ascii_uppercase() as i32),
+            '0'..='9' => Some(key as i32),
+            _ => None,
+        };
+    }
+
+    if let Some(number) = name.strip_prefix("numpad").and_then(|digit| digit.parse::<i32>().ok()) {
+        return (0..=9).contains(&number).then_some(0x60 + number);
+    }
+
+    match name.strip_prefix('f').and_then(|number| number.parse::<i32>().ok()) {
+        Some(number) if (1..=24).contains(&number) => Some(0x6F + number),
+        _ => None,
+    }
+}
+
+/// Tell how to stop the sync, e.g. "Press 'escape' to quit"
+///
+/// # Arguments
+/// * `quit_key` - The `quit_key` of the config
+pub fn quit_hint(quit_key: &str) -> String {
+    if cfg!(windows) && !quit_key.is_empty() {
+        format!("Press '{}' to quit", quit_key)
+    } else {
+        "Press Ctrl+C to quit".to_string()
+    }
+}
+
+/// Number keys used to apply the scenes, see `scenes` in the config
+///
+/// They only count while the window focused at `start()` is focused,
+/// and only work on Windows
+pub struct NumberKeys {
+    #[cfg(windows)]
+    window: HWND,
+    /// Keys that were down on the last check, so holding a key only counts once
+    #[cfg(windows)]
+    down: [bool; 10],
+}
+
+impl NumberKeys {
+    /// Start watching the number keys, the window focused right now is taken as the terminal of this program
+    pub fn start() -> NumberKeys {
+        NumberKeys {
+            #[cfg(windows)]
+            window: unsafe { GetForegroundWindow() },
+            #[cfg(windows)]
+            down: [false; 10],
+        }
+    }
+
+    /// Get the number key pressed since the last call, if any
+    ///
+    /// The numbers of the main keyboard and the numpad both count
+    pub fn pressed(&mut self) -> Option<usize> {
+        #[cfg(windows)]
+        unsafe {
+            let focused = GetForegroundWindow() == self.window;
+            let mut pressed = None;
+            for number in 0..10 {
+                // High order bit is set while the key is down
+                let down = focused
+                    && (GetKeyState(0x30 + number as i32) as u16 & 0x8000 != 0
+                        || GetKeyState(0x60 + number as i32) as u16 & 0x8000 != 0);
+                if down && !self.down[number] {
+                    pressed = Some(number);
+                }
+                self.down[number] = down;
+            }
+            pressed
+        }
+
+        #[cfg(not(windows))]
+        None
+    }
+}
+
+/// Key watched while syncing, e.g. the one that stops the sync
+#[cfg(windows)]
+pub struct HotKey {
+    code: i32,
+    /// Window that has to be focused for the key to count, `None` = works from any window
+    window: Option<HWND>,
+    /// If the key was down on the last call to `pressed()`
+    down: bool,
+}
+
+#[cfg(windows)]
+impl HotKey {
+    /// Create the key, `None` if `name` is empty
+    ///
+    /// The window focused right now is taken as the terminal of this program
+    ///
+    /// # Arguments
+    /// * `name` - The name of the key, see `virtual_key_code()`
+    /// * `global` - Let the key work while any window is focused
+    pub fn new(name: &str, global: bool) -> Option<HotKey> {
+        let code = virtual_key_code(name)?;
+        let window = if global { None } else { Some(unsafe { GetForegroundWindow() }) };
+
+        // A key still held from before it's created doesn't count until it's released
+        Some(HotKey { code, window, down: true })
+    }
+
+    /// Check if the key was pressed since the last call, holding it only counts once
+    pub fn pressed(&mut self) -> bool {
+        let down = self.is_pressed();
+        let pressed = down && !self.down;
+        self.down = down;
+
+        pressed
+    }
+
+    /// Check if the key is pressed
+    pub fn is_pressed(&self) -> bool {
+        unsafe {
+            match self.window {
+                // High order bit is set while the key is down
+                Some(window) => GetKeyState(self.code) as u16 & 0x8000 != 0 && GetForegroundWindow() == window,
+                None => GetAsyncKeyState(self.code) as u16 & 0x8000 != 0,
+            }
+        }
+    }
+}
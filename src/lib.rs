@@ -0,0 +1,26 @@
+//! Sync WiZ lightbulbs to the colors on your screen
+//!
+//! The easiest way to use it is with [`ScreenSync`], which runs the whole
+//! capture, color and send loop. [`LightCommunication`] and the color functions
+//! can also be used on their own.
+
+pub mod capture;
+pub mod color;
+pub mod config;
+pub mod focus;
+#[cfg(feature = "http")]
+pub mod control;
+pub mod ips;
+pub mod keys;
+pub mod light_communication;
+pub mod metrics;
+pub mod pilot;
+#[cfg(feature = "preview")]
+pub mod preview;
+pub mod screen_sync;
+pub mod tui;
+
+pub use color::{calculate_color_variation, get_average_color, get_dominant_color};
+pub use config::Config;
+pub use light_communication::{LightCommunication, LightError};
+pub use screen_sync::{ScreenSync, SyncError};
@@ -1,34 +1,588 @@
-use std::net::UdpSocket;
-use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use serde_json::*;
+use log::{debug, info, warn};
+use serde_json::json;
 
-use crate::exit_with_error;
+use crate::color::{apply_calibration, clamp_rgb, rgb_to_cct};
+use crate::metrics::Metrics;
+use crate::pilot::{parse_result, PilotState, SystemConfig, UserConfig};
 
-const LAMPS_PORT: &str = "38899";
+/// Port the lamps listen on for discovery broadcasts
+const DISCOVERY_PORT: u16 = 38899;
+/// Lowest dimming the lamps accept
+const MIN_DIMMING: u64 = 10;
+/// Highest dimming the lamps accept
+const MAX_DIMMING: u64 = 100;
+/// Address used to reach every lamp in the local network at once
+const BROADCAST_IP: &str = "255.255.255.255";
+/// Size of the buffer the responses are received in.
+/// The largest WiZ responses (`getSystemConfig`) are under 1KB
+const RESPONSE_BUFFER_SIZE: usize = 4096;
+/// Error Windows returns instead of a truncated datagram (WSAEMSGSIZE)
+const MESSAGE_TOO_LONG_ERROR: i32 = 10040;
+/// How often the thread receiving the responses checks if it has to stop
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Errors that can happen while communicating with the lamps
+/// 
+/// `lamp` identifies the lamp, with its name if it has one, e.g. `'Desk Left' (192.168.0.12)`
+#[derive(Debug)]
+pub enum LightError {
+    /// The message couldn't be sent
+    Send { lamp: String, source: io::Error },
+    /// The response couldn't be read
+    Recv { lamp: String, source: io::Error },
+    /// The lamp didn't answer in time
+    Timeout { lamp: String },
+    /// The lamp answered with invalid JSON, or without the expected fields
+    Parse { lamp: String, source: serde_json::Error },
+    /// The lamp answered with an error
+    Response { lamp: String, error: String },
+    /// The response didn't fit in the receive buffer, `size` bytes
+    Truncated { lamp: String, size: usize },
+    /// The IP isn't one of the lamps
+    UnknownLamp { ip: String },
+    /// No group has this name
+    UnknownGroup { name: String },
+}
+
+impl fmt::Display for LightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LightError::Send { lamp, source } => {
+                write!(f, "Error communicating with {} ({}) \nPlease make sure the IP is correct, the lamp is turned on and connected to the same network as this computer", lamp, source)
+            }
+            LightError::Recv { lamp, source } => {
+                write!(f, "No response from {} ({}) \nPlease make sure the IP is correct, the lamp is turned on and connected to the same network as this computer", lamp, source)
+            }
+            LightError::Timeout { lamp } => {
+                write!(f, "No response from {} in time \nPlease make sure the IP is correct, the lamp is turned on and connected to the same network as this computer", lamp)
+            }
+            LightError::Parse { lamp, source } => {
+                write!(f, "Invalid response from {} ({})", lamp, source)
+            }
+            LightError::Response { lamp, error } => {
+                write!(f, "{} answered with an error ({})", lamp, error)
+            }
+            LightError::Truncated { lamp, size } => {
+                write!(f, "The response from {} is longer than {} bytes and was truncated", lamp, size)
+            }
+            LightError::UnknownLamp { ip } => {
+                write!(f, "{} is not one of the lamps", ip)
+            }
+            LightError::UnknownGroup { name } => {
+                write!(f, "There's no group called {}", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LightError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LightError::Send { source, .. } | LightError::Recv { source, .. } => Some(source),
+            LightError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// How failed messages are retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times a failed message is retried
+    pub retries: u32,
+    /// Delay before the first retry, it doubles on each retry
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { retries: 2, base_delay: Duration::from_millis(50) }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `attempt` until it succeeds or the retries run out, waiting longer after each failure
+    fn run<T>(&self, mut attempt: impl FnMut() -> Result<T, LightError>) -> Result<T, LightError> {
+        let mut delay = self.base_delay;
+        for _ in 0..self.retries {
+            match attempt() {
+                Ok(result) => return Ok(result),
+                Err(LightError::Send { .. }) | Err(LightError::Recv { .. }) | Err(LightError::Timeout { .. }) => {
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        attempt()
+    }
+}
+
+/// Color last sent to a lamp while syncing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SentColor {
+    rgb: (u64, u64, u64),
+    temp: u64,
+    dimming: u64,
+    is_on: bool,
+}
+
+/// What happened with the last color sent to a lamp while syncing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// The lamp hasn't got a color yet, or it was restored
+    Waiting,
+    /// The lamp got this RGB color
+    Sent((u64, u64, u64)),
+    /// The last color couldn't be sent
+    Failed,
+    /// The lamp is skipped until it answers again, see `set_skip_failed()`
+    Skipped,
+    /// The lamp was off on start and is left untouched, see `set_include_off_lamps()`
+    Off,
+}
 
 /// This struct is used to communicate with the lamps
 pub struct LightCommunication {
     /// List of lamps IPs
     lights: Vec<String>,
+    /// Friendly names of the lamps, by IP. Used in messages
+    names: HashMap<String, String>,
+    /// IPs of the lamps in each group, by group name. Set with `set_groups()`
+    groups: HashMap<String, Vec<String>>,
     /// List of lamps initial states.
     /// Has to be initialized with `get_initial_states()`
-    lights_initial_state: HashMap<String, String>,
-    /// Socket used to communicate with the lamps
-    socket: UdpSocket,
+    lights_initial_state: HashMap<String, PilotState>,
+    /// Model information of each lamp.
+    /// Has to be initialized with `get_system_configs()`, lamps not in it are assumed to support RGB
+    system_configs: HashMap<String, SystemConfig>,
+    /// Address of each lamp, its responses are told apart by it
+    addresses: HashMap<String, SocketAddr>,
+    /// Socket shared by the IPv4 lamps, also used for broadcasts and discovery
+    socket: SharedSocket,
+    /// Socket shared by the IPv6 lamps, opened with the first one
+    socket_v6: Option<SharedSocket>,
+    /// How failed messages are retried
+    retry_policy: RetryPolicy,
+    /// If set, colors are logged instead of sent
+    dry_run: bool,
+    /// If set, every lamp gets the colors as a color temperature
+    temp_mode: bool,
+    /// Lamps that get the colors as a color temperature, even if `temp_mode` isn't set
+    temp_lamps: HashSet<String>,
+    /// Color correction matrix of each lamp, applied to the RGB colors sent while syncing
+    calibrations: HashMap<String, [[f64; 3]; 3]>,
+    /// Color last sent to each lamp by `set_color_all()`, `set_color_group()` and `set_colors()`,
+    /// `None` if it couldn't be sent. Used by `set_color_if_changed()` and `sync_status()`,
+    /// lamps are removed when they get anything else
+    sent_colors: Mutex<HashMap<String, Option<SentColor>>>,
+    /// If `set_color_all()` sends the color to every lamp in one message.
+    /// Set with `set_broadcast()`
+    broadcast: bool,
+    /// Shortest time between two colors sent to the same lamp while syncing.
+    /// Lamps get unresponsive when flooded with `setPilot` messages
+    min_send_interval: Duration,
+    /// When each lamp last got a color while syncing, for `min_send_interval`
+    last_sent: Mutex<HashMap<String, Instant>>,
+    /// How long a lamp that fails while syncing is skipped before checking if it answers again.
+    /// `None` = failed lamps are tried again on the next color. Set with `set_skip_failed()`
+    skip_interval: Option<Duration>,
+    /// When each skipped lamp failed or was last checked, for `skip_interval`
+    skipped: Mutex<HashMap<String, Instant>>,
+    /// If the lamps that were off on start get the colors too. Set with `set_include_off_lamps()`
+    include_off_lamps: bool,
+    /// Counters of the colors sent while syncing
+    metrics: Arc<Metrics>,
+    /// Port the lamps listen on, used for the lamps added with `add_lamp()`
+    port: u16,
+    /// How long to wait for a lamp to answer. Set with `set_read_timeout()`
+    read_timeout: Duration,
 }
 
 impl LightCommunication {
     /// Create a new LightCommunication struct
-    pub fn new(lights: Vec<String>) -> Self {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        socket.set_read_timeout(Some(std::time::Duration::from_millis(400))).unwrap();
-        Self { lights, socket, lights_initial_state: HashMap::new() }
+    /// 
+    /// # Arguments
+    /// * `lights` - The IPs of the lamps
+    /// * `port` - The port the lamps listen on
+    /// * `read_timeout` - How long to wait for a lamp to answer, it can't be zero.
+    ///   An unreachable lamp stalls every request/response message this long on each try
+    /// 
+    /// Every lamp is reached through the same socket, one for IPv4 and one for IPv6.
+    /// Fails if a socket can't be created, e.g. an IPv6 lamp on a computer without IPv6
+    pub fn new(lights: Vec<String>, port: u16, read_timeout: Duration) -> Result<Self, LightError> {
+        let socket = SharedSocket::open(false)
+            .map_err(|source| LightError::Send { lamp: BROADCAST_IP.to_string(), source })?;
+
+        let mut light_communication = Self {
+            lights: Vec::new(),
+            names: HashMap::new(),
+            groups: HashMap::new(),
+            addresses: HashMap::new(),
+            socket,
+            socket_v6: None,
+            lights_initial_state: HashMap::new(),
+            system_configs: HashMap::new(),
+            retry_policy: RetryPolicy::default(),
+            dry_run: false,
+            temp_mode: false,
+            temp_lamps: HashSet::new(),
+            calibrations: HashMap::new(),
+            sent_colors: Mutex::new(HashMap::new()),
+            broadcast: false,
+            min_send_interval: Duration::ZERO,
+            last_sent: Mutex::new(HashMap::new()),
+            skip_interval: None,
+            skipped: Mutex::new(HashMap::new()),
+            include_off_lamps: true,
+            metrics: Arc::new(Metrics::default()),
+            port,
+            read_timeout,
+        };
+        for ip in lights.iter() {
+            light_communication.add_lamp(ip)?;
+        }
+
+        Ok(light_communication)
+    }
+
+    /// Start communicating with a new lamp
+    /// 
+    /// Its state and model are unknown until `get_initial_state()` and `get_system_config()`
+    /// are called for it. Does nothing if the lamp is already known
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    pub fn add_lamp(&mut self, ip: &str) -> Result<(), LightError> {
+        if self.addresses.contains_key(ip) {
+            return Ok(());
+        }
+
+        let address = self.open_address(ip, self.port)
+            .map_err(|source| LightError::Send { lamp: self.label(ip), source })?;
+        self.addresses.insert(ip.to_string(), address);
+        self.lights.push(ip.to_string());
+
+        Ok(())
+    }
+
+    /// Stop communicating with a lamp
+    /// 
+    /// The lamp is restored to the state found by `get_initial_states()` first, if any.
+    /// It's removed even if the restore fails. Broadcasting is turned off, see `set_broadcast()`
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    pub fn remove_lamp(&mut self, ip: &str) -> Result<(), LightError> {
+        if !self.addresses.contains_key(ip) {
+            return Err(LightError::UnknownLamp { ip: ip.to_string() });
+        }
+
+        let restored = self.restore_initial_state(ip);
+
+        // The lamp is still in the network, a broadcast would change it too
+        if self.broadcast {
+            warn!("{} would still get the broadcasts, the colors will be sent to each lamp instead", self.label(ip));
+            self.broadcast = false;
+        }
+
+        self.lights.retain(|light| light != ip);
+        self.addresses.remove(ip);
+        self.names.remove(ip);
+        self.lights_initial_state.remove(ip);
+        self.system_configs.remove(ip);
+        self.sent_colors.lock().unwrap().remove(ip);
+        self.last_sent.lock().unwrap().remove(ip);
+        self.skipped.lock().unwrap().remove(ip);
+
+        restored
+    }
+
+    /// Log the colors instead of sending them to the lamps
+    /// 
+    /// Only affects `set_color_all()`, `set_color_group()` and `set_colors()`
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Send the colors to the lamps as a color temperature
+    /// 
+    /// Only affects `set_color_all()`, `set_color_group()` and `set_colors()`.
+    /// The brightness of the color is sent as the dimming, since a temperature has none
+    /// 
+    /// # Arguments
+    /// * `temp_mode` - Use a temperature for every lamp
+    /// * `temp_lamps` - The IPs of the lamps that use a temperature even if `temp_mode` isn't set
+    pub fn set_temp_mode(&mut self, temp_mode: bool, temp_lamps: HashSet<String>) {
+        self.temp_mode = temp_mode;
+        self.temp_lamps = temp_lamps;
+    }
+
+    /// Set the color correction of the lamps, see `apply_calibration()`
+    /// 
+    /// Only affects the RGB colors sent by `set_color_all()`, `set_color_group()` and `set_colors()`
+    /// 
+    /// # Arguments
+    /// * `calibrations` - The correction matrix of each lamp, by IP
+    pub fn set_calibrations(&mut self, calibrations: HashMap<String, [[f64; 3]; 3]>) {
+        self.calibrations = calibrations;
+    }
+
+    /// Send the colors of `set_color_all()` as a single broadcast message instead of one message per lamp
+    /// 
+    /// Every lamp in the local network gets the color, even the ones not in `lights`,
+    /// so the lamps are looked for first and broadcasting stays off if any other lamp answers.
+    /// The colors are still sent to each lamp when some of them need a different message,
+    /// e.g. white-only lamps or lamps on another port, or when the broadcast fails
+    /// 
+    /// # Arguments
+    /// * `broadcast` - If the colors are broadcast
+    /// 
+    /// Fails if the lamps can't be looked for, broadcasting stays off then
+    pub fn set_broadcast(&mut self, broadcast: bool) -> Result<(), LightError> {
+        self.broadcast = false;
+        if !broadcast {
+            return Ok(());
+        }
+
+        let others: Vec<String> = self.find_lamps(self.read_timeout)?.into_iter()
+            .filter(|ip| !self.addresses.contains_key(ip))
+            .collect();
+        if !others.is_empty() {
+            warn!("Lamps that aren't being synced answered from this network ({}), the colors won't be broadcast so they aren't changed too", others.join(", "));
+            return Ok(());
+        }
+
+        info!("Broadcasting the colors, every lamp in this network gets them");
+        self.broadcast = true;
+
+        Ok(())
+    }
+
+    /// Change how long to wait for a lamp to answer
+    /// 
+    /// Applies to every lamp, including the ones added later with `add_lamp()`
+    /// 
+    /// # Arguments
+    /// * `read_timeout` - How long to wait, it can't be zero
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// Send the messages of a lamp to a different port than the one given to `new()`
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `port` - The port the lamp listens on
+    pub fn set_port(&mut self, ip: &str, port: u16) -> Result<(), LightError> {
+        if !self.addresses.contains_key(ip) {
+            return Err(LightError::UnknownLamp { ip: ip.to_string() });
+        }
+
+        let address = self.open_address(ip, port)
+            .map_err(|source| LightError::Send { lamp: self.label(ip), source })?;
+        self.addresses.insert(ip.to_string(), address);
+
+        Ok(())
+    }
+
+    /// Set the friendly names of the lamps, used in messages
+    /// 
+    /// # Arguments
+    /// * `names` - The names of the lamps, by IP
+    pub fn set_names(&mut self, names: HashMap<String, String>) {
+        self.names = names;
+    }
+
+    /// Set or clear the friendly name of a lamp
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `name` - The name of the lamp, `None` to use only its IP
+    pub fn set_name(&mut self, ip: &str, name: Option<String>) {
+        match name {
+            Some(name) => self.names.insert(ip.to_string(), name),
+            None => self.names.remove(ip),
+        };
+    }
+
+    /// Set the groups of lamps that `set_color_group_by_name()` can send to
+    /// 
+    /// # Arguments
+    /// * `groups` - The IPs of the lamps in each group, by group name
+    pub fn set_groups(&mut self, groups: HashMap<String, Vec<String>>) {
+        self.groups = groups;
+    }
+
+    /// Get the name and IP of a lamp to show in messages,
+    /// e.g. `'Desk Left' (192.168.0.12)`, or just the IP if it has no name
+    pub fn label(&self, ip: &str) -> String {
+        match self.names.get(ip) {
+            Some(name) => format!("'{}' ({})", name, ip),
+            None => ip.to_string(),
+        }
+    }
+
+    /// Set the shortest time between two colors sent to the same lamp while syncing
+    /// 
+    /// WiZ lamps get unresponsive when they get more than about 10 `setPilot` messages per second,
+    /// so the colors of `set_color_all()`, `set_color_group()` and `set_colors()` that come too soon
+    /// after the previous one are dropped, and those functions return `Ok(false)` so they're sent again.
+    /// `set_color_if_changed()` only sends them to the lamps that didn't get them.
+    /// Restoring the lamps isn't limited
+    /// 
+    /// # Arguments
+    /// * `interval` - The shortest time between two colors, zero = no limit
+    pub fn set_min_send_interval(&mut self, interval: Duration) {
+        self.min_send_interval = interval;
+    }
+
+    /// Check if a lamp can get a color while syncing, following `min_send_interval`
+    fn can_send(&self, ip: &str) -> bool {
+        self.last_sent.lock().unwrap().get(ip)
+            .is_none_or(|last_sent| last_sent.elapsed() >= self.min_send_interval)
+    }
+
+    /// Record that a lamp got a color, for `min_send_interval`
+    fn mark_sent(&self, ip: &str) {
+        self.last_sent.lock().unwrap().insert(ip.to_string(), Instant::now());
+    }
+
+    /// Skip the lamps that fail while syncing instead of trying them again on the next color
+    /// 
+    /// The colors of `set_color_all()`, `set_color_group()` and `set_colors()` aren't sent to a skipped lamp,
+    /// and don't fail because of it. Every `interval` the lamp is pinged, and it gets the colors again once it answers
+    /// 
+    /// # Arguments
+    /// * `interval` - How often a skipped lamp is checked, `None` = don't skip failed lamps
+    pub fn set_skip_failed(&mut self, interval: Option<Duration>) {
+        self.skip_interval = interval;
+    }
+
+    /// Count the colors sent while syncing, and the ones that failed, in `metrics`
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Choose if the lamps that were off on start get the colors too
+    /// 
+    /// When they don't, the lamps `get_initial_states()` found off aren't sent anything by
+    /// `set_color_all()`, `set_color_group()` and `set_colors()`, their fade speed isn't changed
+    /// and they aren't restored, so they stay off and untouched
+    /// 
+    /// # Arguments
+    /// * `include_off_lamps` - If the lamps that were off get the colors, like the others
+    pub fn set_include_off_lamps(&mut self, include_off_lamps: bool) {
+        self.include_off_lamps = include_off_lamps;
+    }
+
+    /// Check if a lamp is left untouched because it was off on start, see `set_include_off_lamps()`
+    fn is_left_off(&self, ip: &str) -> bool {
+        !self.include_off_lamps && self.lights_initial_state.get(ip).is_some_and(|pilot| !pilot.state)
+    }
+
+    /// Skip a lamp until it answers again, like the lamps that fail while syncing
+    /// 
+    /// Used for the lamps that don't answer on start, so they join the sync when they come back.
+    /// Does nothing unless failed lamps are skipped, see `set_skip_failed()`
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    pub fn skip_lamp(&self, ip: &str) {
+        if self.skip_interval.is_some() {
+            self.skipped.lock().unwrap().insert(ip.to_string(), Instant::now());
+        }
+    }
+
+    /// Check if a lamp is skipped after failing, pinging it if it's time to check it again
+    fn is_skipped(&self, ip: &str) -> bool {
+        let Some(interval) = self.skip_interval else {
+            return false;
+        };
+        let checked = match self.skipped.lock().unwrap().get(ip) {
+            Some(checked) => *checked,
+            None => return false,
+        };
+        if checked.elapsed() < interval {
+            return true;
+        }
+
+        match self.ping(ip) {
+            Ok(_) => {
+                info!("{} answers again, syncing it", self.label(ip));
+                self.skipped.lock().unwrap().remove(ip);
+                false
+            }
+            Err(_) => {
+                self.skipped.lock().unwrap().insert(ip.to_string(), Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Set how failed messages are retried
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Find the lamps in the local network
+    /// 
+    /// Broadcasts a registration message and collects the IPs of every lamp
+    /// that answers before the timeout
+    /// 
+    /// # Arguments
+    /// * `timeout` - How long to wait for answers
+    pub fn discover(timeout: Duration) -> Result<Vec<String>, LightError> {
+        let mut light_communication = Self::new(Vec::new(), DISCOVERY_PORT, timeout.max(Duration::from_millis(1)))?;
+
+        light_communication.find_lamps(timeout)
+    }
+
+    /// Broadcast a registration message through the shared IPv4 socket and collect
+    /// the IPs of every lamp that answers before the timeout, see `discover()`
+    /// 
+    /// # Arguments
+    /// * `timeout` - How long to wait for answers
+    fn find_lamps(&mut self, timeout: Duration) -> Result<Vec<String>, LightError> {
+        let msg = json!({
+            "method": "registration",
+            "params": {
+                "phoneMac": "AAAAAAAAAAAA",
+                "register": false,
+                "phoneIp": "1.2.3.4",
+                "id": "1"
+            }
+        });
+
+        let answered = self.socket.collect_senders(msg.to_string().as_bytes(), (BROADCAST_IP, DISCOVERY_PORT), timeout)
+            .map_err(|source| LightError::Send { lamp: BROADCAST_IP.to_string(), source })?;
+
+        let mut ips = Vec::new();
+        for address in answered {
+            let ip = address.ip().to_string();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+
+        Ok(ips)
     }
 
     /// Set the color of a specific lamp
     /// 
-    /// You have to set either rgb or temp
+    /// You have to set either rgb or temp.
+    /// Lamps that don't support RGB get the correlated color temperature of `rgb` instead
     /// 
     /// # Arguments
     /// * `ip` - The IP of the lamp
@@ -36,26 +590,447 @@ impl LightCommunication {
     /// * `temp` - The temperature to set
     /// * `dimming` - The dimming to set
     /// * `is_on` - If the lamp should be turned on or off
-    fn set_color(&self, ip: &str, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) {
+    fn set_color(&self, ip: &str, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) -> Result<(), LightError> {
+        let temp = if temp == 0 && !self.supports_rgb(ip) { rgb_to_cct(rgb) } else { temp };
         let msg = self.set_pilot_message(rgb, temp, dimming, is_on);
 
-        self.send_message_to_light(msg, ip);
+        self.send_message_no_reply(msg, ip)
+    }
+
+    /// Turn a specific lamp on or off, without changing its color, dimming or scene
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `is_on` - If the lamp should be turned on or off
+    pub fn set_state(&self, ip: &str, is_on: bool) -> Result<(), LightError> {
+        let msg = self.set_state_message(is_on);
+
+        self.send_message_no_reply(msg, ip)
+    }
+
+    /// Turn a specific lamp on or off while syncing
+    /// 
+    /// Works like `set_state()`, but skips the lamps like `set_sync_color()` does
+    /// and keeps the color sent to the lamp, so it's sent again when the lamp is turned on with it
+    /// 
+    /// Returns if the lamp has the state, like `set_sync_color()`
+    fn set_sync_state(&self, ip: &str, is_on: bool) -> Result<bool, LightError> {
+        if self.is_skipped(ip) || self.is_left_off(ip) {
+            return Ok(true);
+        }
+        if !self.can_send(ip) {
+            let sent_colors = self.sent_colors.lock().unwrap();
+            return Ok(matches!(sent_colors.get(ip), Some(Some(sent_color)) if sent_color.is_on == is_on));
+        }
+        self.mark_sent(ip);
+
+        let result = self.set_state(ip, is_on);
+
+        self.record_sync_result(ip, result, |sent_colors| {
+            if let Some(Some(sent_color)) = sent_colors.get_mut(ip) {
+                sent_color.is_on = is_on;
+            }
+        })
+    }
+
+    /// Set the color of a specific lamp while syncing
+    /// 
+    /// Works like `set_color()`, but lamps in temperature mode and the ones that can't show RGB
+    /// get the correlated color temperature of `rgb`, with its brightness moved to the dimming,
+    /// and the other lamps get `rgb` corrected with their calibration
+    /// 
+    /// Returns if the lamp has the color: `false` if it was too soon after the previous color,
+    /// see `set_min_send_interval()`, unless the lamp already had it
+    fn set_sync_color(&self, ip: &str, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) -> Result<bool, LightError> {
+        // The lamp failed before, see `set_skip_failed()`, or it's left off, see `set_include_off_lamps()`
+        if self.is_skipped(ip) || self.is_left_off(ip) {
+            return Ok(true);
+        }
+        // Too soon after the previous color
+        if !self.can_send(ip) {
+            let sent_colors = self.sent_colors.lock().unwrap();
+            return Ok(sent_colors.get(ip) == Some(&Some(SentColor { rgb, temp, dimming, is_on })));
+        }
+        self.mark_sent(ip);
+
+        let result = if temp != 0 {
+            self.set_color(ip, rgb, temp, dimming, is_on)
+        } else if !(self.temp_mode || self.temp_lamps.contains(ip) || !self.supports_rgb(ip)) {
+            let calibrated = match self.calibrations.get(ip) {
+                Some(matrix) => apply_calibration(rgb, matrix),
+                None => rgb,
+            };
+            self.set_color(ip, calibrated, 0, dimming, is_on)
+        } else {
+            let brightness = rgb.0.max(rgb.1).max(rgb.2).min(255);
+            let sync_dimming = (dimming * brightness / 255).clamp(MIN_DIMMING, MAX_DIMMING);
+
+            self.set_color(ip, rgb, rgb_to_cct(rgb), sync_dimming, is_on)
+        };
+
+        self.record_sync_result(ip, result, |sent_colors| {
+            self.metrics.colors_sent(1);
+            sent_colors.insert(ip.to_string(), Some(SentColor { rgb, temp, dimming, is_on }));
+        })
+    }
+
+    /// Keep track of a message sent to a lamp while syncing, by `set_sync_color()` and `set_sync_state()`
+    /// 
+    /// After a failure the color of the lamp is unknown, and the lamp is skipped
+    /// if `set_skip_failed()` is set
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `result` - The result of sending the message
+    /// * `update_sent_color` - Updates the color sent to the lamps after a successful send
+    /// 
+    /// Returns if the lamp got the message, like `set_sync_color()`
+    fn record_sync_result(
+        &self,
+        ip: &str,
+        result: Result<(), LightError>,
+        update_sent_color: impl FnOnce(&mut HashMap<String, Option<SentColor>>),
+    ) -> Result<bool, LightError> {
+        let mut sent_colors = self.sent_colors.lock().unwrap();
+        match result {
+            Ok(()) => update_sent_color(&mut sent_colors),
+            Err(_) => {
+                self.metrics.lamp_send_error(ip);
+                sent_colors.insert(ip.to_string(), None);
+            }
+        };
+
+        match result {
+            Err(error) if self.skip_interval.is_some() => {
+                warn!("{}, skipping it until it answers again", error);
+                self.skipped.lock().unwrap().insert(ip.to_string(), Instant::now());
+                Ok(true)
+            }
+            result => result.map(|()| true),
+        }
+    }
+
+    /// Set a scene on a specific lamp
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `scene_id` - The scene to set
+    /// * `speed` - The speed of the scene animation, if it has one
+    /// * `dimming` - The dimming to set
+    /// * `is_on` - If the lamp should be turned on or off
+    fn set_scene(&self, ip: &str, scene_id: u64, speed: Option<u64>, dimming: u64, is_on: bool) -> Result<(), LightError> {
+        let msg = self.set_scene_message(scene_id, speed, dimming, is_on);
+
+        self.send_message_no_reply(msg, ip)
     }
 
     /// Set the color of all the lamps
     /// 
     /// You have to set either rgb or temp
     /// 
+    /// The lamps are updated concurrently, one thread per lamp,
+    /// so a slow lamp doesn't delay the others
+    /// 
     /// # Arguments
-    /// * `ip` - The IP of the lamp
     /// * `rgb` - The RGB color to set
     /// * `temp` - The temperature to set
     /// * `dimming` - The dimming to set
     /// * `is_on` - If the lamp should be turned on or off
-    pub fn set_color_all(&self, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) {
-        for ip in self.lights.iter() {
-            self.set_color(ip, rgb, temp, dimming, is_on)
+    /// 
+    /// Returns if every lamp has the color, `false` if it was too soon for some of them
+    /// and it has to be sent again, see `set_min_send_interval()`.
+    /// Returns the first error found, after every lamp has been tried
+    pub fn set_color_all(&self, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) -> Result<bool, LightError> {
+        let can_send_all = || self.lights.iter().all(|ip| self.can_send(ip));
+        if self.broadcast && !self.dry_run && self.can_broadcast(temp) && can_send_all() {
+            let msg = self.set_pilot_message(rgb, temp, dimming, is_on);
+            match self.socket.socket.send_to(msg.as_bytes(), (BROADCAST_IP, self.port)) {
+                Ok(_) => {
+                    let sent_color = SentColor { rgb, temp, dimming, is_on };
+                    let mut sent_colors = self.sent_colors.lock().unwrap();
+                    for ip in self.lights.iter() {
+                        sent_colors.insert(ip.to_string(), Some(sent_color));
+                        self.mark_sent(ip);
+                    }
+                    self.metrics.colors_sent(self.lights.len() as u64);
+                    return Ok(true);
+                }
+                Err(error) => debug!("Error broadcasting the color, sending it to each lamp: {}", error),
+            }
+        }
+
+        self.set_color_group(&self.lights, rgb, temp, dimming, is_on)
+    }
+
+    /// Check if every lamp can get the same color message through a broadcast
+    /// 
+    /// Lamps that get a temperature instead of `rgb`, IPv6 lamps
+    /// and lamps on another port need their own message
+    fn can_broadcast(&self, temp: u64) -> bool {
+        let same_color = temp != 0 || (!self.temp_mode && self.temp_lamps.is_empty() && self.calibrations.is_empty());
+
+        same_color && self.lights.iter().all(|ip| {
+            // A broadcast would turn on the lamps that are left off
+            if self.is_left_off(ip) {
+                return false;
+            }
+            let address = self.addresses[ip];
+            let same_address = address.is_ipv4() && address.port() == self.port;
+            same_address && (temp != 0 || self.supports_rgb(ip))
+        })
+    }
+
+    /// Set the color of some of the lamps
+    /// 
+    /// Works like `set_color_all()`, but only sends to the lamps in `ips`.
+    /// Nothing is sent if any of them isn't a known lamp
+    /// 
+    /// # Arguments
+    /// * `ips` - The IPs of the lamps
+    /// * `rgb` - The RGB color to set
+    /// * `temp` - The temperature to set
+    /// * `dimming` - The dimming to set
+    /// * `is_on` - If the lamps should be turned on or off
+    pub fn set_color_group(&self, ips: &[String], rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) -> Result<bool, LightError> {
+        if let Some(ip) = ips.iter().find(|ip| !self.addresses.contains_key(*ip)) {
+            return Err(LightError::UnknownLamp { ip: ip.to_string() });
+        }
+
+        if self.dry_run {
+            info!("Dry run: rgb {:?}, temp {}, dimming {}, on {}", rgb, temp, dimming, is_on);
+            return Ok(true);
+        }
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = ips.iter()
+                .map(|ip| scope.spawn(move || self.set_sync_color(ip, rgb, temp, dimming, is_on)))
+                .collect();
+
+            // The scope waits for every thread even if we return early
+            handles.into_iter()
+                .try_fold(true, |all_sent, handle| Ok(handle.join().unwrap()? && all_sent))
+        })
+    }
+
+    /// Set the color of the lamps of a group set with `set_groups()`
+    /// 
+    /// WiZ rooms and groups only exist in the app and the cloud, the lamps don't know
+    /// which group they are in, so the color is sent to each lamp with `set_color_group()`
+    /// 
+    /// # Arguments
+    /// * `name` - The name of the group
+    /// * `rgb` - The RGB color to set
+    /// * `temp` - The temperature to set
+    /// * `dimming` - The dimming to set
+    /// * `is_on` - If the lamps should be turned on or off
+    pub fn set_color_group_by_name(&self, name: &str, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) -> Result<bool, LightError> {
+        let ips = self.groups.get(name)
+            .ok_or_else(|| LightError::UnknownGroup { name: name.to_string() })?;
+
+        self.set_color_group(ips, rgb, temp, dimming, is_on)
+    }
+
+    /// Set a different color on each lamp
+    /// 
+    /// Works like `set_color_all()`, but with the color of each lamp taken from `colors`.
+    /// Lamps not in `colors` are left untouched
+    /// 
+    /// # Arguments
+    /// * `colors` - The RGB color to set, by lamp IP
+    /// * `dimming` - The dimming to set
+    /// * `is_on` - If the lamps should be turned on or off
+    pub fn set_colors(&self, colors: &HashMap<String, (u64, u64, u64)>, dimming: u64, is_on: bool) -> Result<bool, LightError> {
+        if self.dry_run {
+            for (ip, rgb) in colors.iter() {
+                info!("Dry run: {} rgb {:?}, dimming {}, on {}", self.label(ip), rgb, dimming, is_on);
+            }
+            return Ok(true);
+        }
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = colors.iter()
+                .filter(|(ip, _)| self.addresses.contains_key(*ip))
+                .map(|(ip, rgb)| scope.spawn(move || self.set_sync_color(ip, *rgb, 0, dimming, is_on)))
+                .collect();
+
+            handles.into_iter()
+                .try_fold(true, |all_sent, handle| Ok(handle.join().unwrap()? && all_sent))
+        })
+    }
+
+    /// Set a different color on each lamp, skipping the lamps that already have it
+    /// 
+    /// Works like `set_colors()`, but lamps whose last color, dimming and state sent by
+    /// `set_color_all()`, `set_color_group()` or `set_colors()` are the same aren't sent anything
+    /// 
+    /// # Arguments
+    /// * `colors` - The RGB color to set, by lamp IP
+    /// * `dimming` - The dimming to set
+    /// * `is_on` - If the lamps should be turned on or off
+    pub fn set_color_if_changed(&self, colors: &HashMap<String, (u64, u64, u64)>, dimming: u64, is_on: bool) -> Result<bool, LightError> {
+        let changed_colors: HashMap<String, (u64, u64, u64)> = {
+            let sent_colors = self.sent_colors.lock().unwrap();
+            colors.iter()
+                .filter(|(ip, rgb)| sent_colors.get(*ip) != Some(&Some(SentColor { rgb: **rgb, temp: 0, dimming, is_on })))
+                .map(|(ip, rgb)| (ip.to_string(), *rgb))
+                .collect()
+        };
+
+        self.set_colors(&changed_colors, dimming, is_on)
+    }
+
+    /// Turn all the lamps on or off, without changing their color, dimming or scene
+    /// 
+    /// The lamps are updated concurrently, one thread per lamp, like with `set_color_all()`
+    /// 
+    /// # Arguments
+    /// * `is_on` - If the lamps should be turned on or off
+    /// 
+    /// Returns if every lamp has the state, like `set_color_all()`
+    pub fn set_state_all(&self, is_on: bool) -> Result<bool, LightError> {
+        if self.dry_run {
+            info!("Dry run: on {}", is_on);
+            return Ok(true);
+        }
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = self.lights.iter()
+                .map(|ip| scope.spawn(move || self.set_sync_state(ip, is_on)))
+                .collect();
+
+            handles.into_iter()
+                .try_fold(true, |all_sent, handle| Ok(handle.join().unwrap()? && all_sent))
+        })
+    }
+
+    /// Get what happened with the last color sent to a lamp
+    /// by `set_color_all()`, `set_color_group()` or `set_colors()`
+    pub fn sync_status(&self, ip: &str) -> SyncStatus {
+        if self.is_left_off(ip) {
+            return SyncStatus::Off;
+        }
+        if self.skip_interval.is_some() && self.skipped.lock().unwrap().contains_key(ip) {
+            return SyncStatus::Skipped;
+        }
+
+        match self.sent_colors.lock().unwrap().get(ip) {
+            Some(Some(sent_color)) => SyncStatus::Sent(sent_color.rgb),
+            Some(None) => SyncStatus::Failed,
+            None => SyncStatus::Waiting,
+        }
+    }
+
+    /// Get the IPs of the lamps
+    pub fn lights(&self) -> &[String] {
+        &self.lights
+    }
+
+    /// Blink a lamp between full and minimum brightness, to find which physical lamp it is
+    /// 
+    /// The lamp gets its state back afterwards, like with `restore_initial_states()`
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `rgb` - The color of the blinks
+    /// * `blinks` - How many times the lamp blinks
+    /// * `interval` - How long each half of a blink lasts
+    pub fn identify(&mut self, ip: &str, rgb: (u64, u64, u64), blinks: u32, interval: Duration) -> Result<(), LightError> {
+        self.get_initial_state(ip)?;
+
+        for _ in 0..blinks {
+            self.set_color(ip, rgb, 0, MAX_DIMMING, true)?;
+            thread::sleep(interval);
+            self.set_color(ip, rgb, 0, MIN_DIMMING, true)?;
+            thread::sleep(interval);
+        }
+
+        self.restore_initial_state(ip)
+    }
+
+    /// Check if a lamp answers, and how long it takes
+    /// 
+    /// Sends a `getPilot` message and returns the round-trip time
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    pub fn ping(&self, ip: &str) -> Result<Duration, LightError> {
+        if !self.addresses.contains_key(ip) {
+            return Err(LightError::UnknownLamp { ip: ip.to_string() });
+        }
+
+        let start = Instant::now();
+        let response = self.send_message_to_light(self.get_pilot_message(), ip)?;
+        let round_trip = start.elapsed();
+        parse_result::<PilotState>(&self.label(ip), &response)?;
+
+        Ok(round_trip)
+    }
+
+    /// Ping all the lamps concurrently
+    /// 
+    /// Returns the result of each lamp, in the same order as `lights()`
+    pub fn ping_all(&self) -> Vec<(String, Result<Duration, LightError>)> {
+        thread::scope(|scope| {
+            let handles: Vec<_> = self.lights.iter()
+                .map(|ip| scope.spawn(move || (ip.to_string(), self.ping(ip))))
+                .collect();
+
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    /// Get the model information of all the lamps
+    /// 
+    /// Store it in `system_configs`, so colors are sent as a temperature to white-only lamps.
+    /// Lamps whose information can't be parsed are skipped with a warning, and assumed to support RGB
+    /// 
+    /// Returns the first error found, after every lamp has been tried
+    pub fn get_system_configs(&mut self) -> Result<(), LightError> {
+        let mut result = Ok(());
+        for ip in self.lights.clone() {
+            let lamp_result = self.get_system_config(&ip);
+            result = result.and(lamp_result);
         }
+
+        result
+    }
+
+    /// Get the model information of a specific lamp
+    /// 
+    /// Works like `get_system_configs()` for a single lamp
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    pub fn get_system_config(&mut self, ip: &str) -> Result<(), LightError> {
+        if !self.addresses.contains_key(ip) {
+            return Err(LightError::UnknownLamp { ip: ip.to_string() });
+        }
+
+        let response = self.send_message_to_light(self.get_system_config_message(), ip)?;
+
+        match parse_result::<SystemConfig>(&self.label(ip), &response) {
+            Ok(system_config) => {
+                if !system_config.supports_rgb() {
+                    info!("{} doesn't support RGB, colors will be sent as a temperature", self.label(ip));
+                }
+                self.system_configs.insert(ip.to_string(), system_config);
+            }
+            Err(error) => warn!("Error getting the model of {}, assuming it supports RGB: {}", self.label(ip), error),
+        }
+
+        Ok(())
+    }
+
+    /// Get the model information of a lamp, if `get_system_configs()` found it
+    pub fn system_config(&self, ip: &str) -> Option<&SystemConfig> {
+        self.system_configs.get(ip)
+    }
+
+    /// Check if a lamp can show RGB colors
+    fn supports_rgb(&self, ip: &str) -> bool {
+        self.system_configs.get(ip).is_none_or(SystemConfig::supports_rgb)
     }
 
     /// Set the dimming time of a specific lamp
@@ -64,109 +1039,242 @@ impl LightCommunication {
     /// * `ip` - The IP of the lamp
     /// * `fade_in` - The fade in time
     /// * `fade_out` - The fade out time
-    fn set_fade_speed(&self, ip: &str, fade_in: u64, fade_out: u64) {
+    pub fn set_fade_speed(&self, ip: &str, fade_in: u64, fade_out: u64) -> Result<(), LightError> {
         let msg = self.set_user_config_message(fade_in, fade_out);
 
-        self.send_message_to_light(msg, ip);
+        self.send_message_to_light(msg, ip)?;
+
+        Ok(())
     }
 
-    /// Set the dimming time of all lamps
+    /// Set the dimming time of all lamps, except the skipped ones
     /// 
     /// # Arguments
-    /// * `ip` - The IP of the lamp
     /// * `fade_in` - The fade in time
     /// * `fade_out` - The fade out time
-    pub fn set_fade_speed_all(&self, fade_in: u64, fade_out: u64) {
-        for ip in self.lights.iter() {
-            self.set_fade_speed(ip, fade_in, fade_out)
+    /// 
+    /// Returns the first error found, after every lamp has been tried
+    pub fn set_fade_speed_all(&self, fade_in: u64, fade_out: u64) -> Result<(), LightError> {
+        let mut result = Ok(());
+        // Skipped lamps aren't answering, see `set_skip_failed()`, and lamps left off aren't touched
+        for ip in self.lights.iter().filter(|ip| !matches!(self.sync_status(ip), SyncStatus::Skipped | SyncStatus::Off)) {
+            let lamp_result = self.set_fade_speed(ip, fade_in, fade_out);
+            result = result.and(lamp_result);
         }
+
+        result
     }
 
     /// Get the initial state of all the lamps
     /// 
-    /// Store the initial state in `lights_initial_state`
+    /// Store the initial state in `lights_initial_state`.
+    /// Lamps whose state can't be parsed are skipped with a warning, and won't be restored
     /// 
     /// This function has to be called before `restore_initial_states()`
-    pub fn get_initial_states(&mut self) {
-        for ip in self.lights.iter() {
-            // Send getPilot message
-            let get_pilot_reponse = self.send_message_to_light(self.get_pilot_message(), ip);
+    /// 
+    /// Returns the first error found, after every lamp has been tried
+    pub fn get_initial_states(&mut self) -> Result<(), LightError> {
+        let mut result = Ok(());
+        for ip in self.lights.clone() {
+            let lamp_result = self.get_initial_state(&ip);
+            result = result.and(lamp_result);
+        }
 
-            // Send getUserConfig message
-            let get_user_config_reponse = self.send_message_to_light(self.get_user_config_message(), ip);
+        result
+    }
 
-            // Parse response
-            let mut parsed_pilot: Value = serde_json::from_str(&get_pilot_reponse).unwrap();
-            let parsed_user_config: Value = serde_json::from_str(&get_user_config_reponse).unwrap();
+    /// Save the initial states found by `get_initial_states()` to a JSON file
+    /// 
+    /// They can be loaded back with `load_initial_states()`, e.g. to restore the lamps after a crash
+    /// 
+    /// # Arguments
+    /// * `path` - The path of the file
+    pub fn save_initial_states(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(&self.lights_initial_state)
+            .map_err(|e| format!("Error saving the lamps state: {}", e))?;
 
-            // Add fadeIn and fadeOut from userConfig to pilot[result]
-            parsed_pilot["result"]["fadeIn"] = parsed_user_config["result"]["fadeIn"].clone();
-            parsed_pilot["result"]["fadeOut"] = parsed_user_config["result"]["fadeOut"].clone();
+        fs::write(path, json).map_err(|e| format!("Error writing {}: {}", path, e))
+    }
+
+    /// Load the initial states saved with `save_initial_states()`
+    /// 
+    /// They replace the states found by `get_initial_states()` for the lamps in the file.
+    /// States of unknown lamps are skipped with a warning
+    /// 
+    /// # Arguments
+    /// * `path` - The path of the file
+    /// 
+    /// Returns how many states were loaded
+    pub fn load_initial_states(&mut self, path: &str) -> Result<usize, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+        let states: HashMap<String, PilotState> = serde_json::from_str(&contents)
+            .map_err(|e| format!("Error parsing {}: {}", path, e))?;
 
-            self.lights_initial_state
-                .insert(ip.to_string(), parsed_pilot.to_string());
+        let mut loaded = 0;
+        for (ip, state) in states {
+            if !self.addresses.contains_key(&ip) {
+                warn!("{} in {} is not one of the lamps, its state won't be restored", ip, path);
+                continue;
+            }
+            self.lights_initial_state.insert(ip, state);
+            loaded += 1;
         }
+
+        Ok(loaded)
     }
 
-    /// Restore the initial state of all the lamps
-    pub fn restore_initial_states(&self) {
-        for (ip, is_on) in self.lights_initial_state.iter() {
-            let is_on: Value = serde_json::from_str(is_on).unwrap();
+    /// Get the initial state of a lamp, if `get_initial_states()` found it
+    pub fn initial_state(&self, ip: &str) -> Option<&PilotState> {
+        self.lights_initial_state.get(ip)
+    }
 
-            let result = is_on["result"].as_object().unwrap();
+    /// Get the initial state of a specific lamp
+    /// 
+    /// Works like `get_initial_states()` for a single lamp, e.g. one added with `add_lamp()`
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    pub fn get_initial_state(&mut self, ip: &str) -> Result<(), LightError> {
+        if !self.addresses.contains_key(ip) {
+            return Err(LightError::UnknownLamp { ip: ip.to_string() });
+        }
 
-            if result.contains_key("temp") {
-                let temp = result["temp"].as_u64().unwrap();
-                let dimming = result["dimming"].as_u64().unwrap();
-                let is_on: bool = result["state"].as_bool().unwrap();
-                let fade_in = result["fadeIn"].as_u64().unwrap();
-                let fade_out = result["fadeOut"].as_u64().unwrap();
+        // Send getPilot message
+        let get_pilot_reponse = self.send_message_to_light(self.get_pilot_message(), ip)?;
 
-                self.set_color(ip, (0, 0, 0), temp, dimming, is_on);
-                self.set_fade_speed(ip, fade_in, fade_out)
-            } else {
-                let r = result["r"].as_u64().unwrap();
-                let g = result["g"].as_u64().unwrap();
-                let b = result["b"].as_u64().unwrap();
-                let dimming = result["dimming"].as_u64().unwrap();
-                let is_on: bool = result["state"].as_bool().unwrap();
-                let fade_in = result["fadeIn"].as_u64().unwrap();
-                let fade_out = result["fadeOut"].as_u64().unwrap();
+        // Send getUserConfig message
+        let get_user_config_reponse = self.send_message_to_light(self.get_user_config_message(), ip)?;
 
-                self.set_color(ip, (r, g, b), 0, dimming, is_on);
-                self.set_fade_speed(ip, fade_in, fade_out)
+        // Parse response
+        let mut pilot: PilotState = match parse_result(&self.label(ip), &get_pilot_reponse) {
+            Ok(pilot) => pilot,
+            Err(error) => {
+                warn!("Skipping the state of {}, it won't be restored. {}", self.label(ip), error);
+                return Ok(());
             }
+        };
+
+        // Add fadeIn and fadeOut from userConfig, some firmware versions don't have them
+        if let Ok(user_config) = parse_result::<UserConfig>(&self.label(ip), &get_user_config_reponse) {
+            pilot.fade_in = user_config.fade_in;
+            pilot.fade_out = user_config.fade_out;
         }
+
+        self.lights_initial_state.insert(ip.to_string(), pilot);
+
+        Ok(())
     }
 
-    /// Send a message to a lamp and return the response
-    fn send_message_to_light(&self, msg: String, ip: &str) -> String {
-        match self.socket.send_to(msg.as_bytes(), format!("{}:{}", ip, LAMPS_PORT)) {
-            Ok(_) => {},
-            Err(_) => {
-                exit_with_error(&format!("Error communicating with {} \nPlease make sure the IP is correct, the lamp is turned on and connected to the same network as this computer", ip));
-            }
+    /// Restore the initial state of all the lamps
+    /// 
+    /// Returns the first error found, after every lamp has been tried
+    pub fn restore_initial_states(&self) -> Result<(), LightError> {
+        let mut result = Ok(());
+        for ip in self.lights_initial_state.keys() {
+            let lamp_result = self.restore_initial_state(ip);
+            result = result.and(lamp_result);
         }
 
-        let mut buf = [0; 1024];
-        let amt:usize;
+        result
+    }
 
-        // Receive response
-        match self.socket.recv_from(&mut buf) {
-            Ok((a, _)) => {
-                amt = a;
-            }
-            Err(_) => {
-                amt = 0;
-                exit_with_error(&format!("Error communicating with {} \nPlease make sure the IP is correct, the lamp is turned on and connected to the same network as this computer", ip));
+    /// Restore the initial state of a specific lamp, if `get_initial_state()` found it
+    /// 
+    /// Lamps left off by `set_include_off_lamps()` were never changed, so they're not sent anything
+    fn restore_initial_state(&self, ip: &str) -> Result<(), LightError> {
+        let Some(pilot) = self.lights_initial_state.get(ip).filter(|_| !self.is_left_off(ip)) else {
+            return Ok(());
+        };
+        self.sent_colors.lock().unwrap().remove(ip);
+        let dimming = pilot.dimming.unwrap_or(100);
+
+        // A running scene takes precedence, its color comes from the scene
+        let color_result = if let Some(scene_id) = pilot.scene() {
+            self.set_scene(ip, scene_id, pilot.speed, dimming, pilot.state)
+        } else if let Some(temp) = pilot.temp {
+            self.set_color(ip, (0, 0, 0), temp, dimming, pilot.state)
+        } else if let Some(rgb) = pilot.rgb() {
+            self.set_color(ip, rgb, 0, dimming, pilot.state)
+        } else {
+            warn!("The previous color of {} is unknown, it won't be restored", self.label(ip));
+            Ok(())
+        };
+
+        // The fade speed is restored even if the color couldn't be
+        let fade_result = match (pilot.fade_in, pilot.fade_out) {
+            (Some(fade_in), Some(fade_out)) => self.set_fade_speed(ip, fade_in, fade_out),
+            _ => Ok(()),
+        };
+
+        color_result.and(fade_result)
+    }
+
+    /// Send a message to a lamp and return the response
+    /// 
+    /// Failed attempts are retried following `retry_policy`
+    fn send_message_to_light(&self, msg: String, ip: &str) -> Result<String, LightError> {
+        let address = self.addresses[ip];
+        let socket = self.socket_for(&address);
+
+        self.retry_policy.run(|| {
+            let received = socket.request(msg.as_bytes(), address, self.read_timeout)
+                .map_err(|source| LightError::Send { lamp: self.label(ip), source })?;
+
+            match received {
+                Some(Received::Message(response)) => Ok(String::from_utf8_lossy(&response).to_string()),
+                Some(Received::Truncated) => Err(LightError::Truncated { lamp: self.label(ip), size: RESPONSE_BUFFER_SIZE }),
+                None => Err(LightError::Timeout { lamp: self.label(ip) }),
             }
+        })
+    }
+
+    /// Send a message to a lamp without waiting for the response
+    /// 
+    /// Used in the sync loop, where the response isn't needed.
+    /// Failed sends are retried following `retry_policy`
+    fn send_message_no_reply(&self, msg: String, ip: &str) -> Result<(), LightError> {
+        let address = self.addresses[ip];
+        let socket = self.socket_for(&address);
+
+        self.retry_policy.run(|| {
+            socket.socket.send_to(msg.as_bytes(), address)
+                .map_err(|source| LightError::Send { lamp: self.label(ip), source })?;
+
+            Ok(())
+        })
+    }
+
+    /// Get the shared socket that reaches an address
+    fn socket_for(&self, address: &SocketAddr) -> &SharedSocket {
+        match self.socket_v6.as_ref() {
+            Some(socket_v6) if address.is_ipv6() => socket_v6,
+            _ => &self.socket,
         }
+    }
 
-        String::from_utf8_lossy(&buf[..amt]).to_string()
+    /// Find the address of a lamp, opening the IPv6 socket if it's the first IPv6 lamp
+    /// 
+    /// # Arguments
+    /// * `ip` - The IP of the lamp
+    /// * `port` - The port the lamp listens on
+    fn open_address(&mut self, ip: &str, port: u16) -> io::Result<SocketAddr> {
+        let address = (ip, port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+        if address.is_ipv6() && self.socket_v6.is_none() {
+            self.socket_v6 = Some(SharedSocket::open(true)?);
+        }
+
+        Ok(address)
     }
 
-    /// Create the message to get the pilot state
+    /// Create the message to set the pilot state
+    /// 
+    /// The color is clamped to 0 to 255 and the dimming to `MIN_DIMMING` to `MAX_DIMMING`,
+    /// since the lamps reject anything out of those ranges
     fn set_pilot_message(&self, rgb: (u64, u64, u64), temp: u64, dimming: u64, is_on: bool) -> String {
+        let rgb = clamp_rgb(rgb);
+        let dimming = clamp_dimming(dimming);
+
         if temp != 0 {
             let msg = json!({
                 "method": "setPilot",
@@ -176,7 +1284,7 @@ impl LightCommunication {
                     "state": is_on
                 }
             });
-            return msg.to_string();
+            msg.to_string()
         } else {
             let msg = json!({
                 "method": "setPilot",
@@ -188,17 +1296,54 @@ impl LightCommunication {
                     "state": is_on
                 }
             });
-            return msg.to_string();
+            msg.to_string()
         }
     }
 
+    /// Create the message to turn a lamp on or off, leaving the rest of its state as it is
+    fn set_state_message(&self, is_on: bool) -> String {
+        let msg = json!({
+            "method": "setPilot",
+            "params": {
+                "state": is_on
+            }
+        });
+        msg.to_string()
+    }
+
+    /// Create the message to set a scene
+    fn set_scene_message(&self, scene_id: u64, speed: Option<u64>, dimming: u64, is_on: bool) -> String {
+        let dimming = clamp_dimming(dimming);
+        let mut msg = json!({
+            "method": "setPilot",
+            "params": {
+                "sceneId": scene_id,
+                "dimming": dimming,
+                "state": is_on
+            }
+        });
+        if let Some(speed) = speed {
+            msg["params"]["speed"] = json!(speed);
+        }
+        msg.to_string()
+    }
+
     /// Create the message to get the pilot state
     fn get_pilot_message(&self) -> String {
         let msg = json!({
             "method": "getPilot",
             "params": {}
         });
-        return msg.to_string();
+        msg.to_string()
+    }
+
+    /// Create the message to get the model information
+    fn get_system_config_message(&self) -> String {
+        let msg = json!({
+            "method": "getSystemConfig",
+            "params": {}
+        });
+        msg.to_string()
     }
 
     /// Create the message to get the user config
@@ -207,7 +1352,7 @@ impl LightCommunication {
             "method": "getUserConfig",
             "params": {}
         });
-        return msg.to_string();
+        msg.to_string()
     }
 
     /// Create the message to set the dimming time
@@ -219,6 +1364,191 @@ impl LightCommunication {
                 "fadeOut": fade_out
             }
         });
-        return msg.to_string();
+        msg.to_string()
+    }
+}
+
+/// Clamp a dimming to the range the lamps accept, warning if it was out of it
+fn clamp_dimming(dimming: u64) -> u64 {
+    let clamped = dimming.clamp(MIN_DIMMING, MAX_DIMMING);
+    if clamped != dimming {
+        warn!("Dimming {} is out of range, using {}", dimming, clamped);
+    }
+
+    clamped
+}
+
+/// Check if a receive failed because the datagram didn't fit in the buffer
+/// 
+/// Only happens on Windows, other systems return the truncated datagram
+fn is_message_too_long(error: &io::Error) -> bool {
+    cfg!(windows) && error.raw_os_error() == Some(MESSAGE_TOO_LONG_ERROR)
+}
+
+/// A response received by a `SharedSocket`
+enum Received {
+    /// The whole response
+    Message(Vec<u8>),
+    /// The response didn't fit in the receive buffer
+    Truncated,
+}
+
+/// Responses received by a `SharedSocket`, waiting to be taken by the message they answer
+#[derive(Default)]
+struct Responses {
+    state: Mutex<ResponsesState>,
+    /// Notified when a response arrives or a message stops waiting for one
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct ResponsesState {
+    /// Addresses with a message waiting for its response, with the response once it arrives.
+    /// Responses nothing is waiting for are dropped, e.g. the ones to `send_message_no_reply()`
+    waiting: HashMap<SocketAddr, Option<Received>>,
+    /// Every address a datagram came from, while `collect_senders()` is running
+    senders: Option<Vec<SocketAddr>>,
+}
+
+/// Socket shared by every lamp of an address family
+/// 
+/// A thread receives every datagram and hands it to the message waiting for a response
+/// from that address, so the lamps can be sent messages at the same time
+struct SharedSocket {
+    socket: Arc<UdpSocket>,
+    responses: Arc<Responses>,
+    /// Tells the receiving thread to stop
+    stop: Arc<AtomicBool>,
+    receiver: Option<thread::JoinHandle<()>>,
+}
+
+impl SharedSocket {
+    /// Create the socket on a free port and start receiving
+    /// 
+    /// # Arguments
+    /// * `ipv6` - Bind it for IPv6 instead of IPv4, an IPv4 socket can't reach IPv6 lamps.
+    ///   IPv4 sockets can send broadcast messages
+    fn open(ipv6: bool) -> io::Result<Self> {
+        let socket = UdpSocket::bind(if ipv6 { "[::]:0" } else { "0.0.0.0:0" })?;
+        socket.set_read_timeout(Some(RECEIVE_POLL_INTERVAL))?;
+        if !ipv6 {
+            socket.set_broadcast(true)?;
+        }
+
+        let socket = Arc::new(socket);
+        let responses = Arc::new(Responses::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let receiver = {
+            let (socket, responses, stop) = (socket.clone(), responses.clone(), stop.clone());
+            thread::spawn(move || receive_responses(&socket, &responses, &stop))
+        };
+
+        Ok(Self { socket, responses, stop, receiver: Some(receiver) })
     }
-}
\ No newline at end of file
+
+    /// Send a message and wait for the response
+    /// 
+    /// Only one message per address waits for a response at a time,
+    /// the others wait for it to finish before being sent.
+    /// Returns `None` if the response doesn't arrive before `timeout`
+    /// 
+    /// # Arguments
+    /// * `msg` - The message to send
+    /// * `address` - The address of the lamp
+    /// * `timeout` - How long to wait for the response
+    fn request(&self, msg: &[u8], address: SocketAddr, timeout: Duration) -> io::Result<Option<Received>> {
+        let key = response_key(address);
+
+        let mut state = self.responses.state.lock().unwrap();
+        while state.waiting.contains_key(&key) {
+            state = self.responses.changed.wait(state).unwrap();
+        }
+        // Wait before sending, so a fast response isn't dropped
+        state.waiting.insert(key, None);
+        drop(state);
+
+        let sent = self.socket.send_to(msg, address);
+
+        let mut state = self.responses.state.lock().unwrap();
+        if sent.is_ok() {
+            let deadline = Instant::now() + timeout;
+            while matches!(state.waiting.get(&key), Some(None)) {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                state = self.responses.changed.wait_timeout(state, remaining).unwrap().0;
+            }
+        }
+        let received = state.waiting.remove(&key).flatten();
+        self.responses.changed.notify_all();
+
+        sent.map(|_| received)
+    }
+
+    /// Send a message and collect the address of everything that answers before `timeout`
+    /// 
+    /// # Arguments
+    /// * `msg` - The message to send
+    /// * `address` - Where to send it, usually a broadcast address
+    /// * `timeout` - How long to wait for answers
+    fn collect_senders(&self, msg: &[u8], address: (&str, u16), timeout: Duration) -> io::Result<Vec<SocketAddr>> {
+        self.responses.state.lock().unwrap().senders = Some(Vec::new());
+
+        let sent = self.socket.send_to(msg, address);
+        if sent.is_ok() {
+            thread::sleep(timeout);
+        }
+
+        let senders = self.responses.state.lock().unwrap().senders.take().unwrap_or_default();
+        sent.map(|_| senders)
+    }
+}
+
+impl Drop for SharedSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(receiver) = self.receiver.take() {
+            let _ = receiver.join();
+        }
+    }
+}
+
+/// Receive the datagrams of a `SharedSocket` until `stop` is set
+/// 
+/// A datagram is received whole, or truncated if it doesn't fit
+fn receive_responses(socket: &UdpSocket, responses: &Responses, stop: &AtomicBool) {
+    let mut buf = [0; RESPONSE_BUFFER_SIZE];
+
+    while !stop.load(Ordering::Relaxed) {
+        let (received, address) = match socket.recv_from(&mut buf) {
+            Ok((amt, address)) if amt < buf.len() => (Received::Message(buf[..amt].to_vec()), address),
+            Ok((_, address)) => (Received::Truncated, address),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut => continue,
+            // Windows doesn't say where the datagram came from, its message times out instead
+            Err(error) if is_message_too_long(&error) => {
+                debug!("Dropped a response longer than {} bytes", buf.len());
+                continue;
+            }
+            Err(error) => {
+                debug!("Error receiving a response: {}", error);
+                continue;
+            }
+        };
+
+        let mut state = responses.state.lock().unwrap();
+        if let Some(senders) = state.senders.as_mut() {
+            senders.push(address);
+        }
+        if let Some(response @ None) = state.waiting.get_mut(&response_key(address)) {
+            *response = Some(received);
+            responses.changed.notify_all();
+        }
+    }
+}
+
+/// Get the address a response is matched by, without the IPv6 flow info and scope,
+/// which responses don't always have
+fn response_key(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip(), address.port())
+}
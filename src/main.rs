@@ -1,181 +1,525 @@
-mod light_communication;
+use wiz_screen_sync::color::parse_hex_color;
+use wiz_screen_sync::config::ColorMode;
+use wiz_screen_sync::light_communication::RetryPolicy;
+use wiz_screen_sync::screen_sync::create_light_communication;
+use wiz_screen_sync::{config, ips, Config, LightCommunication, ScreenSync};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::fs;
+use std::io::{self, Write};
+
+/// Colors shown by `--calibrate`, with their names
+const REFERENCE_COLORS: [(&str, (u64, u64, u64)); 4] = [
+    ("white", (255, 255, 255)),
+    ("warm white", (255, 190, 120)),
+    ("grey", (128, 128, 128)),
+    ("skin tone", (225, 170, 135)),
+];
+
+/// If running with `--daemon`, set once the config is loaded
+static DAEMON: AtomicBool = AtomicBool::new(false);
+
+/// Sync WiZ lightbulbs to the colors on your screen
+///
+/// Options given here override the ones in config.toml
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Print the IPs of the lamps found in the network and exit
+    #[arg(long)]
+    discover: bool,
+    /// Check that every lamp answers, print their latency and exit
+    #[arg(long)]
+    test_lamps: bool,
+    /// Restore the lamps to the state saved by a session that didn't end cleanly and exit
+    #[arg(long)]
+    restore: bool,
+    /// Set the lamps to the current screen color and exit
+    #[arg(long)]
+    once: bool,
+    /// Blink a lamp to find which one it is and exit. Takes its IP or its name in the lamps file
+    #[arg(long, value_name = "IP")]
+    identify: Option<String>,
+    /// Show reference colors on a lamp and the other ones to find its `white_point_offset` and exit.
+    /// Takes its IP or its name in the lamps file
+    #[arg(long, value_name = "IP")]
+    calibrate: Option<String>,
+    /// Compute the colors without sending them to the lamps
+    #[arg(long)]
+    dry_run: bool,
+    /// Show the live color and latency in the terminal instead of logging them
+    #[arg(long)]
+    tui: bool,
+    /// Show the captured frame, the color sent and the crop and regions in a window
+    #[arg(long)]
+    preview: bool,
+    /// Run without a terminal, e.g. as a service or at login. Stop it with Ctrl+C, a signal or the HTTP control endpoint
+    #[arg(long)]
+    daemon: bool,
+    /// Write a JSON line to stdout each time a color is sent
+    #[arg(long)]
+    json_status: bool,
+    /// Send the colors to the lamps as a color temperature, for tunable white lamps
+    #[arg(long)]
+    temp_mode: bool,
+    /// File with the IPs of the lamps [default: $WIZ_SYNC_IPS or ips.txt]
+    #[arg(long, value_name = "PATH")]
+    ips: Option<String>,
+    /// Only use every nth pixel
+    #[arg(long, value_name = "N")]
+    pixel_skip: Option<usize>,
+    /// Only use every nth row of the screen
+    #[arg(long, value_name = "N")]
+    row_skip: Option<usize>,
+    /// Minimum color variation needed to send a new color
+    #[arg(long, value_name = "N")]
+    threshold: Option<u64>,
+    /// How the color is extracted from the screen
+    #[arg(long, value_name = "MODE")]
+    color_mode: Option<ColorMode>,
+    /// Maximum iterations per second, 0 = no limit
+    #[arg(long, value_name = "N")]
+    max_fps: Option<u64>,
+    /// Monitor to capture, 0 is the primary monitor
+    #[arg(long, value_name = "INDEX")]
+    monitor: Option<usize>,
+    /// Profile to sync, from `profiles` in the config: its lamps file and settings
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-use dxgcap::*;
-use winapi::um::winuser::*;
-use crossterm::{queue , terminal , cursor};
+/// Things to do instead of syncing
+#[derive(Subcommand)]
+enum Command {
+    /// Set every lamp to a color and exit
+    SetColor {
+        /// The color, as RRGGBB, e.g. FF8800
+        #[arg(long, value_name = "RRGGBB")]
+        hex: String,
+        /// The dimming, from 10 to 100
+        #[arg(long, value_name = "N", default_value_t = 100)]
+        dimming: u64,
+    },
+}
 
-use std::collections::HashMap;
-use std::time::Instant;
-use std::fs;
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-/// Improves performance by skipping pixels. Reduces color accuracy.
-/// 1 = no skipping, 2 = skip every other pixel, etc.
-const PIXEL_SKIPPING: usize = 1;
+    let args = Args::parse();
+    DAEMON.store(args.daemon, Ordering::SeqCst);
 
-/// If the color variation between iterations is lower than this value, 
-/// the program will not send a new color to the lamps
-const COLOR_VARIATION_THRESHOLD: u64 = 20;  // 0 = no variation, 255 = max variation
+    // Print the lamps found in the network and exit
+    if args.discover {
+        discover();
+        return;
+    }
 
-fn main() {
-    // Initialize lamps IPs
-    let mut lamps_ips = Vec::new();
-    match fs::read_to_string("ips.txt") {
-        Ok(lamps) => {
-            for lamp in lamps.lines() {
-                lamps_ips.push(lamp.to_string());
-            }
-        }
-        Err(_) => {
-            exit_with_error("Error reading ips.txt");
-        }
+    let (config, ips_path, lamps) = load(&args, args.profile.as_deref());
+
+    // Restore the lamps after a crash and exit
+    if args.restore {
+        restore(&config, lamps);
+        return;
     }
-    if lamps_ips.len() == 0 {
-        exit_with_error("No lamps found in ips.txt");
+
+    // Blink a lamp and exit
+    if let Some(lamp) = &args.identify {
+        identify(&config, lamps, lamp);
+        return;
     }
-            
 
-    // Initialize LightCommunication
-    let mut light_communication = light_communication::LightCommunication::new(lamps_ips);
+    // Find the white point offset of a lamp and exit
+    if let Some(lamp) = &args.calibrate {
+        calibrate(&config, lamps, lamp);
+        return;
+    }
 
-    // Get initial states
-    println!("Getting initial states...");
-    light_communication.get_initial_states();
+    // Set a static color and exit
+    if let Some(Command::SetColor { hex, dimming }) = &args.command {
+        set_color(&config, lamps, hex, *dimming);
+        return;
+    }
 
-    // Set fade speed to 0
-    println!("Setting fade speed to 0...");
-    light_communication.set_fade_speed_all(0, 0);
+    // Print which lamps answer and exit
+    if args.test_lamps {
+        test_lamps(&config, lamps);
+        return;
+    }
 
-    // Initialize capture
-    println!("Initializing capture...");
+    // Set the lamps to the current screen color and exit
+    if args.once {
+        let result = ScreenSync::new(config, lamps).and_then(|mut screen_sync| screen_sync.run_once());
+        if let Err(error) = result {
+            exit_with_error(&error.to_string());
+        }
+        return;
+    }
 
-    let mut capturer = DXGIManager::new(300).unwrap();
-    let (mut previous_frame, (width , height)) = capturer.capture_frame().unwrap();
+    // Stop the loop on Ctrl+C, SIGTERM or when the console is closed, whatever profile is syncing.
+    // Repeated presses are ignored so the restore only runs once
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let handler_stop_flag = stop_flag.clone();
+    ctrlc::set_handler(move || {
+        if !handler_stop_flag.swap(true, Ordering::SeqCst) {
+            info!("Stopping...");
+        }
+    }).unwrap_or_else(|_| exit_with_error("Error setting Ctrl+C handler"));
 
-    // Get this window
-    let this_window = unsafe { GetForegroundWindow() };
-    
-    // Clear terminal
-    queue!(std::io::stdout(), terminal::Clear(terminal::ClearType::All)).unwrap();
-    
-    let mut previous_color = (0, 0, 0);
+    // Each session restores its lamps before returning, so switching profiles starts from scratch
+    let mut profile = args.profile.clone();
+    let (mut config, mut ips_path, mut lamps) = (config, ips_path, lamps);
     loop {
-        // Start timer
-        let start = Instant::now();
- 
-        // Capture frame or fallback to previous frame
-        let (frame, (_,_)) = capturer.capture_frame().unwrap_or((previous_frame, (width, height)));
-        previous_frame = frame.clone();
-
-        // Get most common color
-        // let selected_color = get_most_common_color(frame);
-
-        // Get average color
-        let selected_color = get_average_color(frame);
-        
-        // Send color to lamps
-        if calculate_color_variation(selected_color, previous_color) > COLOR_VARIATION_THRESHOLD {
-            light_communication.set_color_all(selected_color, 0, 100, true);
-
-            print_color_and_instructions(selected_color, start);
-
-            previous_color = selected_color;
+        let profiles: Vec<String> = config.profiles.keys().cloned().collect();
+        let mut screen_sync = ScreenSync::new(config, lamps)
+            .unwrap_or_else(|error| exit_with_error(&error.to_string()));
+        screen_sync.set_stop_flag(stop_flag.clone());
+        // Pick up lamps added to or removed from the file while syncing
+        if fs::metadata(&ips_path).is_ok() {
+            screen_sync.watch_lamps_file(&ips_path);
         }
 
-        // If ESC is pressed (high order bit is set)
-        // and active window is this window
-        unsafe {
-            if GetKeyState(VK_ESCAPE) & 0x1000 != 0{
-                let current_window = GetForegroundWindow();
-                if current_window == this_window {
-                    break;
-                }
-            }  
-        } 
+        if let Err(error) = screen_sync.run() {
+            exit_with_error(&error.to_string());
+        }
+        if !screen_sync.profile_switch_requested() {
+            break;
+        }
+
+        profile = Some(next_profile(&profiles, profile.as_deref()));
+        info!("Switching to the {} profile", profile.as_deref().unwrap_or_default());
+        (config, ips_path, lamps) = load(&args, profile.as_deref());
     }
 
-    // Restore previous lamps state
-    println!("Restoring previous lamps state...");
-    light_communication.restore_initial_states();
+    info!("Byebye!");
+}
+
+/// Load the config and the lamps, of a profile if one is given
+///
+/// The command line overrides the config of the profile, which overrides config.toml, which overrides the defaults.
+/// Exits on errors
+///
+/// # Arguments
+/// * `args` - The command line options
+/// * `profile` - The name of the profile in `profiles`, `None` = config.toml and the default lamps file
+///
+/// Returns the config, the path of the lamps file and the lamps
+fn load(args: &Args, profile: Option<&str>) -> (Config, String, Vec<ips::Lamp>) {
+    let config_path = file_path(None, config::CONFIG_ENV, config::CONFIG_PATH);
+    let loaded = match profile {
+        Some(profile) => Config::load_profile(&config_path, profile).map(|(config, profile)| (config, Some(profile))),
+        None => Config::load(&config_path).map(|config| (config, None)),
+    };
+    let (mut config, profile) = loaded.unwrap_or_else(|error| exit_with_error(&error));
+    if fs::metadata(&config_path).is_ok() {
+        info!("Loaded the config from {}", config_path);
+    } else {
+        info!("{} not found, using the default config", config_path);
+    }
+    if let Some(profile) = profile.as_ref().filter(|profile| !profile.config.is_empty()) {
+        info!("Loaded the profile settings from {}", profile.config);
+    }
+    apply_args(&mut config, args);
+    DAEMON.store(config.daemon, Ordering::SeqCst);
+    if let Err(error) = config.validate() {
+        exit_with_error(&error);
+    }
 
-    println!("Byebye!");
+    // Initialize lamps. A dry run doesn't need any
+    let profile_ips = profile.as_ref().map(|profile| profile.ips.as_str());
+    let ips_path = file_path(args.ips.as_deref().or(profile_ips), config::IPS_ENV, config::IPS_PATH);
+    let lamps = match fs::read_to_string(&ips_path) {
+        Ok(lamps) => match ips::parse_lamps(&lamps) {
+            Ok(lamps) => lamps,
+            Err(error) => exit_with_error(&format!("Error parsing {}\n{}", ips_path, error)),
+        },
+        Err(_) if config.dry_run => Vec::new(),
+        Err(_) => exit_with_error(&format!("Error reading {}", ips_path)),
+    };
+    if lamps.is_empty() && !config.dry_run {
+        exit_with_error(&format!("No lamps found in {}", ips_path));
+    }
+    if !lamps.is_empty() {
+        info!("Loaded {} lamps from {}", lamps.len(), ips_path);
+    }
+
+    (config, ips_path, lamps)
 }
 
+/// Get the profile after `current`, in alphabetical order and going back to the first after the last
+///
+/// # Arguments
+/// * `profiles` - The names of the profiles, sorted
+/// * `current` - The profile syncing now, `None` = no profile
+fn next_profile(profiles: &[String], current: Option<&str>) -> String {
+    let next = current
+        .and_then(|current| profiles.iter().position(|profile| profile == current))
+        .map_or(0, |index| (index + 1) % profiles.len());
+
+    profiles[next].clone()
+}
 
-fn calculate_color_variation(rgb: (u64, u64, u64), previous_rgb: (u64, u64, u64)) -> u64 {
-    let r = (rgb.0 as i64 - previous_rgb.0 as i64).abs() as u64;
-    let g = (rgb.1 as i64 - previous_rgb.1 as i64).abs() as u64;
-    let b = (rgb.2 as i64 - previous_rgb.2 as i64).abs() as u64;
 
-    return r + g + b;
+/// Get the path of a file: the command line option if given, else the environment variable, else the default
+///
+/// Relative paths are made absolute, so the messages show which file was used wherever the program runs from
+///
+/// # Arguments
+/// * `arg` - The path given in the command line
+/// * `env_var` - The environment variable with the path
+/// * `default` - The path used otherwise, relative to the working directory
+fn file_path(arg: Option<&str>, env_var: &str, default: &str) -> String {
+    let path = arg.map(str::to_string)
+        .or_else(|| std::env::var(env_var).ok().filter(|path| !path.is_empty()))
+        .unwrap_or_else(|| default.to_string());
+
+    std::path::absolute(&path).map_or(path, |absolute| absolute.display().to_string())
+}
+
+/// Override the config with the options given in the command line
+fn apply_args(config: &mut Config, args: &Args) {
+    config.dry_run |= args.dry_run;
+    config.tui |= args.tui;
+    config.preview |= args.preview;
+    config.daemon |= args.daemon;
+    config.json_status |= args.json_status;
+    config.temp_mode |= args.temp_mode;
+    if let Some(pixel_skip) = args.pixel_skip {
+        config.pixel_skipping = pixel_skip;
+    }
+    if let Some(row_skip) = args.row_skip {
+        config.row_skipping = row_skip;
+    }
+    if let Some(threshold) = args.threshold {
+        config.color_variation_threshold = threshold;
+    }
+    if let Some(color_mode) = args.color_mode {
+        config.color_mode = color_mode;
+    }
+    if let Some(max_fps) = args.max_fps {
+        config.max_fps = max_fps;
+    }
+    if let Some(monitor) = args.monitor {
+        config.monitor_index = monitor;
+    }
 }
 
-fn get_average_color(pixels: Vec<BGRA8>) -> (u64, u64, u64) {    
-    let mut r: u64 = 0;
-    let mut g: u64 = 0;
-    let mut b: u64 = 0;
+/// Ping every lamp and print a table with the results
+fn test_lamps(config: &Config, lamps: Vec<ips::Lamp>) {
+    info!("Testing {} lamps...", lamps.len());
 
-    // Filter (0,0,0) pixels
-    let filtered_pixels = pixels.iter().filter(|pixel| pixel.r != 0 || pixel.g != 0 || pixel.b != 0);
-    let pixel_count = filtered_pixels.clone().count() as u64;
+    // Without retries, so an offline lamp is only waited for once
+    let mut light_communication = create_light_communication(config, lamps)
+        .unwrap_or_else(|error| exit_with_error(&error.to_string()));
+    light_communication.set_retry_policy(RetryPolicy { retries: 0, base_delay: Duration::ZERO });
 
-    // If amount of pixels after filtering out black is less than 10%, return (1,1,1).
-    // (0,0,0) is not accepted by the lamps
-    if pixel_count < pixels.len() as u64 * 10 / 100 {
-        return (1, 1, 1);
+    println!("{:<40} {:<10} Latency", "Lamp", "Reachable");
+    for (ip, result) in light_communication.ping_all() {
+        match result {
+            Ok(latency) => println!("{:<40} {:<10} {}ms", light_communication.label(&ip), "yes", latency.as_millis()),
+            Err(error) => println!("{:<40} {:<10} {}", light_communication.label(&ip), "no", error),
+        }
     }
-    
-    for pixel in filtered_pixels.step_by(PIXEL_SKIPPING) {
-        r += pixel.r as u64;
-        g += pixel.g as u64;
-        b += pixel.b as u64;
+}
+
+/// Set every lamp to a color, without syncing or restoring them afterwards
+///
+/// # Arguments
+/// * `config` - The configuration to use
+/// * `lamps` - The lamps to set
+/// * `hex` - The color, as `RRGGBB`
+/// * `dimming` - The dimming, from 10 to 100
+fn set_color(config: &Config, lamps: Vec<ips::Lamp>, hex: &str, dimming: u64) {
+    let rgb = parse_hex_color(hex).unwrap_or_else(|error| exit_with_error(&error));
+    if !(10..=100).contains(&dimming) {
+        exit_with_error(&format!("Invalid dimming ({}), it has to be between 10 and 100", dimming));
     }
 
-    return (
-        (r / pixel_count),
-        (g / pixel_count),
-        (b / pixel_count),
-    );
+    let mut light_communication = create_light_communication(config, lamps)
+        .unwrap_or_else(|error| exit_with_error(&error.to_string()));
+    // White-only lamps get the closest white, the other ones are set anyway
+    if let Err(error) = light_communication.get_system_configs() {
+        info!("{}", error);
+    }
+    if let Err(error) = light_communication.set_color_all(rgb, 0, dimming, true) {
+        exit_with_error(&error.to_string());
+    }
+    info!("Set {} lamps to #{}", light_communication.lights().len(), hex.trim_start_matches('#').to_uppercase());
 }
 
-fn _get_most_common_color(pixels: Vec<BGRA8>) -> (u8, u8, u8) {
-    let mut colors: HashMap<(u8, u8, u8), u32> = HashMap::new();
+/// Blink a lamp with the `identify_*` options, then give it its state back
+///
+/// # Arguments
+/// * `config` - The configuration to use
+/// * `lamps` - The lamps from the lamps file, for the names and ports
+/// * `lamp` - The IP or the name of the lamp, it doesn't have to be in the lamps file
+fn identify(config: &Config, lamps: Vec<ips::Lamp>, lamp: &str) {
+    let lamp = find_lamp(&lamps, lamp);
+    let ip = lamp.ip.to_string();
+
+    let mut light_communication = create_light_communication(config, vec![lamp])
+        .unwrap_or_else(|error| exit_with_error(&error.to_string()));
+    // White-only lamps blink in the closest white
+    if let Err(error) = light_communication.get_system_configs() {
+        info!("{}", error);
+    }
 
-    for pixel in pixels {
-        let color = (pixel.r, pixel.g, pixel.b);
-        let count = colors.entry(color).or_insert(0);
-        *count += 1;
+    info!("Blinking {}...", light_communication.label(&ip));
+    let [r, g, b] = config.identify_color;
+    let interval = Duration::from_millis(config.identify_interval_ms);
+    if let Err(error) = light_communication.identify(&ip, (r, g, b), config.identify_blinks, interval) {
+        exit_with_error(&error.to_string());
     }
-    
-    let mut most_common_color = *colors.keys().nth(0).unwrap();
+}
 
-    if colors.len() > 0 {
-        for (color, count) in colors.iter() {
-            if count > colors.get(&most_common_color).unwrap() {
-                most_common_color = *color;
+/// Show the `REFERENCE_COLORS` on a lamp and the other lamps, changing its `white_point_offset`
+/// with the commands typed until they match, then give them their states back and print the offset
+///
+/// # Arguments
+/// * `config` - The configuration to use
+/// * `lamps` - The lamps from the lamps file, to compare the lamp with
+/// * `lamp` - The IP or the name of the lamp, it doesn't have to be in the lamps file
+fn calibrate(config: &Config, lamps: Vec<ips::Lamp>, lamp: &str) {
+    let lamp = find_lamp(&lamps, lamp);
+    let ip = lamp.ip.to_string();
+    let mut lamps: Vec<ips::Lamp> = lamps.into_iter().filter(|other| other.ip != lamp.ip).collect();
+    lamps.push(lamp);
+
+    let mut config = config.clone();
+    let mut light_communication = create_light_communication(&config, lamps)
+        .unwrap_or_else(|error| exit_with_error(&error.to_string()));
+    // Each command shows its color, however fast they're typed
+    light_communication.set_min_send_interval(Duration::ZERO);
+    if let Err(error) = light_communication.get_initial_states() {
+        exit_with_error(&error.to_string());
+    }
+    if light_communication.system_config(&ip).is_some_and(|system_config| !system_config.supports_rgb())
+        || config.temp_mode || config.temp_lamps.contains(&ip) {
+        warn!("{} gets color temperatures, the offset doesn't change them", light_communication.label(&ip));
+    }
+
+    println!("Type r, g or b followed by a change of the offset (e.g. b-10) until {} matches the other lamps,", light_communication.label(&ip));
+    println!("n for the next reference color and q to finish");
+    let mut offset = config.white_point_offset.get(&ip).copied().unwrap_or([0; 3]);
+    let mut reference = 0;
+    loop {
+        config.white_point_offset.insert(ip.clone(), offset);
+        light_communication.set_calibrations(config.lamp_calibrations());
+        let (name, rgb) = REFERENCE_COLORS[reference];
+        if let Err(error) = light_communication.set_color_all(rgb, 0, 100, true) {
+            warn!("{}", error);
+        }
+
+        print!("{} - offset {:?} > ", name, offset);
+        let _ = io::stdout().flush();
+        let mut command = String::new();
+        if io::stdin().read_line(&mut command).unwrap_or(0) == 0 {
+            break;
+        }
+        let command = command.trim().to_lowercase();
+        match command.as_str() {
+            "q" => break,
+            "n" => reference = (reference + 1) % REFERENCE_COLORS.len(),
+            _ => {
+                let channel = match command.chars().next() {
+                    Some('r') => 0,
+                    Some('g') => 1,
+                    Some('b') => 2,
+                    _ => {
+                        println!("Unknown command, use r, g or b followed by a change (e.g. g+5), n or q");
+                        continue;
+                    }
+                };
+                match command[1..].trim().parse::<i64>() {
+                    Ok(change) => offset[channel] = (offset[channel] + change).clamp(-255, 255),
+                    Err(_) => println!("Invalid change, use a number like +5 or -10"),
+                }
             }
         }
     }
 
-    return most_common_color;
+    if let Err(error) = light_communication.restore_initial_states() {
+        exit_with_error(&error.to_string());
+    }
+    println!("Add this line under [white_point_offset] in the config:");
+    println!("\"{}\" = [{}, {}, {}]", ip, offset[0], offset[1], offset[2]);
+}
+
+/// Find a lamp of the lamps file by IP or by name, or parse it if it's not in the file. Exits if it's neither
+///
+/// # Arguments
+/// * `lamps` - The lamps from the lamps file, for the names and ports
+/// * `lamp` - The IP or the name of the lamp
+fn find_lamp(lamps: &[ips::Lamp], lamp: &str) -> ips::Lamp {
+    let known = lamps.iter()
+        .find(|known| known.ip.to_string() == lamp || known.name.as_deref() == Some(lamp));
+    match known {
+        Some(lamp) => lamp.clone(),
+        None => match ips::parse_lamps(lamp) {
+            Ok(mut parsed) if parsed.len() == 1 => parsed.remove(0),
+            _ => exit_with_error(&format!("{} is not an IP nor the name of a lamp", lamp)),
+        },
+    }
+}
+
+/// Restore the lamps to the states saved in `state_file` and remove it
+fn restore(config: &Config, lamps: Vec<ips::Lamp>) {
+    if config.state_file.is_empty() || fs::metadata(&config.state_file).is_err() {
+        info!("There are no saved lamps states, nothing to restore");
+        return;
+    }
+
+    let mut light_communication = create_light_communication(config, lamps)
+        .unwrap_or_else(|error| exit_with_error(&error.to_string()));
+    match light_communication.load_initial_states(&config.state_file) {
+        Ok(count) => info!("Restoring {} lamps...", count),
+        Err(error) => exit_with_error(&error),
+    }
+    if let Err(error) = light_communication.restore_initial_states() {
+        exit_with_error(&error.to_string());
+    }
+
+    if let Err(error) = fs::remove_file(&config.state_file) {
+        exit_with_error(&format!("Error removing {}: {}", config.state_file, error));
+    }
+    info!("Lamps restored");
 }
 
+/// Print the IPs of the lamps found in the network, in the `ips.txt` format
+fn discover() {
+    info!("Looking for lamps...");
 
-fn exit_with_error(error: &str) {
-    println!("{}", error);
-    println!("");
+    match LightCommunication::discover(Duration::from_secs(3)) {
+        Ok(ips) if ips.is_empty() => info!("No lamps found"),
+        Ok(ips) => {
+            info!("Found {} lamps, add them to ips.txt:", ips.len());
+            for ip in ips {
+                println!("{}", ip);
+            }
+        }
+        Err(error) => exit_with_error(&error.to_string()),
+    }
+}
+
+/// Log an error and exit
+///
+/// Waits for enter first so the error can be read when the console closes on exit,
+/// unless running as a daemon where nobody can press it
+fn exit_with_error(error: &str) -> ! {
+    error!("{}", error);
+    if DAEMON.load(Ordering::SeqCst) {
+        std::process::exit(1);
+    }
+
+    println!();
     println!("Press enter to exit...");
 
     std::io::stdin().read_line(&mut String::new()).unwrap();
 
     std::process::exit(1);
 }
-
-fn print_color_and_instructions(rgb: (u64, u64, u64), time_start: Instant) {
-    // set cursor to 0,0
-    queue!(std::io::stdout(), cursor::MoveTo(0, 0)).unwrap();
-    // clear line
-    queue!(std::io::stdout(), terminal::Clear(terminal::ClearType::CurrentLine)).unwrap();
-    println!("Color set to: {:?} - {}ms", rgb, time_start.elapsed().as_millis());
-    println!();
-    println!("Press 'ESC' to quit");
-}
\ No newline at end of file
@@ -0,0 +1,73 @@
+//! Counters of a running sync, for monitoring
+//!
+//! They're always counted, and served in the Prometheus text format on `GET /metrics`
+//! by the control server when built with the `metrics` feature.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters of a sync, shared between the loop, the lamps and the control server
+#[derive(Default)]
+pub struct Metrics {
+    /// Frames taken from the capture
+    frames_captured: AtomicU64,
+    /// Colors sent to a lamp, a color sent to every lamp counts once per lamp
+    colors_sent: AtomicU64,
+    /// Colors that couldn't be sent, by lamp IP. Sorted so the output is stable
+    lamp_send_errors: Mutex<BTreeMap<String, u64>>,
+    /// How long the last iteration of the loop took, in microseconds
+    loop_latency_us: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a frame taken from the capture
+    pub fn frame_captured(&self) {
+        self.frames_captured.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count colors sent to the lamps
+    ///
+    /// # Arguments
+    /// * `lamps` - How many lamps got the color
+    pub fn colors_sent(&self, lamps: u64) {
+        self.colors_sent.fetch_add(lamps, Ordering::Relaxed);
+    }
+
+    /// Count a color that couldn't be sent to a lamp
+    pub fn lamp_send_error(&self, ip: &str) {
+        *self.lamp_send_errors.lock().unwrap().entry(ip.to_string()).or_insert(0) += 1;
+    }
+
+    /// Set how long the last iteration of the loop took
+    pub fn set_loop_latency(&self, latency: Duration) {
+        self.loop_latency_us.store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Get the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        let _ = writeln!(text, "# HELP frames_captured_total Frames taken from the screen capture");
+        let _ = writeln!(text, "# TYPE frames_captured_total counter");
+        let _ = writeln!(text, "frames_captured_total {}", self.frames_captured.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP colors_sent_total Colors sent to a lamp, once per lamp");
+        let _ = writeln!(text, "# TYPE colors_sent_total counter");
+        let _ = writeln!(text, "colors_sent_total {}", self.colors_sent.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP lamp_send_errors_total Colors that couldn't be sent to a lamp");
+        let _ = writeln!(text, "# TYPE lamp_send_errors_total counter");
+        for (ip, errors) in self.lamp_send_errors.lock().unwrap().iter() {
+            let _ = writeln!(text, "lamp_send_errors_total{{ip=\"{}\"}} {}", ip, errors);
+        }
+
+        let _ = writeln!(text, "# HELP loop_latency_ms Time the last iteration of the sync loop took");
+        let _ = writeln!(text, "# TYPE loop_latency_ms gauge");
+        let _ = writeln!(text, "loop_latency_ms {:.3}", self.loop_latency_us.load(Ordering::Relaxed) as f64 / 1000.0);
+
+        text
+    }
+}
@@ -0,0 +1,121 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::fmt;
+
+use crate::light_communication::LightError;
+
+/// State of a lamp, as returned by `getPilot`
+///
+/// Some firmware versions omit fields, so everything but `state` is optional
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PilotState {
+    /// If the lamp is on
+    pub state: bool,
+    /// Brightness, from 10 to 100
+    pub dimming: Option<u64>,
+    /// Color temperature in Kelvin, only present in white mode
+    pub temp: Option<u64>,
+    /// RGB color, only present in color mode
+    pub r: Option<u64>,
+    pub g: Option<u64>,
+    pub b: Option<u64>,
+    /// Scene the lamp is running, 0 = no scene
+    pub scene_id: Option<u64>,
+    /// Speed of the scene animation, from 10 to 200
+    pub speed: Option<u64>,
+    /// Fade in time, taken from `getUserConfig`
+    pub fade_in: Option<u64>,
+    /// Fade out time, taken from `getUserConfig`
+    pub fade_out: Option<u64>,
+}
+
+impl PilotState {
+    /// Get the RGB color, if the lamp is in color mode
+    pub fn rgb(&self) -> Option<(u64, u64, u64)> {
+        Some((self.r?, self.g?, self.b?))
+    }
+
+    /// Get the scene the lamp is running, if any
+    pub fn scene(&self) -> Option<u64> {
+        self.scene_id.filter(|scene_id| *scene_id != 0)
+    }
+}
+
+impl fmt::Display for PilotState {
+    /// Describe the state for messages, e.g. `on, rgb (255, 120, 0), dimming 100`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", if self.state { "on" } else { "off" })?;
+
+        if let Some(scene_id) = self.scene() {
+            write!(f, ", scene {}", scene_id)?;
+        } else if let Some(temp) = self.temp {
+            write!(f, ", {}K", temp)?;
+        } else if let Some(rgb) = self.rgb() {
+            write!(f, ", rgb {:?}", rgb)?;
+        }
+
+        match self.dimming {
+            Some(dimming) => write!(f, ", dimming {}", dimming),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Fade times, as returned by `getUserConfig`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserConfig {
+    pub fade_in: Option<u64>,
+    pub fade_out: Option<u64>,
+}
+
+/// Lamp model information, as returned by `getSystemConfig`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemConfig {
+    /// Model of the lamp, e.g. `ESP01_SHRGB1C_31`
+    pub module_name: Option<String>,
+    /// MAC address of the lamp
+    pub mac: Option<String>,
+}
+
+impl SystemConfig {
+    /// Check if the lamp can show RGB colors
+    ///
+    /// Only color lamps have `RGB` in their module name, white-only ones have `TW` or `DW`.
+    /// Lamps with an unknown module name are assumed to support RGB
+    pub fn supports_rgb(&self) -> bool {
+        match &self.module_name {
+            Some(module_name) => module_name.contains("RGB") || !(module_name.contains("TW") || module_name.contains("DW")),
+            None => true,
+        }
+    }
+}
+
+/// Envelope of every lamp response. Either `result` or `error` is set
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    result: Option<T>,
+    error: Option<Value>,
+}
+
+/// Parse the `result` of a lamp response
+///
+/// # Arguments
+/// * `lamp` - The lamp that sent the response, used in errors
+/// * `response` - The response of the lamp
+pub fn parse_result<T: DeserializeOwned>(lamp: &str, response: &str) -> Result<T, LightError> {
+    let response: Response<T> = serde_json::from_str(response)
+        .map_err(|source| LightError::Parse { lamp: lamp.to_string(), source })?;
+
+    match (response.result, response.error) {
+        (Some(result), _) => Ok(result),
+        (None, error) => Err(LightError::Response {
+            lamp: lamp.to_string(),
+            error: error.map_or("empty result".to_string(), |error| error.to_string()),
+        }),
+    }
+}
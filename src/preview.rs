@@ -0,0 +1,113 @@
+//! Window showing the captured frame and the color sent to the lamps, used with `preview`
+//!
+//! The frame is shown downscaled, surrounded by a border with the current color.
+//! The cropped part of the screen is outlined in white, the excluded regions in red
+//! and the regions of the lamps in green, to check them visually.
+
+use minifb::{Window, WindowOptions};
+
+use crate::capture::BGRA8;
+use crate::color::downscale;
+use crate::config::{Config, Region};
+
+/// Width of the frame in the window, in pixels. The height follows the aspect ratio of the screen
+const PREVIEW_WIDTH: usize = 480;
+/// Width of the color border around the frame, in pixels
+const BORDER: usize = 24;
+/// Colors of the outlines, as `0RGB`
+const CROP_OUTLINE: u32 = 0xFFFFFF;
+const EXCLUDED_OUTLINE: u32 = 0xFF0000;
+const REGION_OUTLINE: u32 = 0x00FF00;
+
+/// The preview window, it's closed when it's dropped
+pub struct Preview {
+    window: Window,
+    /// Pixels of the window, as `0RGB`
+    buffer: Vec<u32>,
+    /// Size of the frame in the window
+    width: usize,
+    height: usize,
+}
+
+impl Preview {
+    /// Open the preview window
+    ///
+    /// # Arguments
+    /// * `(frame_width, frame_height)` - The size of the captured frames
+    pub fn open((frame_width, frame_height): (usize, usize)) -> Result<Self, String> {
+        let width = PREVIEW_WIDTH;
+        let height = (PREVIEW_WIDTH * frame_height / frame_width.max(1)).max(1);
+        let window = Window::new(
+            "wiz-screen-sync preview",
+            width + 2 * BORDER,
+            height + 2 * BORDER,
+            WindowOptions::default(),
+        ).map_err(|error| format!("Error opening the preview window: {}", error))?;
+
+        Ok(Self { window, buffer: vec![0; (width + 2 * BORDER) * (height + 2 * BORDER)], width, height })
+    }
+
+    /// Draw a frame and the current color
+    ///
+    /// Returns false once the window has been closed
+    ///
+    /// # Arguments
+    /// * `frame` - The captured frame, before cutting the margins
+    /// * `(frame_width, frame_height)` - The size of the frame
+    /// * `rgb` - The color sent to the lamps
+    /// * `config` - The configuration, for the crop and the regions
+    pub fn update(&mut self, frame: &[BGRA8], (frame_width, frame_height): (usize, usize), rgb: (u64, u64, u64), config: &Config) -> bool {
+        if !self.window.is_open() {
+            return false;
+        }
+
+        let (pixels, (width, height)) = downscale(frame, frame_width, frame_height, self.width, self.height);
+        let stride = self.width + 2 * BORDER;
+
+        self.buffer.fill(((rgb.0 as u32) << 16) | ((rgb.1 as u32) << 8) | rgb.2 as u32);
+        for y in 0..height.min(self.height) {
+            for x in 0..width.min(self.width) {
+                let pixel = pixels[y * width + x];
+                self.buffer[(y + BORDER) * stride + x + BORDER] = ((pixel.r as u32) << 16) | ((pixel.g as u32) << 8) | pixel.b as u32;
+            }
+        }
+
+        // Excluded and lamp regions are relative to the cropped screen
+        let crop = config.capture_crop.region();
+        let in_crop = |region: &Region| Region {
+            x: crop.x + region.x * crop.w,
+            y: crop.y + region.y * crop.h,
+            w: region.w * crop.w,
+            h: region.h * crop.h,
+        };
+        if !config.capture_crop.is_empty() {
+            self.outline(&crop, CROP_OUTLINE);
+        }
+        for region in config.excluded_regions.iter() {
+            self.outline(&in_crop(region), EXCLUDED_OUTLINE);
+        }
+        for region in config.regions.values() {
+            self.outline(&in_crop(region), REGION_OUTLINE);
+        }
+
+        self.window.update_with_buffer(&self.buffer, stride, self.height + 2 * BORDER).is_ok()
+    }
+
+    /// Draw the outline of a region of the frame
+    fn outline(&mut self, region: &Region, color: u32) {
+        let stride = self.width + 2 * BORDER;
+        let x_start = (region.x * self.width as f64) as usize;
+        let y_start = (region.y * self.height as f64) as usize;
+        let x_end = (((region.x + region.w) * self.width as f64) as usize).clamp(x_start + 1, self.width);
+        let y_end = (((region.y + region.h) * self.height as f64) as usize).clamp(y_start + 1, self.height);
+
+        for x in x_start..x_end {
+            self.buffer[(y_start + BORDER) * stride + x + BORDER] = color;
+            self.buffer[(y_end - 1 + BORDER) * stride + x + BORDER] = color;
+        }
+        for y in y_start..y_end {
+            self.buffer[(y + BORDER) * stride + x_start + BORDER] = color;
+            self.buffer[(y + BORDER) * stride + x_end - 1 + BORDER] = color;
+        }
+    }
+}
@@ -0,0 +1,1147 @@
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::capture::{capture_warm_frame, create_capturer, CaptureThread, BGRA8};
+use crate::color::*;
+use crate::config::{BlackScreenBehavior, BlackScreenTimeoutBehavior, CaptureCrop, Config, OnLampError, Scene};
+use crate::focus::FocusWatcher;
+#[cfg(feature = "http")]
+use crate::control::{ControlServer, ControlState};
+use crate::ips::{IpsWatcher, Lamp};
+use crate::keys::{quit_hint, NumberKeys};
+#[cfg(windows)]
+use crate::keys::HotKey;
+use crate::light_communication::{LightCommunication, LightError, RetryPolicy, SyncStatus};
+use crate::metrics::Metrics;
+#[cfg(feature = "preview")]
+use crate::preview::Preview;
+use crate::tui::Tui;
+
+/// Color temperature used by `BlackScreenBehavior::Dim`
+const DIM_TEMP: u64 = 2700;
+/// Dimming used by `BlackScreenBehavior::Dim`, the lowest the lamps accept
+const DIM_DIMMING: u64 = 10;
+/// How often `pause()` is checked while paused
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Delay between the attempts to reach the lamps on start, see `startup_timeout_secs`
+const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// Smallest dimming change that is sent to the lamps on its own
+const DIMMING_THRESHOLD: u64 = 5;
+/// How long to wait for a new frame before reusing the previous one
+const FRAME_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Errors that stop a sync session
+#[derive(Debug)]
+pub enum SyncError {
+    /// Communication with a lamp failed
+    Light(LightError),
+    /// The screen couldn't be captured
+    Capture(String),
+    /// The control server couldn't be started
+    Control(String),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Light(error) => write!(f, "{}", error),
+            SyncError::Capture(error) => write!(f, "Error capturing the screen: {}", error),
+            SyncError::Control(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<LightError> for SyncError {
+    fn from(error: LightError) -> Self {
+        SyncError::Light(error)
+    }
+}
+
+/// Line written to stdout with `json_status` each time a color is sent
+///
+/// The fields are part of the output format, only add new ones
+#[derive(Debug, Serialize)]
+pub struct StatusLine {
+    /// When the color was sent, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// The color sent, as `[r, g, b]`
+    pub rgb: [u64; 3],
+    /// Time from the capture to the color being sent, in milliseconds
+    pub latency_ms: u64,
+    /// How many lamps the color was sent to
+    pub lamp_count: usize,
+}
+
+/// Colors extracted from a frame, before smoothing
+struct FrameColors {
+    /// Color of the whole frame
+    color: (u64, u64, u64),
+    /// Dimming to send to the lamps
+    dimming: u64,
+    /// If the `black_screen_behavior` applies to the frame
+    is_black: bool,
+    /// Color of each lamp with a region, by lamp IP
+    region_colors: HashMap<String, (u64, u64, u64)>,
+}
+
+/// Loop durations collected between two reports of `latency_stats_interval`
+struct LatencyStats {
+    /// Duration of each frame since the last report
+    durations: Vec<Duration>,
+    /// When the stats were last logged
+    last_report: Instant,
+}
+
+impl LatencyStats {
+    fn new() -> Self {
+        Self { durations: Vec::new(), last_report: Instant::now() }
+    }
+
+    /// Add the duration of a frame, and log the stats once `interval` has passed since the last report
+    ///
+    /// The durations are cleared after each report, so every report covers its own interval
+    ///
+    /// # Arguments
+    /// * `duration` - Time from the capture to the end of the frame, without the wait for `max_fps`
+    /// * `interval` - Time between the reports
+    fn record(&mut self, duration: Duration, interval: Duration) {
+        self.durations.push(duration);
+        if self.last_report.elapsed() < interval {
+            return;
+        }
+
+        self.durations.sort();
+        let count = self.durations.len();
+        let total: Duration = self.durations.iter().sum();
+        let p95 = self.durations[((count as f64 * 0.95).ceil() as usize).saturating_sub(1)];
+        info!(
+            "Latency over {} frames: min {:.1}ms, avg {:.1}ms, max {:.1}ms, p95 {:.1}ms",
+            count,
+            self.durations[0].as_secs_f64() * 1000.0,
+            total.as_secs_f64() * 1000.0 / count as f64,
+            self.durations[count - 1].as_secs_f64() * 1000.0,
+            p95.as_secs_f64() * 1000.0,
+        );
+
+        self.durations.clear();
+        self.last_report = Instant::now();
+    }
+}
+
+/// Captures the screen and syncs the lamps to it
+pub struct ScreenSync {
+    config: Config,
+    light_communication: LightCommunication,
+    /// Set by `stop()` to end the loop in `run()`
+    stop_requested: Arc<AtomicBool>,
+    /// Set by `pause()`, the lamps are restored while it's set
+    paused: Arc<AtomicBool>,
+    /// Toggled by `lock_key`, the lamps hold the last color sent while it's set
+    locked: Arc<AtomicBool>,
+    /// Last color sent to the lamps
+    current_color: Arc<Mutex<(u64, u64, u64)>>,
+    /// Counters of the sync, shared with the lamps and the control server
+    metrics: Arc<Metrics>,
+    /// File with the lamps, watched by `run()` to add and remove lamps while syncing
+    ips_path: Option<String>,
+    /// Set when `run()` returns because `profile_key` was pressed
+    profile_switch_requested: bool,
+}
+
+impl ScreenSync {
+    /// Create a new ScreenSync struct
+    ///
+    /// # Arguments
+    /// * `config` - The configuration to use
+    /// * `lamps` - The lamps to sync
+    /// 
+    /// Fails if the lamps can't be reached at all, e.g. IPv6 lamps on a computer without IPv6
+    pub fn new(config: Config, lamps: Vec<Lamp>) -> Result<Self, SyncError> {
+        let mut light_communication = create_light_communication(&config, lamps)?;
+        let metrics = Arc::new(Metrics::default());
+        light_communication.set_metrics(metrics.clone());
+
+        Ok(Self {
+            config,
+            light_communication,
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            locked: Arc::new(AtomicBool::new(false)),
+            current_color: Arc::new(Mutex::new((0, 0, 0))),
+            metrics,
+            ips_path: None,
+            profile_switch_requested: false,
+        })
+    }
+
+    /// Update the lamps while `run()` is syncing when a file with them changes
+    ///
+    /// Lamps added to the file start syncing, and the removed ones are restored and dropped
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file, in the `ips.txt` format
+    pub fn watch_lamps_file(&mut self, path: &str) {
+        self.ips_path = Some(path.to_string());
+    }
+
+    /// Make `run()` restore the lamps and return
+    pub fn stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Get the flag used by `stop()`, to stop the sync from another thread
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop_requested.clone()
+    }
+
+    /// Use another flag for `stop()`, e.g. one shared by the sessions of several profiles
+    pub fn set_stop_flag(&mut self, stop_flag: Arc<AtomicBool>) {
+        self.stop_requested = stop_flag;
+    }
+
+    /// Check if the last `run()` returned because `profile_key` was pressed,
+    /// so the next profile has to be started
+    pub fn profile_switch_requested(&self) -> bool {
+        self.profile_switch_requested
+    }
+
+    /// Restore the lamps and stop syncing them until `resume()` is called
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Start syncing the lamps again after `pause()`
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Get the flag used by `pause()` and `resume()`, to control the sync from another thread
+    pub fn pause_flag(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
+    /// Get the counters of the sync, e.g. to export them somewhere else than `GET /metrics`
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Set the lamps to the current color of the screen once
+    ///
+    /// Unlike `run()` the lamps aren't restored afterwards
+    pub fn run_once(&mut self) -> Result<(), SyncError> {
+        let config = &self.config;
+        let light_communication = &self.light_communication;
+
+        let mut capturer = create_capturer(config.monitor_index).map_err(SyncError::Capture)?;
+        let (frame, frame_size) = capture_warm_frame(capturer.as_mut(), config.warmup_frames).map_err(SyncError::Capture)?;
+        let colors = get_frame_colors(&frame, frame_size, light_communication.lights(), config);
+
+        if colors.is_black {
+            apply_black_screen(light_communication, config)?;
+            info!("Screen is black, applied black_screen_behavior");
+            return Ok(());
+        }
+
+        let selected_color = colors.color;
+        let dimming = colors.dimming;
+        if config.regions.is_empty() {
+            light_communication.set_color_all(selected_color, 0, dimming, true)?;
+        } else {
+            let lamp_colors: HashMap<String, (u64, u64, u64)> = light_communication.lights().iter()
+                .map(|ip| (ip.to_string(), colors.region_colors.get(ip).copied().unwrap_or(selected_color)))
+                .collect();
+            light_communication.set_colors(&lamp_colors, dimming, true)?;
+        }
+        *self.current_color.lock().unwrap() = selected_color;
+
+        info!("Color set to: {:?}", selected_color);
+
+        Ok(())
+    }
+
+    /// Sync the lamps to the screen until `stop()` is called or the quit key is pressed
+    ///
+    /// The lamps are restored to their previous state before returning
+    pub fn run(&mut self) -> Result<(), SyncError> {
+        self.profile_switch_requested = false;
+        let config = &self.config;
+        let light_communication = &mut self.light_communication;
+
+        // The lamps aren't touched in a dry run, so there's nothing to restore
+        if !config.dry_run {
+            // Get initial states, and find the lamps that can't show RGB
+            info!("Getting initial states and lamps models...");
+            let lamps_found = wait_for_lamps(light_communication, config, &self.stop_requested);
+
+            // Show what was found before stopping on the lamps that didn't answer
+            log_lamps_summary(light_communication);
+            lamps_found?;
+            if self.stop_requested.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            // Keep the initial states on disk until they're restored, in case the program crashes
+            if !config.state_file.is_empty() {
+                if Path::new(&config.state_file).exists() {
+                    // The lamps probably still have the colors of that session, its states are the real ones
+                    warn!("{} was left by a session that didn't end cleanly, restoring the lamps to its states", config.state_file);
+                    if let Err(error) = light_communication.load_initial_states(&config.state_file) {
+                        warn!("{}", error);
+                    }
+                }
+                save_initial_states(light_communication, config);
+            }
+
+            // Set the fade speed used while syncing
+            info!("Setting fade speed to {}ms...", config.sync_fade_speed);
+            // The lamps that didn't get it still sync, only with their own fade speed
+            if let Err(error) = light_communication.set_fade_speed_all(config.sync_fade_speed, config.sync_fade_speed) {
+                warn!("Error setting the fade speed: {}", error);
+            }
+        }
+
+        // Start the control server
+        #[cfg(feature = "http")]
+        let control_server = match config.control_port {
+            0 => None,
+            port => {
+                let state = ControlState {
+                    paused: self.paused.clone(),
+                    locked: self.locked.clone(),
+                    color: self.current_color.clone(),
+                    metrics: self.metrics.clone(),
+                };
+                Some(ControlServer::start(port, state).map_err(SyncError::Control)?)
+            }
+        };
+        #[cfg(not(feature = "http"))]
+        if config.control_port != 0 {
+            warn!("control_port is set but the program was built without the 'http' feature, ignoring it");
+        }
+
+        // Watch the lamps file
+        let ips_watcher = self.ips_path.as_deref().map(IpsWatcher::start);
+
+        // Initialize capture
+        info!("Initializing capture...");
+
+        // Frames are captured on their own thread while the colors are sent.
+        // Only the cropped part of the screen is captured if the backend can
+        let crop = (!config.capture_crop.is_empty()).then(|| config.capture_crop.region());
+        let (capture_thread, (mut previous_frame, mut previous_size)) =
+            CaptureThread::start(config.monitor_index, config.warmup_frames, crop).map_err(SyncError::Capture)?;
+        // The frames of a capturer that only captures the crop don't have to be cropped again
+        let cropped_config;
+        let frame_config = if capture_thread.captures_region() {
+            cropped_config = Config { capture_crop: CaptureCrop::default(), ..config.clone() };
+            &cropped_config
+        } else {
+            config
+        };
+
+        // Get the quit key, it only works while this window is focused unless it's global
+        // Without a terminal there's no key to wait for
+        #[cfg(windows)]
+        let quit_key = HotKey::new(&config.quit_key, config.global_quit_key).filter(|_| !config.daemon);
+        // Same for the key that switches to the next profile, it ends the session like the quit key
+        #[cfg(windows)]
+        let mut profile_key = HotKey::new(&config.profile_key, config.global_quit_key)
+            .filter(|_| !config.daemon && !config.profiles.is_empty());
+        // And for the key that locks the current color
+        #[cfg(windows)]
+        let mut lock_key = HotKey::new(&config.lock_key, config.global_quit_key).filter(|_| !config.daemon);
+        let mut number_keys = (!config.daemon).then(NumberKeys::start);
+        let quit_key_name = if config.daemon { "" } else { config.quit_key.as_str() };
+
+        // Show the live view, the terminal is restored when it's dropped
+        let mut tui = if config.tui && !config.json_status && !config.daemon {
+            match Tui::start(quit_hint(quit_key_name)) {
+                Ok(tui) => Some(tui),
+                Err(error) => {
+                    warn!("Error starting the terminal view, logging instead: {}", error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if tui.is_none() {
+            info!("Syncing. {}", quit_hint(quit_key_name));
+        }
+
+        // Show the frame and the color in a window, the sync goes on if it's closed
+        #[cfg(feature = "preview")]
+        let mut preview = if config.preview {
+            match Preview::open(previous_size) {
+                Ok(preview) => Some(preview),
+                Err(error) => {
+                    warn!("{}", error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(not(feature = "preview"))]
+        if config.preview {
+            warn!("preview is set but the program was built without the 'preview' feature, ignoring it");
+        }
+
+        let mut previous_color = (0, 0, 0);
+        let mut previous_dimming = 0;
+        let mut previous_lamp_colors: HashMap<String, (u64, u64, u64)> = HashMap::new();
+        let mut smoother = ColorSmoother::new(config.smoothing_factor);
+        smoother.set_dither(config.dither_amount);
+        if config.dither_amount > 0.0 && config.smoothing_factor == 0.0 && config.lowpass_cutoff_hz == 0.0 && config.blend_frames <= 1 {
+            warn!("dither_amount only works with smoothing_factor, lowpass_cutoff_hz or blend_frames, ignoring it");
+        }
+        let mut blender = FrameBlender::new(config.blend_frames, config.blend_decay);
+        let mut channel_filter = ChannelFilter::new(config.lowpass_cutoff_hz);
+        let mut dimming_limiter = SlewLimiter::new(config.max_dimming_step);
+        let mut debouncer = ColorDebouncer::new(config.debounce_frames);
+        let mut adaptive_threshold = AdaptiveThreshold::new(config.color_variation_threshold);
+        let mut lamp_smoothers: HashMap<String, ColorSmoother> = HashMap::new();
+        let mut lamp_blenders: HashMap<String, FrameBlender> = HashMap::new();
+        let frame_budget = match config.max_fps {
+            0 => Duration::ZERO,
+            max_fps => Duration::from_secs_f64(1.0 / max_fps as f64),
+        };
+        let mut previous_start = Instant::now();
+        // If the black screen behavior is applied to the lamps
+        let mut black_screen = false;
+        // When the screen started being black, and if `black_screen_timeout_behavior` is applied to the lamps
+        let mut black_since: Option<Instant> = None;
+        let mut black_screen_timed_out = false;
+        let black_screen_timeout = Duration::from_secs(config.black_screen_timeout_secs);
+        // If the lamps were restored because of `pause()`
+        let mut was_paused = false;
+        // If the lamps have the idle state because the colors haven't changed for `idle_timeout_secs`
+        let mut idle = false;
+        // When the lamps last got a color from the screen
+        let mut last_change = Instant::now();
+        let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+        // Shortest time between two color changes, see `max_change_hz`
+        let min_change_interval = match config.max_change_hz {
+            0.0 => Duration::ZERO,
+            max_change_hz => Duration::from_secs_f64(1.0 / max_change_hz),
+        };
+        // Only sync while the `sync_when_focused` window is focused
+        let mut focus_watcher = (!config.sync_when_focused.is_empty()).then(|| FocusWatcher::new(&config.sync_when_focused));
+        #[cfg(not(windows))]
+        if focus_watcher.is_some() {
+            warn!("sync_when_focused only works on Windows, ignoring it");
+        }
+        let mut was_unfocused = false;
+        // Scene applied with a number key, the sync stops while it's set
+        let mut active_scene: Option<&Scene> = None;
+        // If the active scene or the idle state of `unfocused_idle` still have to be sent,
+        // they're dropped if they come too soon after the previous color, see `min_send_interval_ms`
+        let mut scene_pending = false;
+        let mut unfocused_idle_pending = false;
+        // If the lamps need the colors again after a pause, a scene or the window being unfocused
+        let mut resync = false;
+        // If lamps were added and haven't got a color yet
+        let mut lamps_added = false;
+        // Colors of the last frame, reused while the screen doesn't change
+        let mut frame_colors: Option<FrameColors> = None;
+        let mut latency_stats = LatencyStats::new();
+        // Error that stops the sync with `on_lamp_error = "exit"`
+        let mut lamp_error: Option<LightError> = None;
+        let latency_stats_interval = Duration::from_secs(config.latency_stats_interval_secs);
+        while !self.stop_requested.load(Ordering::SeqCst) {
+            // Start timer
+            let start = Instant::now();
+            let frame_time = start.duration_since(previous_start);
+            let fps = 1.0 / frame_time.as_secs_f64().max(f64::EPSILON);
+            previous_start = start;
+
+            // Add and remove lamps when the lamps file changes
+            if let Some(lamps) = ips_watcher.as_ref().and_then(IpsWatcher::take_changes) {
+                info!("The lamps file changed, updating the lamps...");
+                lamps_added |= reload_lamps(light_communication, config, lamps);
+                if !config.dry_run && !config.state_file.is_empty() {
+                    save_initial_states(light_communication, config);
+                }
+                // The new lamps may have a region that isn't in the cached colors
+                frame_colors = None;
+            }
+
+            // Give the lamps back while paused, and send the colors again on resume
+            let paused = self.paused.load(Ordering::SeqCst);
+            if paused != was_paused && !config.dry_run {
+                if paused {
+                    info!("Paused, restoring previous lamps state...");
+                    // A lamp that doesn't answer shouldn't stop the sync, it's sent its color again on resume
+                    if let Err(error) = light_communication.restore_initial_states() {
+                        warn!("Error restoring the lamps: {}", error);
+                    }
+                } else {
+                    info!("Resumed");
+                    if let Err(error) = light_communication.set_fade_speed_all(config.sync_fade_speed, config.sync_fade_speed) {
+                        warn!("Error setting the fade speed: {}", error);
+                    }
+                }
+            }
+
+            // Apply a scene with its number key, 0 goes back to syncing
+            let mut scene_ended = false;
+            match number_keys.as_mut().and_then(NumberKeys::pressed).filter(|_| !paused) {
+                Some(0) if active_scene.is_some() => {
+                    info!("Scene ended, syncing again");
+                    active_scene = None;
+                    scene_pending = false;
+                    scene_ended = true;
+                }
+                Some(number) => {
+                    if let Some(scene) = number.checked_sub(1).and_then(|index| config.scenes.get(index)) {
+                        info!("Applying scene {}", scene.name);
+                        active_scene = Some(scene);
+                        scene_pending = !config.dry_run;
+                    }
+                }
+                None => {}
+            }
+            if let Some(scene) = active_scene.filter(|_| scene_pending && !paused) {
+                let result = if scene.group.is_empty() {
+                    light_communication.set_color_all(scene.rgb(), scene.temp, scene.dimming, scene.on)
+                } else {
+                    light_communication.set_color_group_by_name(&scene.group, scene.rgb(), scene.temp, scene.dimming, scene.on)
+                };
+                scene_pending = match result {
+                    Ok(sent) => !sent,
+                    Err(error) => {
+                        warn!("Error applying scene {}: {}", scene.name, error);
+                        false
+                    }
+                };
+            }
+
+            // Hold the lamps while the window to sync isn't focused
+            let unfocused = focus_watcher.as_mut().is_some_and(|watcher| !watcher.is_focused());
+            if unfocused && !was_unfocused {
+                info!("The window to sync isn't focused, holding the lamps");
+                unfocused_idle_pending = config.unfocused_idle;
+            } else if !unfocused && was_unfocused {
+                info!("The window to sync is focused, syncing again");
+                unfocused_idle_pending = false;
+            }
+            if unfocused_idle_pending && !paused && active_scene.is_none() {
+                unfocused_idle_pending = match apply_idle(light_communication, config) {
+                    Ok(sent) => !sent,
+                    Err(error) => {
+                        warn!("Error setting the idle state: {}", error);
+                        false
+                    }
+                };
+            }
+
+            // Hold the last color sent while locked, the screen is still captured
+            #[cfg(windows)]
+            if lock_key.as_mut().is_some_and(HotKey::pressed) {
+                let locked = !self.locked.load(Ordering::SeqCst);
+                self.locked.store(locked, Ordering::SeqCst);
+                if locked {
+                    info!("Color locked, holding {:?}", *self.current_color.lock().unwrap());
+                } else {
+                    info!("Color unlocked, syncing again");
+                    // The time spent locked doesn't count for `idle_timeout_secs`
+                    last_change = start;
+                }
+            }
+            let locked = self.locked.load(Ordering::SeqCst);
+
+            resync |= (was_paused && !paused) || scene_ended || (was_unfocused && !unfocused);
+            was_paused = paused;
+            was_unfocused = unfocused;
+            if let Some(tui) = tui.as_mut() {
+                // A terminal that can't be drawn on isn't worth stopping the sync
+                let _ = tui.draw(fps, paused || active_scene.is_some() || unfocused, locked, light_communication);
+            }
+            if paused || active_scene.is_some() || unfocused {
+                capture_thread.set_active(false);
+                thread::sleep(PAUSE_POLL_INTERVAL);
+                continue;
+            }
+            capture_thread.set_active(true);
+
+            // Take the latest frame or fallback to previous frame
+            let (frame, (frame_width, frame_height)) = match capture_thread.next_frame(FRAME_TIMEOUT) {
+                Some(frame) => {
+                    self.metrics.frame_captured();
+                    frame
+                }
+                None => (previous_frame.clone(), previous_size),
+            };
+
+            // Reuse the colors of the previous frame while the screen doesn't change
+            let frame_unchanged = config.static_frame_stride != 0
+                && frames_match(&frame, &previous_frame, config.static_frame_stride);
+            let colors = match frame_colors.take().filter(|_| frame_unchanged) {
+                Some(colors) => colors,
+                None => get_frame_colors(&frame, (frame_width, frame_height), light_communication.lights(), frame_config),
+            };
+            previous_frame = frame;
+            previous_size = (frame_width, frame_height);
+
+            // Get screen color
+            let filtered_color = channel_filter.update(blender.update(colors.color), frame_time);
+            let selected_color = smoother.update(filtered_color);
+
+            let threshold = if config.adaptive_threshold {
+                adaptive_threshold.update(selected_color, config.variation_mode)
+            } else {
+                config.color_variation_threshold
+            };
+
+            let dimming = dimming_limiter.update(colors.dimming).clamp(config.min_dimming, config.max_dimming);
+            let dimming_changed = dimming.abs_diff(previous_dimming) >= DIMMING_THRESHOLD;
+
+            let frame_is_black = colors.is_black;
+
+            // Stop sending to the lamps while the screen stays black, e.g. a monitor asleep, until it shows something again
+            black_since = if frame_is_black { black_since.or(Some(start)) } else { None };
+            let black_screen_timeout_reached = config.black_screen_timeout_secs != 0
+                && black_since.is_some_and(|black_since| black_since.elapsed() >= black_screen_timeout);
+            if black_screen_timeout_reached && !black_screen_timed_out {
+                // Tried again on the next frames if it's too soon after the previous color
+                black_screen_timed_out = config.dry_run || match apply_black_screen_timeout(light_communication, config) {
+                    Ok(sent) => sent,
+                    Err(error) => {
+                        warn!("Error applying black_screen_timeout_behavior: {}", error);
+                        true
+                    }
+                };
+                if black_screen_timed_out {
+                    info!("The screen has been black for {} seconds, applied black_screen_timeout_behavior", config.black_screen_timeout_secs);
+                }
+            } else if !frame_is_black && black_screen_timed_out {
+                info!("The screen isn't black anymore, syncing again");
+                // Restoring the lamps gave them back their fade speed
+                if config.black_screen_timeout_behavior == BlackScreenTimeoutBehavior::Restore && !config.dry_run {
+                    if let Err(error) = light_communication.set_fade_speed_all(config.sync_fade_speed, config.sync_fade_speed) {
+                        warn!("Error setting the fade speed: {}", error);
+                    }
+                }
+                black_screen_timed_out = false;
+            }
+
+            // Give the lamps the idle state while the colors don't change, the next change sends them again
+            let idle_timeout_reached = config.idle_timeout_secs != 0 && !locked && last_change.elapsed() >= idle_timeout;
+            if idle_timeout_reached && !frame_is_black && (!idle || lamps_added) && matches!(apply_idle(light_communication, config), Ok(true)) {
+                if !idle {
+                    info!("No color changes for {} seconds, setting the idle state", config.idle_timeout_secs);
+                }
+                idle = true;
+                lamps_added = false;
+            }
+
+            // When the screen stops being black, send the colors again even if they didn't change much
+            let force_send = (black_screen || resync || lamps_added) && !frame_is_black;
+            // Hold the previous color until `max_change_hz` allows a new one, even if the screen flashes
+            let change_allowed = !locked && !black_screen_timed_out && last_change.elapsed() >= min_change_interval;
+
+            if frame_is_black {
+                if change_allowed && (!black_screen || lamps_added) && sent(apply_black_screen(light_communication, config), config, &mut lamp_error) {
+                    report_color(config, tui.as_mut(), (0, 0, 0), start, fps, light_communication.lights().len());
+
+                    black_screen = true;
+                    resync = false;
+                    lamps_added = false;
+                    idle = false;
+                    last_change = start;
+                }
+            }
+            else if !config.regions.is_empty() {
+                // Get the color of each lamp region, or the whole screen if it has none
+                let lamp_colors: HashMap<String, (u64, u64, u64)> = light_communication.lights().iter()
+                    .map(|ip| {
+                        let color = match colors.region_colors.get(ip) {
+                            Some(region_color) => {
+                                let blended = lamp_blenders.entry(ip.to_string())
+                                    .or_insert_with(|| FrameBlender::new(config.blend_frames, config.blend_decay))
+                                    .update(*region_color);
+                                lamp_smoothers.entry(ip.to_string())
+                                    .or_insert_with(|| {
+                                        let mut lamp_smoother = ColorSmoother::new(config.smoothing_factor);
+                                        lamp_smoother.set_dither(config.dither_amount);
+                                        lamp_smoother
+                                    })
+                                    .update(blended)
+                            }
+                            None => selected_color,
+                        };
+                        (ip.to_string(), color)
+                    })
+                    .collect();
+
+                // Each lamp is compared against the last color it got, with its own threshold check
+                let changed_lamps: HashMap<String, (u64, u64, u64)> = lamp_colors.iter()
+                    .filter(|(ip, color)| {
+                        let previous = previous_lamp_colors.get(*ip).copied().unwrap_or((0, 0, 0));
+                        color_variation(**color, previous, config.variation_mode) > threshold
+                    })
+                    .map(|(ip, color)| (ip.to_string(), *color))
+                    .collect();
+                let changed = force_send || debouncer.update(
+                    selected_color,
+                    dimming_changed || !changed_lamps.is_empty(),
+                    config.variation_mode,
+                    threshold,
+                );
+                // Only the lamps whose region changed enough get a message, unless every lamp needs its color again
+                let lamp_colors = if force_send || dimming_changed || idle { lamp_colors } else { changed_lamps };
+                if change_allowed && changed {
+                    let result = light_communication.set_color_if_changed(&lamp_colors, dimming, true);
+
+                    // Lamps skipped because of `min_send_interval_ms` or that failed keep their previous color, so they get it later.
+                    // Lamps skipped by `on_lamp_error` are checked again when their color changes
+                    previous_lamp_colors.extend(lamp_colors.into_iter().filter(|(ip, color)| {
+                        let status = light_communication.sync_status(ip);
+                        config.dry_run || status == SyncStatus::Sent(*color) || matches!(status, SyncStatus::Skipped | SyncStatus::Off)
+                    }));
+                    // The rest waits until every lamp got its color
+                    if sent(result, config, &mut lamp_error) {
+                        report_color(config, tui.as_mut(), selected_color, start, fps, light_communication.lights().len());
+                        *self.current_color.lock().unwrap() = selected_color;
+
+                        previous_dimming = dimming;
+                        black_screen = false;
+                        resync = false;
+                        lamps_added = false;
+                        idle = false;
+                        last_change = start;
+                    }
+                }
+            }
+            // Send color to lamps
+            else if change_allowed && (force_send || debouncer.update(
+                selected_color,
+                dimming_changed || color_variation(selected_color, previous_color, config.variation_mode) > threshold,
+                config.variation_mode,
+                threshold,
+            )) {
+                // On failure, or if it was too soon for some lamps, keep the previous color so it's sent again on the next iteration
+                if sent(light_communication.set_color_all(selected_color, 0, dimming, true), config, &mut lamp_error) {
+                    report_color(config, tui.as_mut(), selected_color, start, fps, light_communication.lights().len());
+                    *self.current_color.lock().unwrap() = selected_color;
+
+                    previous_color = selected_color;
+                    previous_dimming = dimming;
+                    black_screen = false;
+                    resync = false;
+                    lamps_added = false;
+                    idle = false;
+                    last_change = start;
+                }
+            }
+            frame_colors = Some(colors);
+            if lamp_error.is_some() {
+                break;
+            }
+
+            #[cfg(feature = "preview")]
+            if preview.as_mut().is_some_and(|preview| !preview.update(&previous_frame, previous_size, selected_color, frame_config)) {
+                info!("Preview window closed");
+                preview = None;
+            }
+
+            // On other platforms the sync is stopped with Ctrl+C
+            #[cfg(windows)]
+            if quit_key.as_ref().is_some_and(HotKey::is_pressed) {
+                break;
+            }
+            #[cfg(windows)]
+            if profile_key.as_mut().is_some_and(HotKey::pressed) {
+                self.profile_switch_requested = true;
+                break;
+            }
+
+            self.metrics.set_loop_latency(start.elapsed());
+            if config.latency_stats_interval_secs != 0 {
+                latency_stats.record(start.elapsed(), latency_stats_interval);
+            }
+
+            // Wait for the rest of the frame budget
+            if let Some(remaining) = frame_budget.checked_sub(start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+
+        // Give the terminal back so the messages below are visible
+        drop(tui);
+        drop(capture_thread);
+
+        #[cfg(feature = "http")]
+        if let Some(control_server) = control_server {
+            control_server.shutdown();
+        }
+        if let Some(ips_watcher) = ips_watcher {
+            ips_watcher.shutdown();
+        }
+
+        // Restore previous lamps state, unless it was already done by `pause()`
+        if !config.dry_run && !was_paused {
+            info!("Restoring previous lamps state...");
+            light_communication.restore_initial_states()?;
+        }
+
+        // The lamps are back to their initial states, there's nothing left to restore
+        if !config.dry_run && !config.state_file.is_empty() {
+            if let Err(error) = fs::remove_file(&config.state_file) {
+                warn!("Error removing {}: {}", config.state_file, error);
+            }
+        }
+
+        match lamp_error {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Create the LightCommunication for some lamps, using the ports, timeouts and retries of the config
+/// 
+/// # Arguments
+/// * `config` - The configuration to use
+/// * `lamps` - The lamps to communicate with
+/// 
+/// Fails like `LightCommunication::new()`
+pub fn create_light_communication(config: &Config, lamps: Vec<Lamp>) -> Result<LightCommunication, LightError> {
+    let mut light_communication = LightCommunication::new(
+        lamps.iter().map(|lamp| lamp.ip.to_string()).collect(),
+        config.lamps_port,
+        Duration::from_millis(config.read_timeout_ms),
+    )?;
+    light_communication.set_retry_policy(RetryPolicy {
+        retries: config.retries,
+        base_delay: Duration::from_millis(config.base_delay_ms),
+    });
+    for lamp in lamps.iter() {
+        if let Some(port) = lamp.port {
+            if let Err(error) = light_communication.set_port(&lamp.ip.to_string(), port) {
+                warn!("Error setting the port of {}: {}", lamp.ip, error);
+            }
+        }
+    }
+    light_communication.set_names(lamps.into_iter()
+        .filter_map(|lamp| Some((lamp.ip.to_string(), lamp.name?)))
+        .collect());
+    light_communication.set_groups(config.groups.clone());
+    light_communication.set_dry_run(config.dry_run);
+    light_communication.set_min_send_interval(Duration::from_millis(config.min_send_interval_ms));
+    light_communication.set_skip_failed((config.on_lamp_error == OnLampError::Skip)
+        .then(|| Duration::from_secs(config.lamp_recheck_secs)));
+    light_communication.set_include_off_lamps(config.include_off_lamps);
+    if let Err(error) = light_communication.set_broadcast(config.broadcast_colors) {
+        warn!("Error looking for other lamps, the colors will be sent to each lamp: {}", error);
+    }
+    light_communication.set_temp_mode(config.temp_mode, config.temp_lamps.iter().cloned().collect());
+    light_communication.set_calibrations(config.lamp_calibrations());
+
+    Ok(light_communication)
+}
+
+/// Save the initial states to `state_file`, logging a warning on failure
+fn save_initial_states(light_communication: &LightCommunication, config: &Config) {
+    if let Err(error) = light_communication.save_initial_states(&config.state_file) {
+        warn!("{}, the lamps can't be restored with --restore if the program crashes", error);
+    }
+}
+
+/// Get the initial state and model of every lamp, retrying the ones that don't answer
+///
+/// They're retried every `STARTUP_RETRY_INTERVAL` until `startup_timeout_secs` passes,
+/// e.g. while the network comes up. Lamps that never answer are removed if `skip_unreachable` is set
+/// and some lamp answered, otherwise the error of the first one is returned. Stops early if `stop_requested` is set
+///
+/// # Arguments
+/// * `light_communication` - The lamps
+/// * `config` - The configuration to use
+/// * `stop_requested` - The flag set by `stop()`
+fn wait_for_lamps(light_communication: &mut LightCommunication, config: &Config, stop_requested: &AtomicBool) -> Result<(), LightError> {
+    let deadline = Instant::now() + Duration::from_secs(config.startup_timeout_secs);
+    let lamp_count = light_communication.lights().len();
+    let mut pending = light_communication.lights().to_vec();
+    let mut errors = Vec::new();
+
+    loop {
+        errors.clear();
+        pending.retain(|ip| {
+            let result = light_communication.get_initial_state(ip)
+                .and_then(|_| light_communication.get_system_config(ip));
+            match result {
+                Ok(()) => false,
+                Err(error) => {
+                    errors.push(error);
+                    true
+                }
+            }
+        });
+
+        if pending.is_empty() || Instant::now() >= deadline || stop_requested.load(Ordering::SeqCst) {
+            break;
+        }
+        info!("{} of {} lamps aren't reachable yet, retrying...", pending.len(), lamp_count);
+        thread::sleep(STARTUP_RETRY_INTERVAL);
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    // With no lamp left there's nothing to sync
+    if !config.skip_unreachable || pending.len() == lamp_count {
+        return Err(errors.remove(0));
+    }
+
+    for (ip, error) in pending.iter().zip(errors) {
+        // It has no initial state, so there's nothing to restore.
+        // With `on_lamp_error = "skip"` it's checked while syncing and joins when it answers
+        if config.on_lamp_error == OnLampError::Skip {
+            warn!("Skipping {} until it answers: {}", light_communication.label(ip), error);
+            light_communication.skip_lamp(ip);
+        } else {
+            warn!("Skipping {}: {}", light_communication.label(ip), error);
+            let _ = light_communication.remove_lamp(ip);
+        }
+    }
+    Ok(())
+}
+
+/// Log each lamp with its state and model, as found by `get_initial_states()` and `get_system_configs()`
+fn log_lamps_summary(light_communication: &LightCommunication) {
+    let known = light_communication.lights().iter()
+        .filter(|ip| light_communication.initial_state(ip).is_some())
+        .count();
+    info!("Found the state of {} of {} lamps:", known, light_communication.lights().len());
+
+    for ip in light_communication.lights() {
+        let state = match light_communication.initial_state(ip) {
+            Some(state) => state.to_string(),
+            None => "state unknown".to_string(),
+        };
+        let model = match light_communication.system_config(ip) {
+            Some(system_config) => format!(
+                "{} ({})",
+                system_config.module_name.as_deref().unwrap_or("unknown model"),
+                if system_config.supports_rgb() { "RGB" } else { "white only" },
+            ),
+            None => "unknown model".to_string(),
+        };
+        info!("  {}: {} - {}", light_communication.label(ip), state, model);
+    }
+}
+
+/// Update the lamps to the ones in the lamps file
+///
+/// New lamps are set up like in `run()` before they start syncing,
+/// and removed ones are restored before they're dropped.
+/// Lamps that fail are skipped with a warning, so the other lamps keep syncing
+///
+/// Returns if any lamp was added
+fn reload_lamps(light_communication: &mut LightCommunication, config: &Config, lamps: Vec<Lamp>) -> bool {
+    let ips: Vec<String> = lamps.iter().map(|lamp| lamp.ip.to_string()).collect();
+
+    let removed: Vec<String> = light_communication.lights().iter()
+        .filter(|ip| !ips.contains(ip))
+        .cloned()
+        .collect();
+    for ip in removed {
+        info!("Removing {}...", light_communication.label(&ip));
+        if let Err(error) = light_communication.remove_lamp(&ip) {
+            warn!("Error restoring the removed lamp {}: {}", ip, error);
+        }
+    }
+
+    let mut added = false;
+    for lamp in lamps {
+        let ip = lamp.ip.to_string();
+        let is_new = !light_communication.lights().contains(&ip);
+
+        let result = light_communication.add_lamp(&ip)
+            .and_then(|_| {
+                light_communication.set_name(&ip, lamp.name);
+                light_communication.set_port(&ip, lamp.port.unwrap_or(config.lamps_port))
+            })
+            .and_then(|_| match is_new && !config.dry_run {
+                true => setup_lamp(light_communication, config, &ip),
+                false => Ok(()),
+            });
+
+        match result {
+            Ok(()) if is_new => {
+                info!("Added {}", light_communication.label(&ip));
+                added = true;
+            }
+            Ok(()) => {}
+            Err(error) if is_new => {
+                warn!("Error adding {}, skipping it: {}", ip, error);
+                let _ = light_communication.remove_lamp(&ip);
+            }
+            Err(error) => warn!("Error updating {}: {}", ip, error),
+        }
+    }
+
+    added
+}
+
+/// Get the state and model of a new lamp and set its fade speed, like `run()` does on start
+fn setup_lamp(light_communication: &mut LightCommunication, config: &Config, ip: &str) -> Result<(), LightError> {
+    light_communication.get_initial_state(ip)?;
+    light_communication.get_system_config(ip)?;
+    light_communication.set_fade_speed(ip, config.sync_fade_speed, config.sync_fade_speed)
+}
+
+/// Check the result of sending colors while syncing, following `on_lamp_error`
+/// 
+/// Returns if every lamp got the colors. The ones that came too soon after the previous colors
+/// are sent again on the next frame, see `min_send_interval_ms`. With `on_lamp_error = "exit"` the error
+/// is stored in `lamp_error` to stop the sync, otherwise the colors are sent again on the next frame
+fn sent(result: Result<bool, LightError>, config: &Config, lamp_error: &mut Option<LightError>) -> bool {
+    match result {
+        Ok(sent) => sent,
+        Err(error) if config.on_lamp_error == OnLampError::Exit => {
+            *lamp_error = Some(error);
+            false
+        }
+        Err(error) => {
+            debug!("Error sending the colors, sending them again on the next frame: {}", error);
+            false
+        }
+    }
+}
+
+/// Set the lamps for a mostly black screen
+/// 
+/// Returns if every lamp got it, like `LightCommunication::set_color_all()`
+fn apply_black_screen(light_communication: &LightCommunication, config: &Config) -> Result<bool, LightError> {
+    match config.black_screen_behavior {
+        BlackScreenBehavior::Min => {
+            let [r, g, b] = config.black_screen_color;
+            light_communication.set_color_all((r, g, b), 0, config.black_screen_dimming, true)
+        }
+        BlackScreenBehavior::Dim => light_communication.set_color_all((0, 0, 0), DIM_TEMP, DIM_DIMMING, true),
+        BlackScreenBehavior::Off => light_communication.set_state_all(false),
+    }
+}
+
+/// Apply the `black_screen_timeout_behavior`, after the screen has been black for `black_screen_timeout_secs`
+/// 
+/// Returns if every lamp got it, like `LightCommunication::set_color_all()`
+fn apply_black_screen_timeout(light_communication: &LightCommunication, config: &Config) -> Result<bool, LightError> {
+    match config.black_screen_timeout_behavior {
+        BlackScreenTimeoutBehavior::Hold => Ok(true),
+        BlackScreenTimeoutBehavior::Off => light_communication.set_state_all(false),
+        BlackScreenTimeoutBehavior::Restore => light_communication.restore_initial_states().map(|()| true),
+    }
+}
+
+/// Set the lamps to the idle state, after `idle_timeout_secs` without color changes
+/// 
+/// Returns if every lamp got it, like `LightCommunication::set_color_all()`
+fn apply_idle(light_communication: &LightCommunication, config: &Config) -> Result<bool, LightError> {
+    if config.idle_dimming == 0 {
+        return light_communication.set_state_all(false);
+    }
+    let [r, g, b] = config.idle_color;
+    light_communication.set_color_all((r, g, b), config.idle_temp, config.idle_dimming, true)
+}
+
+/// Get the colors of a captured frame, for the whole screen and for each lamp with a region
+/// 
+/// # Arguments
+/// * `frame` - The pixels of the frame, before cutting the margins
+/// * `size` - The width and height of the frame
+/// * `lights` - The IPs of the lamps
+/// * `config` - The configuration to use
+fn get_frame_colors(frame: &[BGRA8], (width, height): (usize, usize), lights: &[String], config: &Config) -> FrameColors {
+    // Cut the margins, skip rows and downscale, everything below only sees the sampled frame
+    let mut sampled;
+    let (frame, (width, height)) = if config.capture_crop.is_empty() && config.row_skipping == 1 && config.excluded_regions.is_empty() {
+        (frame, (width, height))
+    } else {
+        sampled = sample_region(frame, width, height, &config.capture_crop.region(), config.row_skipping);
+        exclude_regions(&mut sampled.0, sampled.1.0, sampled.1.1, &config.excluded_regions);
+        (&sampled.0[..], sampled.1)
+    };
+
+    let downscaled;
+    let (frame, (width, height)) = if config.downscale_size == [0, 0] {
+        (frame, (width, height))
+    } else {
+        downscaled = downscale(frame, width, height, config.downscale_size[0], config.downscale_size[1]);
+        (&downscaled.0[..], downscaled.1)
+    };
+
+    let region_colors = lights.iter()
+        .filter_map(|ip| {
+            let region = config.regions.get(ip)?;
+            let (region_pixels, region_size) = crop_region(frame, width, height, region);
+            Some((ip.to_string(), extract_color(&region_pixels, region_size, config)))
+        })
+        .collect();
+
+    FrameColors {
+        color: extract_color(frame, (width, height), config),
+        dimming: get_dimming(frame, config),
+        is_black: is_mostly_black(frame, config.black_pixel_threshold),
+        region_colors,
+    }
+}
+
+/// Get the color of the pixels and apply the configured adjustments
+fn extract_color(pixels: &[BGRA8], (width, height): (usize, usize), config: &Config) -> (u64, u64, u64) {
+    let color = get_color(pixels, width, height, config);
+    let color = boost_color(color, config.saturation_boost, config.brightness_boost);
+    let color = apply_tone_curve(color, config.tone_contrast, config.tone_lift);
+
+    let hue_shift = if config.complementary { config.hue_shift + 180.0 } else { config.hue_shift };
+    shift_hue(color, hue_shift)
+}
+
+/// Get the dimming to send to the lamps, between `min_dimming` and `max_dimming`
+/// 
+/// With `dimming_from_luminance` the average luminance of the frame is mapped onto that range
+/// 
+/// # Arguments
+/// * `pixels` - The pixels of the frame
+/// * `config` - The configuration to use
+fn get_dimming(pixels: &[BGRA8], config: &Config) -> u64 {
+    if !config.dimming_from_luminance {
+        return config.max_dimming;
+    }
+
+    let luminance = get_average_luminance(pixels, config.pixel_skipping);
+
+    luminance_to_dimming(luminance, config.min_dimming, config.max_dimming)
+}
+
+/// Show the color sent to the lamps
+/// 
+/// As a `StatusLine` on stdout if `json_status` is set, in the live view if there's one
+/// or in the log otherwise
+fn report_color(config: &Config, tui: Option<&mut Tui>, rgb: (u64, u64, u64), time_start: Instant, fps: f64, lamp_count: usize) {
+    if config.json_status {
+        let status = StatusLine {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64),
+            rgb: [rgb.0, rgb.1, rgb.2],
+            latency_ms: time_start.elapsed().as_millis() as u64,
+            lamp_count,
+        };
+        println!("{}", serde_json::to_string(&status).unwrap());
+        return;
+    }
+
+    match tui {
+        Some(tui) => tui.color_sent(rgb, time_start.elapsed()),
+        None => debug!("Color set to: {:?} - {}ms - {:.0} FPS", rgb, time_start.elapsed().as_millis(), fps),
+    }
+}
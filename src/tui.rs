@@ -0,0 +1,125 @@
+//! Live view of the sync in the terminal, used with `tui`
+//!
+//! Shows a swatch of the color sent to the lamps, the latency and FPS,
+//! and one line per lamp with the last color it got.
+
+use crossterm::style::{self, Color};
+use crossterm::{cursor, execute, queue, terminal};
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::light_communication::{LightCommunication, SyncStatus};
+
+/// How often the view is redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// Width of the color swatches, in characters
+const SWATCH_WIDTH: usize = 8;
+
+/// The live view, the terminal is restored when it's dropped
+pub struct Tui {
+    /// Last color sent to the lamps
+    color: (u64, u64, u64),
+    /// Time from the capture to the last color being sent
+    latency: Duration,
+    /// When the view was last drawn
+    last_draw: Option<Instant>,
+    /// How to stop the sync, shown at the bottom
+    quit_hint: String,
+}
+
+impl Tui {
+    /// Switch the terminal to the live view
+    ///
+    /// # Arguments
+    /// * `quit_hint` - How to stop the sync, shown at the bottom
+    pub fn start(quit_hint: String) -> io::Result<Self> {
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+
+        Ok(Self { color: (0, 0, 0), latency: Duration::ZERO, last_draw: None, quit_hint })
+    }
+
+    /// Set the color sent to the lamps
+    ///
+    /// # Arguments
+    /// * `rgb` - The color sent
+    /// * `latency` - Time from the capture to the color being sent
+    pub fn color_sent(&mut self, rgb: (u64, u64, u64), latency: Duration) {
+        self.color = rgb;
+        self.latency = latency;
+    }
+
+    /// Redraw the view, at most once every `REFRESH_INTERVAL`
+    ///
+    /// # Arguments
+    /// * `fps` - The current frames per second
+    /// * `paused` - If the sync is paused
+    /// * `locked` - If the lamps hold their color because of `lock_key`
+    /// * `light_communication` - The lamps to show
+    pub fn draw(&mut self, fps: f64, paused: bool, locked: bool, light_communication: &LightCommunication) -> io::Result<()> {
+        if self.last_draw.is_some_and(|last_draw| last_draw.elapsed() < REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        self.last_draw = Some(Instant::now());
+
+        let mut stdout = io::stdout();
+        let status = if paused {
+            "Paused".to_string()
+        } else if locked {
+            format!("Locked on {:?} - {:.0} FPS", self.color, fps)
+        } else {
+            format!("Color {:?} - {}ms - {:.0} FPS", self.color, self.latency.as_millis(), fps)
+        };
+        // The swatch is two lines tall so it's easier to see
+        draw_line(&mut stdout, 0, Some(self.color), &status)?;
+        draw_line(&mut stdout, 1, Some(self.color), "")?;
+        draw_line(&mut stdout, 3, None, "Lamps:")?;
+
+        let mut row = 4;
+        for ip in light_communication.lights() {
+            let (swatch, status) = match light_communication.sync_status(ip) {
+                SyncStatus::Sent(rgb) => (Some(rgb), format!("{:?}", rgb)),
+                SyncStatus::Waiting => (None, "waiting".to_string()),
+                SyncStatus::Failed => (None, "not reachable".to_string()),
+                SyncStatus::Skipped => (None, "skipped until it answers".to_string()),
+                SyncStatus::Off => (None, "off, left untouched".to_string()),
+            };
+            draw_line(&mut stdout, row, swatch, &format!("{:<40} {}", light_communication.label(ip), status))?;
+            row += 1;
+        }
+
+        draw_line(&mut stdout, row + 1, None, &self.quit_hint)?;
+        // Clear the lines of removed lamps
+        queue!(stdout, cursor::MoveTo(0, row + 2), terminal::Clear(terminal::ClearType::FromCursorDown))?;
+
+        stdout.flush()
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+    }
+}
+
+/// Replace a line of the terminal with a color swatch and some text
+///
+/// # Arguments
+/// * `stdout` - Where to draw
+/// * `row` - The line to replace
+/// * `swatch` - The color of the swatch, blank if `None`
+/// * `text` - The text after the swatch
+fn draw_line(stdout: &mut io::Stdout, row: u16, swatch: Option<(u64, u64, u64)>, text: &str) -> io::Result<()> {
+    queue!(stdout, cursor::MoveTo(0, row), terminal::Clear(terminal::ClearType::CurrentLine))?;
+
+    let blank = " ".repeat(SWATCH_WIDTH);
+    match swatch {
+        Some((r, g, b)) => {
+            let color = Color::Rgb { r: r.min(255) as u8, g: g.min(255) as u8, b: b.min(255) as u8 };
+            queue!(stdout, style::SetBackgroundColor(color), style::Print(&blank), style::ResetColor)?;
+        }
+        None => queue!(stdout, style::Print(&blank))?,
+    }
+
+    queue!(stdout, style::Print(" "), style::Print(text))
+}
@@ -0,0 +1,167 @@
+//! Fake WiZ lamps for the integration tests
+//!
+//! Each `MockLamp` answers on its own thread like a lamp does, keeps the state that
+//! `getPilot` and `getUserConfig` return and records every message it receives.
+
+// Each test file only uses some of the helpers
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use wiz_screen_sync::LightCommunication;
+
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the fake lamps to answer
+pub const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for a message that isn't answered to reach a fake lamp
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A fake lamp answering on a free port
+pub struct MockLamp {
+    pub ip: String,
+    pub port: u16,
+    /// State returned by getPilot, updated by setPilot
+    pub pilot: Arc<Mutex<Value>>,
+    /// State returned by getUserConfig, updated by setUserConfig
+    pub user_config: Arc<Mutex<Value>>,
+    /// Model returned by getSystemConfig, a color lamp unless it's replaced
+    pub system_config: Arc<Mutex<Value>>,
+    /// Every message received, in order
+    messages: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockLamp {
+    /// Start a fake lamp on a free port of `ip`, e.g. `127.0.0.2`
+    ///
+    /// # Arguments
+    /// * `ip` - The loopback IP to listen on, each lamp of a test needs its own
+    /// * `pilot` - The state returned by getPilot
+    /// * `user_config` - The state returned by getUserConfig
+    pub fn start(ip: &str, pilot: Value, user_config: Value) -> Self {
+        let socket = UdpSocket::bind((ip, 0)).expect("Error binding a fake lamp socket");
+
+        Self::answer_on(socket, ip, pilot, user_config)
+    }
+
+    /// Start a fake lamp on a free port of every address, so it also gets the broadcasts to that port.
+    /// It's reached at `127.0.0.1`, only one of them can run on a port
+    ///
+    /// # Arguments
+    /// * `pilot` - The state returned by getPilot
+    /// * `user_config` - The state returned by getUserConfig
+    pub fn start_for_broadcasts(pilot: Value, user_config: Value) -> Self {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).expect("Error binding a fake lamp socket");
+
+        Self::answer_on(socket, "127.0.0.1", pilot, user_config)
+    }
+
+    /// Answer the messages received on `socket` on a new thread
+    fn answer_on(socket: UdpSocket, ip: &str, pilot: Value, user_config: Value) -> Self {
+        let port = socket.local_addr().unwrap().port();
+        let lamp = Self {
+            ip: ip.to_string(),
+            port,
+            pilot: Arc::new(Mutex::new(pilot)),
+            user_config: Arc::new(Mutex::new(user_config)),
+            system_config: Arc::new(Mutex::new(json!({ "mac": "a8bb50000000", "moduleName": "ESP01_SHRGB1C_31" }))),
+            messages: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let pilot = lamp.pilot.clone();
+        let user_config = lamp.user_config.clone();
+        let system_config = lamp.system_config.clone();
+        let messages = lamp.messages.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok((amt, src)) = socket.recv_from(&mut buffer) {
+                let Ok(message) = serde_json::from_slice::<Value>(&buffer[..amt]) else {
+                    continue;
+                };
+                let response = answer(&message, &mut pilot.lock().unwrap(), &mut user_config.lock().unwrap(), &system_config.lock().unwrap());
+                messages.lock().unwrap().push(message);
+                let _ = socket.send_to(response.to_string().as_bytes(), src);
+            }
+        });
+
+        lamp
+    }
+
+    /// Get the messages received so far
+    pub fn messages(&self) -> Vec<Value> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// Get the messages received with a method, e.g. `setPilot`
+    pub fn messages_with_method(&self, method: &str) -> Vec<Value> {
+        self.messages().into_iter().filter(|message| message["method"] == method).collect()
+    }
+
+    /// Wait until `count` messages with a method were received, for the messages that aren't answered
+    ///
+    /// Returns them, or panics if they don't arrive in time
+    pub fn wait_for(&self, method: &str, count: usize) -> Vec<Value> {
+        let start = Instant::now();
+        loop {
+            let messages = self.messages_with_method(method);
+            if messages.len() >= count {
+                return messages;
+            }
+            assert!(start.elapsed() < MESSAGE_TIMEOUT, "{} got {} {} messages, expected {}", self.ip, messages.len(), method, count);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Create a LightCommunication talking to fake lamps
+pub fn light_communication(lamps: &[&MockLamp]) -> LightCommunication {
+    let mut light_communication = LightCommunication::new(lamps.iter().map(|lamp| lamp.ip.clone()).collect(), 0, READ_TIMEOUT)
+        .expect("Error creating the sockets");
+    for lamp in lamps {
+        light_communication.set_port(&lamp.ip, lamp.port).expect("Error setting the port of a fake lamp");
+    }
+
+    light_communication
+}
+
+/// Apply a message to the state of a fake lamp and get its response
+fn answer(message: &Value, pilot: &mut Value, user_config: &mut Value, system_config: &Value) -> Value {
+    let method = message["method"].as_str().unwrap_or("");
+    let params = &message["params"];
+
+    match method {
+        "getPilot" => json!({ "method": method, "env": "pro", "result": pilot }),
+        "getUserConfig" => json!({ "method": method, "env": "pro", "result": user_config }),
+        "getSystemConfig" => json!({ "method": method, "env": "pro", "result": system_config }),
+        "setPilot" => {
+            // Like the lamps, a color, a temperature and a scene replace each other
+            let state = pilot.as_object_mut().unwrap();
+            if params.get("sceneId").is_some() {
+                for key in ["r", "g", "b", "temp"] {
+                    state.remove(key);
+                }
+            } else if params.get("temp").is_some() {
+                for key in ["r", "g", "b"] {
+                    state.remove(key);
+                }
+                state.insert("sceneId".to_string(), json!(0));
+            } else if params.get("r").is_some() {
+                state.remove("temp");
+                state.insert("sceneId".to_string(), json!(0));
+            }
+            for (key, value) in params.as_object().into_iter().flatten() {
+                state.insert(key.clone(), value.clone());
+            }
+            json!({ "method": method, "env": "pro", "result": { "success": true } })
+        }
+        "setUserConfig" => {
+            for (key, value) in params.as_object().into_iter().flatten() {
+                user_config[key] = value.clone();
+            }
+            json!({ "method": method, "env": "pro", "result": { "success": true } })
+        }
+        _ => json!({ "method": method, "env": "pro", "error": { "code": -32601, "message": "Method not found" } }),
+    }
+}
@@ -0,0 +1,188 @@
+//! Messages exchanged with the lamps, checked against fake lamps
+
+mod common;
+
+use common::{light_communication, MockLamp, READ_TIMEOUT};
+use serde_json::json;
+use wiz_screen_sync::color::rgb_to_cct;
+use wiz_screen_sync::LightCommunication;
+
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn initial_state_of_a_color_lamp() {
+    let lamp = MockLamp::start(
+        "127.0.0.1",
+        json!({ "mac": "a8bb50000001", "rssi": -60, "state": true, "sceneId": 0, "r": 255, "g": 120, "b": 0, "dimming": 80 }),
+        json!({ "fadeIn": 300, "fadeOut": 800 }),
+    );
+    let mut light_communication = light_communication(&[&lamp]);
+
+    light_communication.get_initial_states().unwrap();
+
+    let state = light_communication.initial_state(&lamp.ip).unwrap();
+    assert!(state.state);
+    assert_eq!(state.rgb(), Some((255, 120, 0)));
+    assert_eq!(state.temp, None);
+    assert_eq!(state.scene(), None);
+    assert_eq!(state.dimming, Some(80));
+    assert_eq!((state.fade_in, state.fade_out), (Some(300), Some(800)));
+}
+
+#[test]
+fn initial_state_of_a_white_lamp_that_is_off() {
+    let lamp = MockLamp::start(
+        "127.0.0.1",
+        json!({ "mac": "a8bb50000002", "rssi": -55, "state": false, "sceneId": 0, "temp": 2700, "dimming": 40 }),
+        json!({ "fadeIn": 1000, "fadeOut": 1000 }),
+    );
+    let mut light_communication = light_communication(&[&lamp]);
+
+    light_communication.get_initial_states().unwrap();
+
+    let state = light_communication.initial_state(&lamp.ip).unwrap();
+    assert!(!state.state);
+    assert_eq!(state.rgb(), None);
+    assert_eq!(state.temp, Some(2700));
+    assert_eq!(state.dimming, Some(40));
+}
+
+#[test]
+fn initial_state_without_user_config() {
+    // Some firmware versions don't answer getUserConfig with the fade times
+    let lamp = MockLamp::start(
+        "127.0.0.1",
+        json!({ "mac": "a8bb50000003", "rssi": -70, "state": true, "sceneId": 12, "speed": 150, "dimming": 70 }),
+        json!({}),
+    );
+    let mut light_communication = light_communication(&[&lamp]);
+
+    light_communication.get_initial_states().unwrap();
+
+    let state = light_communication.initial_state(&lamp.ip).unwrap();
+    assert_eq!(state.scene(), Some(12));
+    assert_eq!(state.speed, Some(150));
+    assert_eq!((state.fade_in, state.fade_out), (None, None));
+}
+
+#[test]
+fn set_color_sends_the_color() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "r": 0, "g": 0, "b": 0, "dimming": 100 }), json!({}));
+    let light_communication = light_communication(&[&lamp]);
+
+    light_communication.set_color_all((255, 80, 0), 0, 60, true).unwrap();
+
+    let messages = lamp.wait_for("setPilot", 1);
+    assert_eq!(messages[0], json!({
+        "method": "setPilot",
+        "params": { "r": 255, "g": 80, "b": 0, "dimming": 60, "state": true },
+    }));
+}
+
+#[test]
+fn set_color_clamps_the_color_and_the_dimming() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "r": 0, "g": 0, "b": 0, "dimming": 100 }), json!({}));
+    let light_communication = light_communication(&[&lamp]);
+
+    light_communication.set_color_all((300, 0, 0), 0, 5, true).unwrap();
+
+    let messages = lamp.wait_for("setPilot", 1);
+    assert_eq!(messages[0]["params"], json!({ "r": 255, "g": 0, "b": 0, "dimming": 10, "state": true }));
+}
+
+#[test]
+fn set_color_sends_a_temperature() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "temp": 4000, "dimming": 100 }), json!({}));
+    let light_communication = light_communication(&[&lamp]);
+
+    light_communication.set_color_all((0, 0, 0), 2700, 50, true).unwrap();
+
+    let messages = lamp.wait_for("setPilot", 1);
+    assert_eq!(messages[0]["params"], json!({ "temp": 2700, "dimming": 50, "state": true }));
+}
+
+#[test]
+fn white_lamps_get_the_color_temperature() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "temp": 4000, "dimming": 100 }), json!({}));
+    *lamp.system_config.lock().unwrap() = json!({ "mac": "a8bb50000004", "moduleName": "ESP01_SHTW1C_31" });
+    let mut light_communication = light_communication(&[&lamp]);
+    light_communication.get_system_configs().unwrap();
+
+    light_communication.set_color_all((128, 70, 20), 0, 100, true).unwrap();
+
+    // A warm white, with the brightness of the color as the dimming
+    let messages = lamp.wait_for("setPilot", 1);
+    let temp = rgb_to_cct((128, 70, 20));
+    assert!(temp < 3000, "{}", temp);
+    assert_eq!(messages[0]["params"], json!({ "temp": temp, "dimming": 50, "state": true }));
+}
+
+#[test]
+fn colors_too_soon_after_the_previous_one_are_not_sent() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "r": 0, "g": 0, "b": 0, "dimming": 100 }), json!({}));
+    let mut light_communication = light_communication(&[&lamp]);
+    light_communication.set_min_send_interval(Duration::from_millis(200));
+
+    assert!(light_communication.set_color_all((255, 0, 0), 0, 100, true).unwrap());
+    assert!(!light_communication.set_color_all((0, 255, 0), 0, 100, true).unwrap());
+    // The lamp already has that color, there's nothing to send again
+    assert!(light_communication.set_color_all((255, 0, 0), 0, 100, true).unwrap());
+    assert!(!light_communication.set_state_all(false).unwrap());
+
+    thread::sleep(Duration::from_millis(200));
+    assert!(light_communication.set_color_all((0, 255, 0), 0, 100, true).unwrap());
+
+    let messages = lamp.wait_for("setPilot", 2);
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1]["params"], json!({ "r": 0, "g": 255, "b": 0, "dimming": 100, "state": true }));
+}
+
+#[test]
+fn set_state_only_sends_the_state() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "r": 255, "g": 0, "b": 0, "dimming": 100 }), json!({}));
+    let light_communication = light_communication(&[&lamp]);
+
+    light_communication.set_state(&lamp.ip, false).unwrap();
+
+    let messages = lamp.wait_for("setPilot", 1);
+    assert_eq!(messages[0]["params"], json!({ "state": false }));
+    // The lamp keeps its color for when it's turned on again
+    assert_eq!(lamp.pilot.lock().unwrap()["r"], 255);
+}
+
+#[test]
+fn ping_answers() {
+    let lamp = MockLamp::start("127.0.0.1", json!({ "state": true, "sceneId": 0, "r": 0, "g": 0, "b": 0, "dimming": 100 }), json!({}));
+    let light_communication = light_communication(&[&lamp]);
+
+    assert!(light_communication.ping(&lamp.ip).is_ok());
+    assert_eq!(lamp.messages_with_method("getPilot").len(), 1);
+}
+
+#[test]
+fn removed_lamps_dont_get_the_broadcasts() {
+    let lamp = MockLamp::start_for_broadcasts(
+        json!({ "mac": "a8bb50000009", "rssi": -60, "state": true, "sceneId": 0, "r": 0, "g": 0, "b": 255, "dimming": 100 }),
+        json!({ "fadeIn": 0, "fadeOut": 0 }),
+    );
+    // The broadcasts go to the port given here
+    let mut light_communication = LightCommunication::new(vec![lamp.ip.clone()], lamp.port, READ_TIMEOUT).unwrap();
+    light_communication.get_initial_states().unwrap();
+    if let Err(error) = light_communication.set_broadcast(true) {
+        eprintln!("Skipping the test, this network doesn't allow broadcasts: {}", error);
+        return;
+    }
+
+    light_communication.set_color_all((255, 0, 0), 0, 100, true).unwrap();
+    lamp.wait_for("setPilot", 1);
+
+    // Restored on removal, but it's still in the network
+    light_communication.remove_lamp(&lamp.ip).unwrap();
+    lamp.wait_for("setPilot", 2);
+
+    light_communication.set_color_all((0, 255, 0), 0, 100, true).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(lamp.messages_with_method("setPilot").len(), 2);
+    assert_eq!(lamp.pilot.lock().unwrap()["b"], 255);
+}
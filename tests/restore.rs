@@ -0,0 +1,139 @@
+//! The lamps get back exactly the state they had before syncing
+
+mod common;
+
+use common::{light_communication, MockLamp, READ_TIMEOUT};
+use serde_json::{json, Value};
+use wiz_screen_sync::LightCommunication;
+
+use std::fs;
+use std::net::UdpSocket;
+
+/// Save the states of the lamps, sync a color to them and restore them, like a sync session
+fn sync_and_restore(lamps: &[&MockLamp]) {
+    let mut light_communication = light_communication(lamps);
+
+    light_communication.get_initial_states().unwrap();
+    light_communication.set_fade_speed_all(0, 0).unwrap();
+    light_communication.set_color_all((0, 80, 255), 0, 100, true).unwrap();
+    // The colors aren't answered, the lamps have to get them before the restore
+    for lamp in lamps {
+        lamp.wait_for("setPilot", 1);
+    }
+    light_communication.restore_initial_states().unwrap();
+    for lamp in lamps {
+        lamp.wait_for("setPilot", 2);
+        lamp.wait_for("setUserConfig", 2);
+    }
+}
+
+/// The last message with a method a lamp received
+fn last_params(lamp: &MockLamp, method: &str) -> Value {
+    lamp.messages_with_method(method).last().unwrap()["params"].clone()
+}
+
+#[test]
+fn restore_a_color() {
+    let pilot = json!({ "mac": "a8bb50000001", "rssi": -60, "state": true, "sceneId": 0, "r": 255, "g": 120, "b": 0, "dimming": 80 });
+    let user_config = json!({ "fadeIn": 300, "fadeOut": 800 });
+    let lamp = MockLamp::start("127.0.0.1", pilot.clone(), user_config.clone());
+
+    sync_and_restore(&[&lamp]);
+
+    assert_eq!(last_params(&lamp, "setPilot"), json!({ "r": 255, "g": 120, "b": 0, "dimming": 80, "state": true }));
+    assert_eq!(last_params(&lamp, "setUserConfig"), json!({ "fadeIn": 300, "fadeOut": 800 }));
+    assert_eq!(*lamp.pilot.lock().unwrap(), pilot);
+    assert_eq!(*lamp.user_config.lock().unwrap(), user_config);
+}
+
+#[test]
+fn restore_a_temperature_turned_off() {
+    let pilot = json!({ "mac": "a8bb50000002", "rssi": -55, "state": false, "sceneId": 0, "temp": 2700, "dimming": 40 });
+    let user_config = json!({ "fadeIn": 1000, "fadeOut": 1000 });
+    let lamp = MockLamp::start("127.0.0.2", pilot.clone(), user_config.clone());
+
+    sync_and_restore(&[&lamp]);
+
+    assert_eq!(last_params(&lamp, "setPilot"), json!({ "temp": 2700, "dimming": 40, "state": false }));
+    assert_eq!(last_params(&lamp, "setUserConfig"), json!({ "fadeIn": 1000, "fadeOut": 1000 }));
+    assert_eq!(*lamp.pilot.lock().unwrap(), pilot);
+    assert_eq!(*lamp.user_config.lock().unwrap(), user_config);
+}
+
+#[test]
+fn restore_a_scene() {
+    let pilot = json!({ "mac": "a8bb50000003", "rssi": -70, "state": true, "sceneId": 12, "speed": 150, "dimming": 70 });
+    let user_config = json!({ "fadeIn": 0, "fadeOut": 0 });
+    let lamp = MockLamp::start("127.0.0.3", pilot.clone(), user_config.clone());
+
+    sync_and_restore(&[&lamp]);
+
+    assert_eq!(last_params(&lamp, "setPilot"), json!({ "sceneId": 12, "speed": 150, "dimming": 70, "state": true }));
+    assert_eq!(last_params(&lamp, "setUserConfig"), json!({ "fadeIn": 0, "fadeOut": 0 }));
+    assert_eq!(*lamp.pilot.lock().unwrap(), pilot);
+    assert_eq!(*lamp.user_config.lock().unwrap(), user_config);
+}
+
+#[test]
+fn restore_several_lamps() {
+    let lamps = [
+        MockLamp::start(
+            "127.0.0.1",
+            json!({ "mac": "a8bb50000001", "rssi": -60, "state": true, "sceneId": 0, "r": 255, "g": 120, "b": 0, "dimming": 80 }),
+            json!({ "fadeIn": 300, "fadeOut": 800 }),
+        ),
+        MockLamp::start(
+            "127.0.0.2",
+            json!({ "mac": "a8bb50000002", "rssi": -55, "state": false, "sceneId": 0, "temp": 2700, "dimming": 40 }),
+            json!({ "fadeIn": 1000, "fadeOut": 1000 }),
+        ),
+        MockLamp::start(
+            "127.0.0.3",
+            json!({ "mac": "a8bb50000003", "rssi": -70, "state": true, "sceneId": 12, "speed": 150, "dimming": 70 }),
+            json!({ "fadeIn": 0, "fadeOut": 0 }),
+        ),
+    ];
+    let initial: Vec<(Value, Value)> = lamps.iter()
+        .map(|lamp| (lamp.pilot.lock().unwrap().clone(), lamp.user_config.lock().unwrap().clone()))
+        .collect();
+
+    sync_and_restore(&lamps.iter().collect::<Vec<_>>());
+
+    for (lamp, (pilot, user_config)) in lamps.iter().zip(initial) {
+        assert_eq!(*lamp.pilot.lock().unwrap(), pilot, "{}", lamp.ip);
+        assert_eq!(*lamp.user_config.lock().unwrap(), user_config, "{}", lamp.ip);
+    }
+}
+
+#[test]
+fn restore_the_other_lamps_when_one_fails() {
+    let pilot = json!({ "mac": "a8bb50000001", "rssi": -60, "state": true, "sceneId": 0, "r": 255, "g": 120, "b": 0, "dimming": 80 });
+    let user_config = json!({ "fadeIn": 300, "fadeOut": 800 });
+    let lamp = MockLamp::start("127.0.0.1", pilot.clone(), user_config.clone());
+    // Nothing listens on the port of the other lamp anymore
+    let missing_ip = "127.0.0.4";
+    let missing_port = UdpSocket::bind((missing_ip, 0)).unwrap().local_addr().unwrap().port();
+
+    let mut light_communication = LightCommunication::new(vec![lamp.ip.clone(), missing_ip.to_string()], 0, READ_TIMEOUT).unwrap();
+    light_communication.set_port(&lamp.ip, lamp.port).unwrap();
+    light_communication.set_port(missing_ip, missing_port).unwrap();
+
+    assert!(light_communication.get_initial_states().is_err());
+    // The missing lamp has a state saved by a previous run
+    let path = std::env::temp_dir().join(format!("wiz-screen-sync-restore-{}.json", std::process::id()));
+    fs::write(&path, json!({ missing_ip: { "state": true, "temp": 3000, "dimming": 50, "fadeIn": 500, "fadeOut": 500 } }).to_string()).unwrap();
+    light_communication.load_initial_states(path.to_str().unwrap()).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(light_communication.set_fade_speed_all(0, 0).is_err());
+    assert_eq!(lamp.wait_for("setUserConfig", 1)[0]["params"], json!({ "fadeIn": 0, "fadeOut": 0 }));
+    // Sending to the missing lamp may fail or not, it isn't answered
+    let _ = light_communication.set_color_all((0, 80, 255), 0, 100, true);
+    lamp.wait_for("setPilot", 1);
+
+    assert!(light_communication.restore_initial_states().is_err());
+    lamp.wait_for("setPilot", 2);
+    lamp.wait_for("setUserConfig", 2);
+    assert_eq!(*lamp.pilot.lock().unwrap(), pilot);
+    assert_eq!(*lamp.user_config.lock().unwrap(), user_config);
+}